@@ -10,7 +10,13 @@ use tokio::task::LocalSet;
 use tokio::time::{self, Duration};
 use tokio_stream::wrappers::IntervalStream;
 use tracing::{debug, error, instrument};
-use tsproto_packets::packets::{AudioData, CodecType, OutAudio, OutPacket};
+use tsclientlib::dsp::{DspChain, DspConfig, Processor};
+use tsclientlib::encoder::EncoderConfig;
+use tsclientlib::soundboard::{Clip, ClipPlayer, PlayMode};
+use tsclientlib::vad::{GateDecision, TransmitMode, VoiceGate};
+use tsclientlib::whisper::{self, WhisperTarget};
+use tsclientlib::{data, ConnectionStats};
+use tsproto_packets::packets::{CodecType, OutPacket};
 
 use super::*;
 
@@ -21,6 +27,11 @@ pub struct AudioToTs {
 
 	is_playing: bool,
 	volume: Arc<Mutex<f32>>,
+	whisper_target: Arc<Mutex<Option<WhisperTarget>>>,
+	gate: Arc<Mutex<VoiceGate>>,
+	dsp: Arc<Mutex<DspChain>>,
+	encoder_config: Arc<Mutex<EncoderConfig>>,
+	soundboard: Arc<Mutex<ClipPlayer>>,
 }
 
 struct SdlCallback {
@@ -28,6 +39,15 @@ struct SdlCallback {
 	encoder: Encoder,
 	listener: Arc<Mutex<Option<mpsc::Sender<OutPacket>>>>,
 	volume: Arc<Mutex<f32>>,
+	whisper_target: Arc<Mutex<Option<WhisperTarget>>>,
+	gate: Arc<Mutex<VoiceGate>>,
+	dsp: Arc<Mutex<DspChain>>,
+	encoder_config: Arc<Mutex<EncoderConfig>>,
+	soundboard: Arc<Mutex<ClipPlayer>>,
+	/// The configuration which is currently set in the encoder.
+	applied_config: Option<EncoderConfig>,
+	/// Captured samples which do not fill a whole frame yet.
+	pending: Vec<f32>,
 
 	opus_output: [u8; MAX_OPUS_FRAME_SIZE],
 }
@@ -36,8 +56,22 @@ impl AudioToTs {
 	pub fn new(audio_subsystem: AudioSubsystem, local_set: &LocalSet) -> Result<Arc<Mutex<Self>>> {
 		let listener = Arc::new(Mutex::new(Default::default()));
 		let volume = Arc::new(Mutex::new(1.0));
+		let whisper_target = Arc::new(Mutex::new(None));
+		let gate = Arc::new(Mutex::new(VoiceGate::new(TransmitMode::Continuous)));
+		let dsp = Arc::new(Mutex::new(DspChain::default()));
+		let encoder_config = Arc::new(Mutex::new(EncoderConfig::default()));
+		let soundboard = Arc::new(Mutex::new(ClipPlayer::new()));
 
-		let device = Self::open_capture(&audio_subsystem, listener.clone(), volume.clone())?;
+		let device = Self::open_capture(
+			&audio_subsystem,
+			listener.clone(),
+			volume.clone(),
+			whisper_target.clone(),
+			gate.clone(),
+			dsp.clone(),
+			encoder_config.clone(),
+			soundboard.clone(),
+		)?;
 
 		let res = Arc::new(Mutex::new(Self {
 			audio_subsystem,
@@ -46,6 +80,11 @@ impl AudioToTs {
 
 			is_playing: false,
 			volume,
+			whisper_target,
+			gate,
+			dsp,
+			encoder_config,
+			soundboard,
 		}));
 
 		Self::start(res.clone(), local_set);
@@ -53,10 +92,21 @@ impl AudioToTs {
 		Ok(res)
 	}
 
-	#[instrument(skip(audio_subsystem, listener, volume))]
+	#[instrument(skip(
+		audio_subsystem,
+		listener,
+		volume,
+		whisper_target,
+		gate,
+		dsp,
+		encoder_config,
+		soundboard
+	))]
 	fn open_capture(
 		audio_subsystem: &AudioSubsystem, listener: Arc<Mutex<Option<mpsc::Sender<OutPacket>>>>,
-		volume: Arc<Mutex<f32>>,
+		volume: Arc<Mutex<f32>>, whisper_target: Arc<Mutex<Option<WhisperTarget>>>,
+		gate: Arc<Mutex<VoiceGate>>, dsp: Arc<Mutex<DspChain>>,
+		encoder_config: Arc<Mutex<EncoderConfig>>, soundboard: Arc<Mutex<ClipPlayer>>,
 	) -> Result<AudioDevice<SdlCallback>> {
 		let desired_spec = AudioSpecDesired {
 			freq: Some(48000),
@@ -88,6 +138,13 @@ impl AudioToTs {
 					encoder,
 					listener,
 					volume,
+					whisper_target,
+					gate,
+					dsp,
+					encoder_config,
+					soundboard,
+					applied_config: None,
+					pending: Vec::new(),
 
 					opus_output: [0; MAX_OPUS_FRAME_SIZE],
 				}
@@ -102,11 +159,88 @@ impl AudioToTs {
 
 	pub fn set_volume(&mut self, volume: f32) { *self.volume.lock().unwrap() = volume; }
 
+	/// Whisper to the given target instead of talking in the current channel.
+	pub fn set_whisper_target(&mut self, target: Option<WhisperTarget>) {
+		*self.whisper_target.lock().unwrap() = target;
+	}
+
+	/// Process captured audio with a new chain of filters.
+	pub fn set_dsp(&mut self, config: &DspConfig) { self.set_dsp_chain(DspChain::new(config)); }
+
+	pub fn set_dsp_chain(&mut self, chain: DspChain) { *self.dsp.lock().unwrap() = chain; }
+
+	/// The filters for captured audio, which can be changed from other tasks
+	/// while capturing.
+	pub fn get_dsp(&self) -> Arc<Mutex<DspChain>> { self.dsp.clone() }
+
+	/// Change the bitrate, complexity and other settings of the opus encoder.
+	pub fn set_encoder_config(&mut self, config: EncoderConfig) {
+		*self.encoder_config.lock().unwrap() = config;
+	}
+
+	pub fn get_encoder_config(&self) -> EncoderConfig { *self.encoder_config.lock().unwrap() }
+
+	/// Adapt the expected packet loss of the encoder to the connection.
+	pub fn update_network_stats(&mut self, stats: &ConnectionStats) {
+		self.encoder_config.lock().unwrap().adapt_packet_loss(stats);
+	}
+
+	/// Use the codec quality of the channel we are in.
+	pub fn update_channel(&mut self, book: &data::Connection) {
+		self.encoder_config.lock().unwrap().follow_channel(book);
+	}
+
+	/// Play a clip of the soundboard, mixed into the captured audio or
+	/// instead of it.
+	///
+	/// Clips are only played while capturing.
+	pub fn play_clip(&mut self, clip: Arc<Clip>, mode: PlayMode) {
+		self.soundboard.lock().unwrap().play(clip, mode);
+	}
+
+	pub fn stop_clips(&mut self) { self.soundboard.lock().unwrap().stop(); }
+
+	/// The player for soundboard clips, which can be used from other tasks.
+	pub fn get_soundboard(&self) -> Arc<Mutex<ClipPlayer>> { self.soundboard.clone() }
+
+	/// Choose when captured audio is sent, continuously, by voice activation
+	/// or with push-to-talk.
+	pub fn set_transmit_mode(&mut self, mode: TransmitMode) {
+		self.gate.lock().unwrap().set_mode(mode);
+	}
+
+	/// Press or release push-to-talk.
+	pub fn set_push_to_talk(&mut self, pressed: bool) {
+		self.gate.lock().unwrap().set_push_to_talk(pressed);
+	}
+
+	/// Toggle push-to-talk and return if it is pressed now.
+	pub fn toggle_push_to_talk(&mut self) -> bool {
+		self.gate.lock().unwrap().toggle_push_to_talk()
+	}
+
+	/// Set the voice activation threshold in dBFS and the time audio is still
+	/// sent after the last loud frame.
+	pub fn set_voice_activation(&mut self, threshold: f32, hangover: Duration) {
+		let mut gate = self.gate.lock().unwrap();
+		gate.set_threshold(threshold);
+		gate.set_hangover(hangover);
+	}
+
 	pub fn set_playing(&mut self, playing: bool) {
 		if playing {
 			self.device.resume();
 		} else {
 			self.device.pause();
+			// End the audio stream if we were sending
+			let mut gate = self.gate.lock().unwrap();
+			if gate.is_active() {
+				gate.reset();
+				let codec = codec_for(self.device.spec(), &self.encoder_config.lock().unwrap());
+				let target = self.whisper_target.lock().unwrap();
+				let packet = whisper::create_audio_packet(target.as_ref(), codec, &[]);
+				send_packet(&self.listener, packet);
+			}
 		}
 		self.is_playing = playing;
 	}
@@ -122,6 +256,11 @@ impl AudioToTs {
 						&a2t.audio_subsystem,
 						a2t.listener.clone(),
 						a2t.volume.clone(),
+						a2t.whisper_target.clone(),
+						a2t.gate.clone(),
+						a2t.dsp.clone(),
+						a2t.encoder_config.clone(),
+						a2t.soundboard.clone(),
 					) {
 						Ok(d) => {
 							a2t.device = d;
@@ -141,6 +280,53 @@ impl AudioToTs {
 	}
 }
 
+/// The codec which is used for the captured audio.
+fn codec_for(spec: &AudioSpec, config: &EncoderConfig) -> CodecType {
+	if spec.channels == 1 { config.codec_type() } else { CodecType::OpusMusic }
+}
+
+/// Write into the packet sink.
+fn send_packet(listener: &Mutex<Option<mpsc::Sender<OutPacket>>>, packet: OutPacket) {
+	let mut listener = listener.lock().unwrap();
+	if let Some(lis) = &mut *listener {
+		if let Err(mpsc::error::TrySendError::Closed(_)) = lis.try_send(packet) {
+			*listener = None;
+		}
+	}
+}
+
+impl SdlCallback {
+	/// Process and send one frame.
+	fn send_frame(&mut self, frame: &mut [f32], config: &EncoderConfig) {
+		let channels = usize::from(self.spec.channels);
+		self.dsp.lock().unwrap().process(frame, channels);
+		self.soundboard.lock().unwrap().mix(frame, channels);
+
+		let frame_duration = Duration::from_secs_f64(
+			frame.len() as f64 / channels as f64 / f64::from(self.spec.freq),
+		);
+		let data = match self.gate.lock().unwrap().process(frame, frame_duration) {
+			GateDecision::Silent => return,
+			// An empty packet tells other clients that we stopped talking
+			GateDecision::EndOfStream => &[][..],
+			GateDecision::Send => {
+				match self.encoder.encode_float(frame, &mut self.opus_output[..]) {
+					Err(error) => {
+						error!(%error, "Failed to encode opus");
+						return;
+					}
+					Ok(len) => &self.opus_output[..len],
+				}
+			}
+		};
+
+		let target = self.whisper_target.lock().unwrap();
+		let packet =
+			whisper::create_audio_packet(target.as_ref(), codec_for(&self.spec, config), data);
+		send_packet(&self.listener, packet);
+	}
+}
+
 impl AudioCallback for SdlCallback {
 	type Channel = f32;
 
@@ -154,29 +340,37 @@ impl AudioCallback for SdlCallback {
 			}
 		}
 
-		match self.encoder.encode_float(buffer, &mut self.opus_output[..]) {
-			Err(error) => {
-				error!(%error, "Failed to encode opus");
+		// Send the pre-encoded frames of a clip instead of the captured audio
+		let mut soundboard = self.soundboard.lock().unwrap();
+		if soundboard.is_interrupting() {
+			self.pending.clear();
+			self.gate.lock().unwrap().reset();
+			let target = self.whisper_target.lock().unwrap();
+			if let Some(packet) = soundboard.next_packet(target.as_ref()) {
+				send_packet(&self.listener, packet);
 			}
-			Ok(len) => {
-				// Create packet
-				let codec = if self.spec.channels == 1 {
-					CodecType::OpusVoice
-				} else {
-					CodecType::OpusMusic
-				};
-				let packet =
-					OutAudio::new(&AudioData::C2S { id: 0, codec, data: &self.opus_output[..len] });
-
-				// Write into packet sink
-				let mut listener = self.listener.lock().unwrap();
-				if let Some(lis) = &mut *listener {
-					match lis.try_send(packet) {
-						Err(mpsc::error::TrySendError::Closed(_)) => *listener = None,
-						_ => {}
-					}
-				}
+			return;
+		}
+		drop(soundboard);
+
+		let config = *self.encoder_config.lock().unwrap();
+		if self.applied_config != Some(config) {
+			if let Err(error) = config.apply(&mut self.encoder) {
+				error!(%error, "Failed to configure opus encoder");
 			}
+			self.applied_config = Some(config);
+		}
+
+		// Split into frames of the configured size
+		self.pending.extend_from_slice(buffer);
+		let frame_len = config.frame_samples() * usize::from(self.spec.channels);
+		let mut pending = std::mem::take(&mut self.pending);
+		let mut frames = pending.chunks_exact_mut(frame_len);
+		for frame in &mut frames {
+			self.send_frame(frame, &config);
 		}
+		let rest = frames.into_remainder().len();
+		pending.drain(..pending.len() - rest);
+		self.pending = pending;
 	}
 }
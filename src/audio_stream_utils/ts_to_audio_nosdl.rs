@@ -8,6 +8,7 @@ use tokio::task::LocalSet;
 use tokio::time::{self, Duration};
 use tokio_stream::wrappers::IntervalStream;
 use tracing::{debug, error, instrument};
+use tsclientlib::dsp::{self, DspChain, DspConfig, Processor};
 use tsclientlib::ClientId;
 use tsproto_packets::packets::InAudioBuf;
 
@@ -15,7 +16,7 @@ use super::*;
 use crate::ConnectionId;
 
 type Id = (ConnectionId, ClientId);
-type AudioHandler = tsclientlib::audio::AudioHandler<Id>;
+type Mixer = tsclientlib::mixer::Mixer<ConnectionId>;
 
 use std::fs::OpenOptions;
 use std::io::{self, Write};
@@ -23,27 +24,17 @@ use std::io::{self, Write};
 pub struct TsToAudio {
 	audio_subsystem: AudioSubsystem,
 	device: AudioDevice<SdlCallback>,
-	data: Arc<Mutex<AudioHandler>>,    
+	data: Arc<Mutex<Mixer>>,
 	buffer_i16: Arc<Mutex<Vec<i16>>>,
+	dsp: Arc<Mutex<DspChain>>,
 }
 
 struct SdlCallback {
-	data: Arc<Mutex<AudioHandler>>,
+	data: Arc<Mutex<Mixer>>,
     buffer_i16: Arc<Mutex<Vec<i16>>>, 
+	dsp: Arc<Mutex<DspChain>>,
 }
 
-fn vec_f32_to_i16_linear(buffer: &mut [f32]) -> Vec<i16> {
-    buffer
-        .iter_mut()
-        .map(|x| {
-            let scaled = *x * 65535.0 * 1.6;
-            // let scaled = *x * 32767.5 + 32767.5;
-            scaled.clamp(-65535.0, 65535.0) as i16
-        })
-        .collect()
-}
-
-
 fn write_vec_i16_to_file(data: &[i16], filename: &str) -> io::Result<()> {
     // 打开文件（追加模式，如果文件不存在则创建）
     let mut file = OpenOptions::new()
@@ -68,16 +59,19 @@ fn write_vec_i16_to_file(data: &[i16], filename: &str) -> io::Result<()> {
 
 impl TsToAudio {
 	pub fn new(audio_subsystem: AudioSubsystem, local_set: &LocalSet) -> Result<Arc<Mutex<Self>>> {
-		let data = Arc::new(Mutex::new(AudioHandler::new()));
+		let data = Arc::new(Mutex::new(Mixer::new()));
         let buffer_i16 = Arc::new(Mutex::new(Vec::new())); // 初始化 buffer_i16
+		let dsp = Arc::new(Mutex::new(DspChain::new(&DspConfig::playback())));
 
-		let device = Self::open_playback(&audio_subsystem, data.clone(), buffer_i16.clone())?;
+		let device =
+			Self::open_playback(&audio_subsystem, data.clone(), buffer_i16.clone(), dsp.clone())?;
 
         let res = Arc::new(Mutex::new(Self {
             audio_subsystem,
             device,
             data,
             buffer_i16,
+            dsp,
         }));
 
 		Self::start(res.clone(), local_set);
@@ -85,11 +79,12 @@ impl TsToAudio {
 		Ok(res)
 	}
 
-	#[instrument(skip(audio_subsystem, data))]
+	#[instrument(skip(audio_subsystem, data, buffer_i16, dsp))]
 	fn open_playback(
         audio_subsystem: &AudioSubsystem,
-        data: Arc<Mutex<AudioHandler>>,
+        data: Arc<Mutex<Mixer>>,
         buffer_i16: Arc<Mutex<Vec<i16>>>,
+        dsp: Arc<Mutex<DspChain>>,
 	) -> Result<AudioDevice<SdlCallback>> {
 		let desired_spec = AudioSpecDesired {
 			freq: Some(48000),
@@ -102,7 +97,7 @@ impl TsToAudio {
 				// This spec will always be the desired spec, the sdl wrapper passes
 				// zero as `allowed_changes`.
 				debug!(?spec, driver = audio_subsystem.current_audio_driver(), "Got playback spec");
-				SdlCallback { data, buffer_i16 }
+				SdlCallback { data, buffer_i16, dsp }
 			})
 			.map_err(|e| format_err!("SDL error: {}", e))
 	}
@@ -115,7 +110,12 @@ impl TsToAudio {
 
 				if t2a.device.status() == AudioStatus::Stopped {
 					// Try to reconnect to audio
-					match Self::open_playback(&t2a.audio_subsystem, t2a.data.clone(), t2a.buffer_i16.clone()) {
+					match Self::open_playback(
+						&t2a.audio_subsystem,
+						t2a.data.clone(),
+						t2a.buffer_i16.clone(),
+						t2a.dsp.clone(),
+					) {
 						Ok(d) => {
 							t2a.device = d;
 							debug!("Reconnected to playback device");
@@ -126,7 +126,7 @@ impl TsToAudio {
 					};
 				}
 
-				let data_empty = t2a.data.lock().unwrap().get_queues().is_empty();
+				let data_empty = t2a.data.lock().unwrap().get_handler().get_queues().is_empty();
 				if t2a.device.status() == AudioStatus::Paused && !data_empty {
 					debug!("Resuming playback");
 					t2a.device.resume();
@@ -142,7 +142,7 @@ impl TsToAudio {
 	#[instrument(skip(self, id, packet))]
 	pub(crate) fn play_packet(&mut self, id: Id, packet: InAudioBuf) -> Result<()> {
 		let mut data = self.data.lock().unwrap();
-		data.handle_packet(id, packet)?;
+		data.handle_packet(id.0, id.1, packet)?;
 
 		if self.device.status() == AudioStatus::Paused {
 			debug!("Resuming playback");
@@ -151,6 +151,22 @@ impl TsToAudio {
 		Ok(())
 	}
 
+	/// Stop playing the audio of a connection, e.g. after it disconnected.
+	pub(crate) fn remove_connection(&mut self, con: ConnectionId) {
+		self.data.lock().unwrap().remove_connection(&con);
+	}
+
+	/// Process played audio with a new chain of filters.
+	///
+	/// The default chain only contains a soft limiter.
+	pub fn set_dsp(&mut self, config: &DspConfig) { self.set_dsp_chain(DspChain::new(config)); }
+
+	pub fn set_dsp_chain(&mut self, chain: DspChain) { *self.dsp.lock().unwrap() = chain; }
+
+	/// The filters for played audio, which can be changed from other tasks
+	/// while playing.
+	pub fn get_dsp(&self) -> Arc<Mutex<DspChain>> { self.dsp.clone() }
+
     pub fn get_buff_i16(&self) -> Vec<i16> {
 		self.buffer_i16.lock().unwrap().clone() 
     }
@@ -179,8 +195,9 @@ impl AudioCallback for SdlCallback {
 
         let mut data = self.data.lock().unwrap();
         data.fill_buffer(buffer);
+        self.dsp.lock().unwrap().process(buffer, 2);
 
-        let buffer_i16 = vec_f32_to_i16_linear(buffer);
+        let buffer_i16 = dsp::to_i16(buffer);
         *self.buffer_i16.lock().unwrap() = buffer_i16;
 		// println!("Audio buffer: {:?}",  &buffer_i16[..buffer_i16.len().min(10)]);
 		// write_vec_i16_to_file(&buffer_i16, "test.pcm");
@@ -17,9 +17,11 @@ use std::net::SocketAddr;
 use tokio::sync::mpsc;
 use tokio::time::{self, Duration};
 
-use tsclientlib::data::{self, Channel, Client};
+use tsclientlib::channel_tree::{ChannelNode, ChannelTree};
+use tsclientlib::data;
 use tsclientlib::prelude::*;
-use tsclientlib::{ClientId, ChannelId, Connection, DisconnectOptions, Identity, StreamItem};
+use tsclientlib::identity_store::IdentityStore;
+use tsclientlib::{ClientId, Connection, DisconnectOptions, StreamItem};
 
 // audio play
 use tokio::task::LocalSet;
@@ -34,33 +36,26 @@ use audiopus::coder::Decoder;
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 struct ConnectionId(u64);
 
-/// `channels` have to be ordered.
-fn print_channels(clients: &[&Client], channels: &[&Channel], parent: ChannelId, depth: usize) {
+fn print_channels(channels: &[ChannelNode], depth: usize) {
 	let indention = "  ".repeat(depth);
 	for channel in channels {
-		if channel.parent == parent {
-			println!("{}- {}", indention, channel.name);
-			// Print all clients in this channel
-			for client in clients {
-				if client.channel == channel.id {
-					println!("{}  {}", indention, client.name);
-				}
-			}
-
-			print_channels(clients, channels, channel.id, depth + 1);
+		match channel.spacer {
+			Some(spacer) => println!("{}{}", indention, spacer.text),
+			None => println!("{}- {}", indention, channel.name),
+		}
+		// Print all clients in this channel
+		for client in &channel.clients {
+			println!("{}  {}", indention, client.name);
 		}
+
+		print_channels(&channel.children, depth + 1);
 	}
 }
 
 fn print_channel_tree(con: &data::Connection) {
-	let mut channels: Vec<_> = con.channels.values().collect();
-	let mut clients: Vec<_> = con.clients.values().collect();
-	// This is not the real sorting order, the order is the ChannelId of the
-	// channel on top of this one, but we don't care for this example.
-	channels.sort_by_key(|ch| ch.order.0);
-	clients.sort_by_key(|c| c.talk_power);
+	let tree = ChannelTree::new(con);
 	println!("{}", con.server.name);
-	print_channels(&clients, &channels, ChannelId(0), 0);
+	print_channels(&tree.nodes(con), 0);
 }
 
 fn decode_packet(packet: InAudioBuf) -> Result<Vec<f32>, anyhow::Error> {
@@ -134,13 +129,8 @@ fn decode_packet_i16(packet: InAudioBuf) -> Result<Vec<i16>, anyhow::Error> {
 }
 
 fn vec_f32_to_i16_linear(vec: &[f32]) -> Vec<i16> {
-    vec.iter()
-        .map(|&x| {
-            let scaled = x * 65535.0 * 1.6;
-            // let scaled = x * 32767.5 +  32767.5 ;
-            scaled.clamp(-65535.0, 65535.0) as i16
-        })
-        .collect()
+    // 使用 dsp::DspChain 调整音量，这里只做转换
+    tsclientlib::dsp::to_i16(vec)
 }
 
 fn write_vec_i16_to_file(data: &[i16], filename: &str) -> io::Result<()> {
@@ -202,12 +192,28 @@ async fn main() -> Result<()> {
                 .help("Sets the I/O port of the TeamSpeak 3 server")
                 .default_value("43500"), // 默认值
         )
+        .arg(
+            Arg::new("identity")
+                .long("identity")
+                .value_name("NAME")
+                .help("Sets the name of the identity in the identity store")
+                .default_value("default"), // 默认值
+        )
+        .arg(
+            Arg::new("identity-dir")
+                .long("identity-dir")
+                .value_name("DIR")
+                .help("Sets the directory of the identity store")
+                .default_value("identities"), // 默认值
+        )
         .get_matches();
 
     // 获取参数值，如果未提供则使用默认值
     let ip: &String = matches.get_one::<String>("ip").unwrap();
     let name: &String = matches.get_one::<String>("name").unwrap();
     let io_port: &String= matches.get_one::<String>("io").unwrap();
+    let identity_name: &String = matches.get_one::<String>("identity").unwrap();
+    let identity_dir: &String = matches.get_one::<String>("identity-dir").unwrap();
 
     // 打印解析结果
     println!("IP Address: {}", ip);
@@ -262,14 +268,14 @@ async fn main() -> Result<()> {
     // 开始创建链接
 	let con_config = Connection::build(ip.as_str());     
 
-	// （可选）设置此客户端的密钥，否则将生成新密钥。
-	let id = Identity::new_from_str(
-		"MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
-		k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITs\
-		C/50CIA8M5nmDBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap();
+	// 从身份库加载此客户端的密钥，不存在时生成新密钥并保存。
+	// 加密的身份需要通过环境变量 TS3EZAPI_IDENTITY_PASSPHRASE 提供密码。
+	let passphrase = std::env::var("TS3EZAPI_IDENTITY_PASSPHRASE").ok();
+	let store = IdentityStore::open(identity_dir.as_str())?;
+	let id = store.load_or_create(identity_name, passphrase.as_deref())?;
     
     // 密钥绑定到连接信息上
-	let con_config = con_config.identity(id);
+	let con_config = con_config.identity(id.identity);
     
     // 连接...
     let mut con = con_config.connect()?;
@@ -289,6 +295,9 @@ async fn main() -> Result<()> {
 		let mut a2t = audiodata.a2ts.lock().unwrap();
 		a2t.set_listener(send);
 		a2t.set_volume(1.0f32);
+		a2t.set_dsp(&tsclientlib::dsp::DspConfig::capture());
+		// Only send audio while someone speaks and end the stream afterwards
+		a2t.set_transmit_mode(tsclientlib::vad::TransmitMode::VoiceActivation);
 		a2t.set_playing(true);
 	}
 
@@ -318,6 +327,9 @@ async fn main() -> Result<()> {
 			.send(&mut con)?;
 	}
 
+	// Update the encoder settings with the network statistics and channel
+	let mut encoder_update = time::interval(Duration::from_secs(1));
+
     // 音频播放
 	loop {
 		let t2a = audiodata.ts2a.clone();
@@ -374,6 +386,16 @@ async fn main() -> Result<()> {
 					break;
 				}
 			}
+			_ = encoder_update.tick() => {
+				// Skip the update while reconnecting
+				let mut a2t = audiodata.a2ts.lock().unwrap();
+				if let Ok(stats) = con.get_network_stats() {
+					a2t.update_network_stats(stats);
+				}
+				if let Ok(state) = con.get_state() {
+					a2t.update_channel(state);
+				}
+			}
 			_ = tokio::signal::ctrl_c() => { break; }
 			r = events => {
 				r?;
@@ -385,6 +407,7 @@ async fn main() -> Result<()> {
     // 断开连接
     con.disconnect(DisconnectOptions::new())?;
     con.events().for_each(|_| future::ready(())).await;
+	audiodata.ts2a.lock().unwrap().remove_connection(con_id);
 
     Ok(())
 }
\ No newline at end of file
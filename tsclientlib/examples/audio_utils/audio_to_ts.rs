@@ -10,7 +10,8 @@ use tokio::task::LocalSet;
 use tokio::time::{self, Duration};
 use tokio_stream::wrappers::IntervalStream;
 use tracing::{debug, error, instrument};
-use tsproto_packets::packets::{AudioData, CodecType, OutAudio, OutPacket};
+use tsclientlib::whisper::{self, WhisperTarget};
+use tsproto_packets::packets::{CodecType, OutPacket};
 
 use super::*;
 
@@ -21,6 +22,7 @@ pub struct AudioToTs {
 
 	is_playing: bool,
 	volume: Arc<Mutex<f32>>,
+	whisper_target: Arc<Mutex<Option<WhisperTarget>>>,
 }
 
 struct SdlCallback {
@@ -28,6 +30,7 @@ struct SdlCallback {
 	encoder: Encoder,
 	listener: Arc<Mutex<Option<mpsc::Sender<OutPacket>>>>,
 	volume: Arc<Mutex<f32>>,
+	whisper_target: Arc<Mutex<Option<WhisperTarget>>>,
 
 	opus_output: [u8; MAX_OPUS_FRAME_SIZE],
 }
@@ -36,8 +39,14 @@ impl AudioToTs {
 	pub fn new(audio_subsystem: AudioSubsystem, local_set: &LocalSet) -> Result<Arc<Mutex<Self>>> {
 		let listener = Arc::new(Mutex::new(Default::default()));
 		let volume = Arc::new(Mutex::new(1.0));
+		let whisper_target = Arc::new(Mutex::new(None));
 
-		let device = Self::open_capture(&audio_subsystem, listener.clone(), volume.clone())?;
+		let device = Self::open_capture(
+			&audio_subsystem,
+			listener.clone(),
+			volume.clone(),
+			whisper_target.clone(),
+		)?;
 
 		let res = Arc::new(Mutex::new(Self {
 			audio_subsystem,
@@ -46,6 +55,7 @@ impl AudioToTs {
 
 			is_playing: false,
 			volume,
+			whisper_target,
 		}));
 
 		Self::start(res.clone(), local_set);
@@ -53,10 +63,10 @@ impl AudioToTs {
 		Ok(res)
 	}
 
-	#[instrument(skip(audio_subsystem, listener, volume))]
+	#[instrument(skip(audio_subsystem, listener, volume, whisper_target))]
 	fn open_capture(
 		audio_subsystem: &AudioSubsystem, listener: Arc<Mutex<Option<mpsc::Sender<OutPacket>>>>,
-		volume: Arc<Mutex<f32>>,
+		volume: Arc<Mutex<f32>>, whisper_target: Arc<Mutex<Option<WhisperTarget>>>,
 	) -> Result<AudioDevice<SdlCallback>> {
 		let desired_spec = AudioSpecDesired {
 			freq: Some(48000),
@@ -88,6 +98,7 @@ impl AudioToTs {
 					encoder,
 					listener,
 					volume,
+					whisper_target,
 
 					opus_output: [0; MAX_OPUS_FRAME_SIZE],
 				}
@@ -102,6 +113,11 @@ impl AudioToTs {
 
 	pub fn set_volume(&mut self, volume: f32) { *self.volume.lock().unwrap() = volume; }
 
+	/// Whisper to the given target instead of talking in the current channel.
+	pub fn set_whisper_target(&mut self, target: Option<WhisperTarget>) {
+		*self.whisper_target.lock().unwrap() = target;
+	}
+
 	pub fn set_playing(&mut self, playing: bool) {
 		if playing {
 			self.device.resume();
@@ -122,6 +138,7 @@ impl AudioToTs {
 						&a2t.audio_subsystem,
 						a2t.listener.clone(),
 						a2t.volume.clone(),
+						a2t.whisper_target.clone(),
 					) {
 						Ok(d) => {
 							a2t.device = d;
@@ -165,8 +182,9 @@ impl AudioCallback for SdlCallback {
 				} else {
 					CodecType::OpusMusic
 				};
+				let target = self.whisper_target.lock().unwrap();
 				let packet =
-					OutAudio::new(&AudioData::C2S { id: 0, codec, data: &self.opus_output[..len] });
+					whisper::create_audio_packet(target.as_ref(), codec, &self.opus_output[..len]);
 
 				// Write into packet sink
 				let mut listener = self.listener.lock().unwrap();
@@ -3,9 +3,10 @@ use clap::Parser;
 use futures::prelude::*;
 use tokio::time::{self, Duration};
 
-use tsclientlib::data::{self, Channel, Client};
+use tsclientlib::channel_tree::{ChannelNode, ChannelTree};
+use tsclientlib::data;
 use tsclientlib::prelude::*;
-use tsclientlib::{ChannelId, Connection, DisconnectOptions, Identity, StreamItem};
+use tsclientlib::{Connection, DisconnectOptions, Identity, StreamItem};
 
 #[derive(Parser, Debug)]
 #[command(author, about)]
@@ -23,33 +24,26 @@ struct Args {
 	verbose: u8,
 }
 
-/// `channels` have to be ordered.
-fn print_channels(clients: &[&Client], channels: &[&Channel], parent: ChannelId, depth: usize) {
+fn print_channels(channels: &[ChannelNode], depth: usize) {
 	let indention = "  ".repeat(depth);
 	for channel in channels {
-		if channel.parent == parent {
-			println!("{}- {}", indention, channel.name);
-			// Print all clients in this channel
-			for client in clients {
-				if client.channel == channel.id {
-					println!("{}  {}", indention, client.name);
-				}
-			}
-
-			print_channels(clients, channels, channel.id, depth + 1);
+		match channel.spacer {
+			Some(spacer) => println!("{}{}", indention, spacer.text),
+			None => println!("{}- {}", indention, channel.name),
 		}
+		// Print all clients in this channel
+		for client in &channel.clients {
+			println!("{}  {}", indention, client.name);
+		}
+
+		print_channels(&channel.children, depth + 1);
 	}
 }
 
 fn print_channel_tree(con: &data::Connection) {
-	let mut channels: Vec<_> = con.channels.values().collect();
-	let mut clients: Vec<_> = con.clients.values().collect();
-	// This is not the real sorting order, the order is the ChannelId of the
-	// channel on top of this one, but we don't care for this example.
-	channels.sort_by_key(|ch| ch.order.0);
-	clients.sort_by_key(|c| c.talk_power);
+	let tree = ChannelTree::new(con);
 	println!("{}", con.server.name);
-	print_channels(&clients, &channels, ChannelId(0), 0);
+	print_channels(&tree.nodes(con), 0);
 }
 
 #[tokio::main]
@@ -4,6 +4,13 @@
 //! client. It decodes the audio, handles out-of-order packets and missing
 //! packets. It automatically adjusts the queue length based on the jitter of
 //! incoming packets.
+//!
+//! How much is buffered can be changed with an [`AudioHandlerConfig`]. Every
+//! queue keeps [`QueueStats`] for diagnostics.
+//!
+//! The audio of every speaker can be pulled separately with
+//! [`AudioHandler::fill_speakers`] or received through a channel from
+//! [`AudioHandler::add_tap`], e.g. to record single clients.
 
 use std::cmp::Reverse;
 use std::collections::{HashMap, VecDeque};
@@ -13,6 +20,7 @@ use std::hash::Hash;
 
 use audiopus::coder::Decoder;
 use audiopus::{packet, Channels, SampleRate};
+use futures::channel::mpsc;
 use thiserror::Error;
 use tracing::{debug, info_span, trace, warn, Span};
 use tsproto_packets::packets::{AudioData, CodecType, InAudioBuf};
@@ -22,18 +30,8 @@ use crate::ClientId;
 const SAMPLE_RATE: SampleRate = SampleRate::Hz48000;
 const CHANNELS: Channels = Channels::Stereo;
 const CHANNEL_NUM: usize = 2;
-/// If this amount of packets is lost consecutively, we assume the stream stopped.
-const MAX_PACKET_LOSSES: usize = 3;
 /// Store the buffer sizes for the last `LAST_BUFFER_SIZE_COUNT` packets.
 const LAST_BUFFER_SIZE_COUNT: u8 = 255;
-/// The amount of samples to maximally buffer. Equivalent to 0.5 s.
-const MAX_BUFFER_SIZE: usize = 48_000 / 2;
-/// Maximum number of packets in the queue.
-const MAX_BUFFER_PACKETS: usize = 50;
-/// Buffer for maximal 0.5 s without playing anything.
-const MAX_BUFFER_TIME: usize = 48_000 / 2;
-/// Duplicate or remove every `step` sample when speeding-up.
-const SPEED_CHANGE_STEPS: usize = 100;
 /// The usual amount of samples in a frame.
 ///
 /// Use 48 kHz, 20 ms frames (50 per second) and mono data (1 channel).
@@ -67,6 +65,58 @@ pub enum Error {
 	UnsupportedCodec(CodecType),
 }
 
+/// Parameters of the jitter buffer of an [`AudioHandler`].
+///
+/// Smaller buffers reduce the latency, bigger buffers handle more jitter and
+/// packet loss.
+///
+/// # Default
+///
+/// Buffers up to 0.5 s or 50 packets, ends a stream after 3 lost packets and
+/// speeds up by dropping every 100th sample.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AudioHandlerConfig {
+	/// If this amount of packets is lost consecutively, we assume the stream stopped.
+	pub max_packet_losses: usize,
+	/// The amount of samples to maximally buffer.
+	pub max_buffer_size: usize,
+	/// Maximum number of packets in the queue.
+	pub max_buffer_packets: usize,
+	/// Buffer for maximal this amount of samples without playing anything.
+	pub max_buffer_time: usize,
+	/// Duplicate or remove every `step` sample when speeding-up.
+	pub speed_change_steps: usize,
+}
+
+/// Statistics of an [`AudioQueue`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct QueueStats {
+	/// The amount of packets that are currently buffered.
+	pub buffered_packets: usize,
+	/// The amount of samples that are currently buffered.
+	pub buffered_samples: usize,
+	/// Packets that were lost and had to be concealed.
+	pub lost_packets: u64,
+	/// How often playback was sped up to shrink the buffer.
+	pub speed_ups: u64,
+	/// How often the buffer was truncated because it grew too big.
+	pub truncations: u64,
+	/// Packets that arrived too late and were dropped.
+	pub late_packets: u64,
+}
+
+/// Decoded audio of one speaker.
+///
+/// The samples are interleaved stereo with 48 kHz. The volume of the queue is
+/// not applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpeakerAudio<Id> {
+	pub id: Id,
+	pub samples: Vec<f32>,
+	/// The speaker stopped talking, this is the last audio of the stream.
+	pub is_end: bool,
+}
+
 #[derive(Clone, Debug)]
 struct SlidingWindowMinimum<T: Copy + Default + Ord> {
 	/// How long a value stays in the sliding window.
@@ -95,6 +145,7 @@ struct QueuePacket {
 /// A queue for audio packets for one audio stream.
 pub struct AudioQueue {
 	span: Span,
+	config: AudioHandlerConfig,
 	decoder: Decoder,
 	pub volume: f32,
 	/// The id of the next packet that should be decoded.
@@ -124,17 +175,64 @@ pub struct AudioQueue {
 	last_buffer_size_max: SlidingWindowMinimum<Reverse<u8>>,
 	/// Buffered for this duration.
 	buffered_for_samples: usize,
+	lost_packets: u64,
+	speed_ups: u64,
+	truncations: u64,
+	late_packets: u64,
 }
 
 /// Handles incoming audio, has one [`AudioQueue`] per sending client.
 pub struct AudioHandler<Id: Clone + Debug + Eq + Hash + PartialEq = ClientId> {
+	config: AudioHandlerConfig,
 	queues: HashMap<Id, AudioQueue>,
+	/// Receivers of the audio of every speaker.
+	taps: Vec<mpsc::Sender<SpeakerAudio<Id>>>,
 	/// Buffer this amount of samples for new queues before starting to play.
 	///
 	/// Updated when a new queue gets added.
 	avg_buffer_samples: usize,
 }
 
+impl Default for AudioHandlerConfig {
+	fn default() -> Self {
+		Self {
+			max_packet_losses: 3,
+			max_buffer_size: 48_000 / 2,
+			max_buffer_packets: 50,
+			max_buffer_time: 48_000 / 2,
+			speed_change_steps: 100,
+		}
+	}
+}
+
+impl AudioHandlerConfig {
+	/// Small buffers for networks with little jitter, like a LAN.
+	///
+	/// Buffers up to 125 ms or 20 packets and catches up faster.
+	pub fn low_latency() -> Self {
+		Self {
+			max_packet_losses: 2,
+			max_buffer_size: 48_000 / 8,
+			max_buffer_packets: 20,
+			max_buffer_time: 48_000 / 10,
+			speed_change_steps: 50,
+		}
+	}
+
+	/// Big buffers for recording, where latency does not matter.
+	///
+	/// Buffers up to 2 s or 150 packets and tolerates 5 lost packets.
+	pub fn deep_buffer() -> Self {
+		Self {
+			max_packet_losses: 5,
+			max_buffer_size: 48_000 * 2,
+			max_buffer_packets: 150,
+			max_buffer_time: 48_000,
+			speed_change_steps: 200,
+		}
+	}
+}
+
 impl<T: Copy + Default + Ord> SlidingWindowMinimum<T> {
 	fn new(size: u8) -> Self { Self { size, queue: Default::default(), cur_time: 0 } }
 
@@ -159,12 +257,12 @@ impl<T: Copy + Default + Ord> SlidingWindowMinimum<T> {
 }
 
 impl AudioQueue {
-	fn new(packet: InAudioBuf) -> Result<Self> {
+	fn new(packet: InAudioBuf, config: AudioHandlerConfig) -> Result<Self> {
 		let data = packet.data().data();
 		let opus_packet = data.data().try_into().map_err(Error::GetPacketSample)?;
 		let last_packet_samples =
 			packet::nb_samples(opus_packet, SAMPLE_RATE).map_err(Error::GetPacketSample)?;
-		if last_packet_samples > MAX_BUFFER_SIZE {
+		if last_packet_samples > config.max_buffer_size {
 			return Err(Error::TooManySamples);
 		}
 
@@ -172,6 +270,7 @@ impl AudioQueue {
 		let whispering = matches!(data, AudioData::S2CWhisper { .. });
 		let mut res = Self {
 			span: Span::current(),
+			config,
 			decoder: Decoder::new(SAMPLE_RATE, CHANNELS).map_err(Error::CreateDecoder)?,
 			volume: 1.0,
 			next_id: data.id(),
@@ -186,6 +285,10 @@ impl AudioQueue {
 			last_buffer_size_min: SlidingWindowMinimum::new(LAST_BUFFER_SIZE_COUNT),
 			last_buffer_size_max: SlidingWindowMinimum::<Reverse<u8>>::new(LAST_BUFFER_SIZE_COUNT),
 			buffered_for_samples: 0,
+			lost_packets: 0,
+			speed_ups: 0,
+			truncations: 0,
+			late_packets: 0,
 		};
 		res.add_buffer_size(0);
 		res.add_packet(packet)?;
@@ -194,6 +297,20 @@ impl AudioQueue {
 
 	pub fn get_decoder(&self) -> &Decoder { &self.decoder }
 	pub fn is_whispering(&self) -> bool { self.whispering }
+	/// The amount of packets that are currently buffered in this queue.
+	pub fn get_buffered_packets(&self) -> usize { self.packet_buffer.len() }
+	pub fn get_config(&self) -> &AudioHandlerConfig { &self.config }
+
+	pub fn get_stats(&self) -> QueueStats {
+		QueueStats {
+			buffered_packets: self.packet_buffer.len(),
+			buffered_samples: self.packet_buffer_samples,
+			lost_packets: self.lost_packets,
+			speed_ups: self.speed_ups,
+			truncations: self.truncations,
+			late_packets: self.late_packets,
+		}
+	}
 
 	/// Size is in samples.
 	fn add_buffer_size(&mut self, size: usize) {
@@ -214,7 +331,8 @@ impl AudioQueue {
 
 	fn add_packet(&mut self, packet: InAudioBuf) -> Result<()> {
 		let _span = self.span.enter();
-		if self.packet_buffer.len() >= MAX_BUFFER_PACKETS {
+		let max_packets = self.config.max_buffer_packets;
+		if self.packet_buffer.len() >= max_packets {
 			return Err(Error::QueueFull);
 		}
 		let samples;
@@ -226,14 +344,15 @@ impl AudioQueue {
 				packet.data().data().data().try_into().map_err(Error::GetPacketSample)?;
 			samples =
 				packet::nb_samples(opus_packet, SAMPLE_RATE).map_err(Error::GetPacketSample)?;
-			if samples > MAX_BUFFER_SIZE {
+			if samples > self.config.max_buffer_size {
 				return Err(Error::TooManySamples);
 			}
 		}
 
 		let id = packet.data().data().id();
 		let packet = QueuePacket { packet, samples, id };
-		if id.wrapping_sub(self.next_id) > MAX_BUFFER_PACKETS as u16 {
+		if id.wrapping_sub(self.next_id) > max_packets as u16 {
+			self.late_packets += 1;
 			return Err(Error::TooLate { wanted: self.next_id, got: id });
 		}
 
@@ -244,7 +363,7 @@ impl AudioQueue {
 				.iter()
 				.enumerate()
 				.rev()
-				.take_while(|(_, p)| p.id.wrapping_sub(id) <= MAX_BUFFER_PACKETS as u16)
+				.take_while(|(_, p)| p.id.wrapping_sub(id) <= max_packets as u16)
 				.count();
 		// Check for duplicate packet
 		if let Some(p) = self.packet_buffer.get(i) {
@@ -284,6 +403,9 @@ impl AudioQueue {
 			len = self.last_packet_samples;
 		}
 		self.packet_loss_num += 1;
+		if packet.is_none() || fec {
+			self.lost_packets += 1;
+		}
 
 		self.decoded_buffer.resize(self.decoded_pos + len * CHANNEL_NUM, 0.0);
 		let len: usize = self
@@ -335,7 +457,7 @@ impl AudioQueue {
 	pub fn get_next_data(&mut self, len: usize) -> Result<(&[f32], bool)> {
 		let _span = self.span.clone().entered();
 		if self.buffering_samples > 0 {
-			if self.buffered_for_samples >= MAX_BUFFER_TIME {
+			if self.buffered_for_samples >= self.config.max_buffer_time {
 				self.buffering_samples = 0;
 				self.buffered_for_samples = 0;
 				trace!(
@@ -384,7 +506,7 @@ impl AudioQueue {
 				self.next_id = self.next_id.wrapping_add(1);
 				if packet.id != cur_id {
 					debug_assert!(
-						packet.id.wrapping_sub(cur_id) < MAX_BUFFER_PACKETS as u16,
+						packet.id.wrapping_sub(cur_id) < self.config.max_buffer_packets as u16,
 						"Invalid packet queue state: {} < {}",
 						packet.id,
 						cur_id
@@ -415,8 +537,10 @@ impl AudioQueue {
 			// Check if we should speed-up playback
 			let min = self.last_buffer_size_min.get_min();
 			let dev = self.get_deviation();
-			if min > (MAX_BUFFER_SIZE / USUAL_FRAME_SIZE) as u8 {
+			let max_frames = (self.config.max_buffer_size / USUAL_FRAME_SIZE).min(255) as u8;
+			if min > max_frames {
 				debug!(min, "Truncating buffer");
+				self.truncations += 1;
 				// Throw out all but min samples
 				let mut keep_samples = 0;
 				let keep = self
@@ -443,9 +567,11 @@ impl AudioQueue {
 					dev,
 					"Speed-up buffer"
 				);
+				self.speed_ups += 1;
+				let steps = self.config.speed_change_steps.max(2);
 				let start = self.decoded_buffer.len() - self.last_packet_samples * CHANNEL_NUM;
-				for i in 0..(self.last_packet_samples / SPEED_CHANGE_STEPS) {
-					let i = start + i * (SPEED_CHANGE_STEPS - 1) * CHANNEL_NUM;
+				for i in 0..(self.last_packet_samples / steps) {
+					let i = start + i * (steps - 1) * CHANNEL_NUM;
 					self.decoded_buffer.drain(i..(i + CHANNEL_NUM));
 				}
 			}
@@ -457,12 +583,31 @@ impl AudioQueue {
 }
 
 impl<Id: Clone + Debug + Eq + Hash + PartialEq> Default for AudioHandler<Id> {
-	fn default() -> Self { Self { queues: Default::default(), avg_buffer_samples: 0 } }
+	fn default() -> Self { Self::with_config(Default::default()) }
 }
 
 impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 	pub fn new() -> Self { Default::default() }
 
+	pub fn with_config(config: AudioHandlerConfig) -> Self {
+		Self { config, queues: Default::default(), taps: Vec::new(), avg_buffer_samples: 0 }
+	}
+
+	pub fn get_config(&self) -> &AudioHandlerConfig { &self.config }
+
+	/// Change the buffer parameters of this handler and all current queues.
+	pub fn set_config(&mut self, config: AudioHandlerConfig) {
+		self.config = config;
+		for queue in self.queues.values_mut() {
+			queue.config = config;
+		}
+	}
+
+	/// The statistics of all queues.
+	pub fn get_stats(&self) -> HashMap<Id, QueueStats> {
+		self.queues.iter().map(|(id, q)| (id.clone(), q.get_stats())).collect()
+	}
+
 	/// Delete all queues
 	pub fn reset(&mut self) { self.queues.clear(); }
 
@@ -483,26 +628,74 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 	///
 	/// Returns the clients that are not talking anymore.
 	pub fn fill_buffer_with_proc<F: FnMut(&Id, &[f32])>(
-		&mut self, buf: &mut [f32], mut handle: F,
+		&mut self, buf: &mut [f32], handle: F,
+	) -> Vec<Id> {
+		self.fill(buf.len(), Some(buf), handle)
+	}
+
+	/// Decode `len` samples of every speaker into separate buffers.
+	///
+	/// The speakers are also mixed into `mix` if it is set. Speakers which
+	/// stopped talking are returned with `is_end` set.
+	pub fn fill_speakers(&mut self, len: usize, mix: Option<&mut [f32]>) -> Vec<SpeakerAudio<Id>> {
+		let mut res = Vec::new();
+		let ended = self.fill(len, mix, |id, samples| {
+			res.push(SpeakerAudio { id: id.clone(), samples: samples.to_vec(), is_end: false })
+		});
+		for id in ended {
+			if let Some(audio) = res.iter_mut().find(|a| a.id == id) {
+				audio.is_end = true;
+			} else {
+				res.push(SpeakerAudio { id, samples: Vec::new(), is_end: true });
+			}
+		}
+		res
+	}
+
+	/// Receive the audio of every speaker whenever this handler is filling a
+	/// buffer.
+	///
+	/// The channel buffers `capacity` frames, but at least one. Frames are
+	/// dropped if the receiver does not keep up. The tap is removed when the receiver is
+	/// dropped.
+	pub fn add_tap(&mut self, capacity: usize) -> mpsc::Receiver<SpeakerAudio<Id>> {
+		// The channel has space for one more message per sender
+		let (send, recv) = mpsc::channel(capacity.saturating_sub(1));
+		self.taps.push(send);
+		recv
+	}
+
+	fn fill<F: FnMut(&Id, &[f32])>(
+		&mut self, len: usize, mut mix: Option<&mut [f32]>, mut handle: F,
 	) -> Vec<Id> {
-		trace!(len = buf.len(), "Filling audio buffer");
+		trace!(len, "Filling audio buffer");
 		let mut to_remove = Vec::new();
+		let mut tapped = Vec::new();
+		let tap = !self.taps.is_empty();
 		for (id, queue) in self.queues.iter_mut() {
-			if queue.packet_loss_num >= MAX_PACKET_LOSSES {
+			if queue.packet_loss_num >= queue.config.max_packet_losses {
 				debug!(packet_loss_num = queue.packet_loss_num, "Removing talker");
 				to_remove.push(id.clone());
+				if tap {
+					tapped.push(SpeakerAudio { id: id.clone(), samples: Vec::new(), is_end: true });
+				}
 				continue;
 			}
 
 			let vol = queue.volume;
-			match queue.get_next_data(buf.len()) {
+			match queue.get_next_data(len) {
 				Err(error) => {
 					warn!(%error, "Failed to decode audio packet");
 				}
 				Ok((r, is_end)) => {
 					handle(id, r);
-					for i in 0..r.len() {
-						buf[i] += r[i] * vol;
+					if let Some(buf) = mix.as_deref_mut() {
+						for i in 0..r.len() {
+							buf[i] += r[i] * vol;
+						}
+					}
+					if tap {
+						tapped.push(SpeakerAudio { id: id.clone(), samples: r.to_vec(), is_end });
 					}
 					if is_end {
 						to_remove.push(id.clone());
@@ -514,6 +707,18 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 		for id in &to_remove {
 			self.queues.remove(id);
 		}
+		if tap {
+			for audio in tapped {
+				for t in &mut self.taps {
+					if let Err(error) = t.try_send(audio.clone()) {
+						if error.is_full() {
+							debug!("Audio tap is full, dropping frame");
+						}
+					}
+				}
+			}
+			self.taps.retain(|t| !t.is_closed());
+		}
 		to_remove
 	}
 
@@ -537,7 +742,7 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 
 			let _span = info_span!("audio queue", client = ?id);
 			trace!("Adding talker");
-			let mut queue = AudioQueue::new(packet)?;
+			let mut queue = AudioQueue::new(packet, self.config)?;
 			if !self.queues.is_empty() {
 				// Update avg_buffer_samples
 				self.avg_buffer_samples = USUAL_FRAME_SIZE
@@ -861,6 +1066,65 @@ mod test {
 		simulate(a)
 	}
 
+	#[test]
+	fn queue_stats() -> Result<()> {
+		let mut a = vec![SimulateAction::CreateEncoder];
+		for i in 0..3 {
+			a.push(SimulateAction::ReceivePacket(i, true));
+			a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, Some(i)));
+		}
+		a.push(SimulateAction::ReceivePacket(1, false));
+		a.push(SimulateAction::ReceivePacket(5, true));
+		a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, None));
+		a.push(SimulateAction::Check(Box::new(|h| {
+			let stats = h.get_stats()[&ClientId(0)];
+			assert_eq!(stats.late_packets, 1);
+			assert_eq!(stats.lost_packets, 1);
+			assert_eq!(stats.buffered_packets, 1);
+		})));
+		simulate(a)
+	}
+
+	#[test]
+	fn low_latency_config() {
+		let mut handler = AudioHandler::<ClientId>::with_config(AudioHandlerConfig::low_latency());
+		assert_eq!(handler.get_config().max_buffer_packets, 20);
+		handler.set_config(AudioHandlerConfig::deep_buffer());
+		assert_eq!(handler.get_config(), &AudioHandlerConfig::deep_buffer());
+	}
+
+	#[test]
+	fn speaker_taps() {
+		fn receive(handler: &mut AudioHandler, from: u16, id: u16, data: &[u8]) {
+			let packet =
+				OutAudio::new(&AudioData::S2C { id, codec: CodecType::OpusMusic, from, data });
+			let input = InAudioBuf::try_new(Direction::S2C, packet.into_vec()).unwrap();
+			handler.handle_packet(ClientId(from), input).unwrap();
+		}
+
+		create_logger();
+		let mut handler = AudioHandler::<ClientId>::new();
+		let mut tap = handler.add_tap(1);
+		receive(&mut handler, 1, 0, &[0, 0, 0, 0, 0, 0, 0]);
+		receive(&mut handler, 2, 0, &[0, 0, 0, 0, 0, 0, 0]);
+
+		let audio = handler.fill_speakers(48_000 / 100 * 2, None);
+		assert_eq!(audio.len(), 2);
+		let first = audio.iter().find(|a| a.id == ClientId(1)).unwrap();
+		assert_eq!(first.samples.len(), 48_000 / 100 * 2);
+		assert!(!first.is_end);
+		// The tap has space for a single frame, the second one is dropped
+		assert!(tap.try_next().unwrap().is_some());
+		assert!(tap.try_next().is_err());
+
+		// End of stream
+		receive(&mut handler, 1, 1, &[]);
+		let mut mix = vec![0.0; 48_000 / 100 * 2];
+		let audio = handler.fill_speakers(mix.len(), Some(&mut mix));
+		assert!(audio.iter().any(|a| a.id == ClientId(1) && a.is_end));
+		assert_eq!(handler.get_queues().len(), 1);
+	}
+
 	#[test]
 	fn packet_wrapping_loss() -> Result<()> {
 		let mut a = vec![SimulateAction::CreateEncoder];
@@ -0,0 +1,562 @@
+//! Relay voice between two connections.
+//!
+//! A [`Bridge`] takes the audio that connection A receives and sends it
+//! through connection B and the other way round, e.g. to connect channels on
+//! two different servers.
+//!
+//! The audio can be passed through as it is, which needs no decoding, but
+//! only one speaker per direction can be relayed at a time and the volume
+//! cannot be changed. With the `audio` feature, the audio can be decoded,
+//! mixed and encoded again instead.
+//!
+//! When passing audio through, [`Bridge::check_timeouts`] should be called
+//! regularly, so a speaker who stops without sending the end of the stream
+//! does not keep the floor.
+//!
+//! Audio of the bridge itself is never relayed, so two connections on the
+//! same server do not create an echo. The bridge also reports who is
+//! speaking, so the name of the speaker can e.g. be shown in the nickname or
+//! description of the relaying client.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use tsclientlib::bridge::{Bridge, BridgeItem, Side};
+//! # fn f(a: &tsclientlib::Connection, b: &mut tsclientlib::Connection,
+//! #     packet: tsproto_packets::packets::InAudioBuf) -> anyhow::Result<()> {
+//!
+//! let mut bridge = Bridge::new(Default::default());
+//! bridge.update_identity(Side::A, a.get_state()?);
+//! bridge.update_identity(Side::B, b.get_state()?);
+//!
+//! // For every StreamItem::Audio of connection A
+//! for item in bridge.handle_packet(Side::A, a.get_state()?, packet) {
+//!     match item {
+//!         BridgeItem::Audio { packet, .. } => b.send_audio(packet)?,
+//!         BridgeItem::Speaker { name, .. } => println!("Relaying {:?}", name),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "audio")]
+use audiopus::coder::Encoder;
+#[cfg(feature = "audio")]
+use thiserror::Error;
+#[cfg(feature = "audio")]
+use tracing::debug;
+use tsproto_packets::packets::{AudioData, CodecType, InAudioBuf, OutPacket};
+
+#[cfg(feature = "audio")]
+use crate::audio::AudioHandler;
+use crate::{data, whisper, ClientId, UidBuf};
+
+/// A passed through speaker loses the floor if no packet arrived for this
+/// time.
+const SPEAKER_TIMEOUT: Duration = Duration::from_millis(500);
+/// The amount of stereo samples in a 20 ms frame.
+#[cfg(feature = "audio")]
+const FRAME_SIZE: usize = 48_000 / 50 * 2;
+/// The maximum size of an opus frame is 1275 as from RFC6716.
+#[cfg(feature = "audio")]
+const MAX_OPUS_FRAME_SIZE: usize = 1275;
+
+#[cfg(feature = "audio")]
+type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(feature = "audio")]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+	#[error("Failed to create opus encoder: {0}")]
+	CreateEncoder(#[source] audiopus::Error),
+	#[error("Opus encode failed: {0}")]
+	Encode(#[source] audiopus::Error),
+}
+
+/// One of the two bridged connections.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Side {
+	A,
+	B,
+}
+
+/// How audio is relayed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BridgeMode {
+	/// Send the received opus data without decoding it.
+	///
+	/// Only one speaker per direction is relayed at a time and the volume of
+	/// the direction is ignored.
+	PassThrough,
+	/// Decode and mix all speakers and encode the result again.
+	///
+	/// Packets have to be sent by calling [`Bridge::tick`] every 20 ms.
+	#[cfg(feature = "audio")]
+	Reencode,
+}
+
+/// Settings for the audio that is relayed from one side to the other.
+///
+/// # Default
+///
+/// Enabled with a volume of 1 and unchanged names.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirectionConfig {
+	pub enabled: bool,
+	/// Multiply relayed audio with this factor.
+	pub volume: f32,
+	/// The name of the speaker that is reported to the other side, `{name}`
+	/// is replaced by the nickname.
+	pub name_format: String,
+	/// Use these names instead of `name_format` for certain nicknames.
+	pub names: HashMap<String, String>,
+}
+
+/// Parameters of a [`Bridge`].
+///
+/// # Default
+///
+/// Passes audio through in both directions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BridgeConfig {
+	pub mode: BridgeMode,
+	pub a_to_b: DirectionConfig,
+	pub b_to_a: DirectionConfig,
+}
+
+/// Output of a [`Bridge`].
+#[derive(Debug)]
+pub enum BridgeItem {
+	/// Send this packet through the connection of side `to`.
+	Audio { to: Side, packet: OutPacket },
+	/// The speakers which are relayed to side `to` changed, `None` if nobody
+	/// is talking anymore.
+	Speaker { to: Side, name: Option<String> },
+}
+
+/// Relays audio between two connections.
+pub struct Bridge {
+	config: BridgeConfig,
+	/// The uids of the clients of both connections.
+	own_uids: [Option<UidBuf>; 2],
+	/// The state per direction, indexed by the side the audio comes from.
+	directions: [Direction; 2],
+}
+
+#[derive(Default)]
+struct Direction {
+	/// The speaker whose audio is passed through.
+	speaker: Option<ClientId>,
+	/// The codec of the passed through speaker.
+	codec: Option<CodecType>,
+	last_packet: Option<Instant>,
+	/// The mapped names of the speakers.
+	names: HashMap<ClientId, String>,
+	/// The last name reported with [`BridgeItem::Speaker`].
+	reported: Option<String>,
+	#[cfg(feature = "audio")]
+	handler: AudioHandler,
+	#[cfg(feature = "audio")]
+	encoder: Option<Encoder>,
+}
+
+impl Side {
+	pub fn other(self) -> Self {
+		match self {
+			Side::A => Side::B,
+			Side::B => Side::A,
+		}
+	}
+
+	fn index(self) -> usize { self as usize }
+}
+
+impl Default for DirectionConfig {
+	fn default() -> Self {
+		Self { enabled: true, volume: 1.0, name_format: "{name}".into(), names: HashMap::new() }
+	}
+}
+
+impl DirectionConfig {
+	/// The name that is reported for a speaker with this nickname.
+	pub fn speaker_name(&self, name: &str) -> String {
+		self.names.get(name).cloned().unwrap_or_else(|| self.name_format.replace("{name}", name))
+	}
+}
+
+impl Default for BridgeConfig {
+	fn default() -> Self {
+		Self {
+			mode: BridgeMode::PassThrough,
+			a_to_b: Default::default(),
+			b_to_a: Default::default(),
+		}
+	}
+}
+
+impl BridgeConfig {
+	/// The settings for audio that comes from side `from`.
+	pub fn direction(&self, from: Side) -> &DirectionConfig {
+		match from {
+			Side::A => &self.a_to_b,
+			Side::B => &self.b_to_a,
+		}
+	}
+}
+
+impl Bridge {
+	pub fn new(config: BridgeConfig) -> Self {
+		Self { config, own_uids: Default::default(), directions: Default::default() }
+	}
+
+	pub fn get_config(&self) -> &BridgeConfig { &self.config }
+
+	/// Change the settings, this stops all currently relayed audio.
+	pub fn set_config(&mut self, config: BridgeConfig) {
+		self.config = config;
+		self.directions = Default::default();
+	}
+
+	/// Remember the uid of the client of a connection, its audio is never
+	/// relayed.
+	///
+	/// Has to be called after connecting.
+	pub fn update_identity(&mut self, side: Side, book: &data::Connection) {
+		let uid = book.clients.get(&book.own_client).and_then(|c| c.uid.clone());
+		self.set_own_uid(side, uid);
+	}
+
+	pub fn set_own_uid(&mut self, side: Side, uid: Option<UidBuf>) {
+		self.own_uids[side.index()] = uid;
+	}
+
+	/// If the audio of this client was sent by the bridge itself.
+	pub fn is_echo(&self, book: &data::Connection, client: ClientId) -> bool {
+		if client == book.own_client {
+			return true;
+		}
+		let uid = book.clients.get(&client).and_then(|c| c.uid.as_ref());
+		uid.map(|uid| self.own_uids.iter().any(|u| u.as_ref() == Some(uid))).unwrap_or(false)
+	}
+
+	/// Handle an audio packet that was received by the connection of side
+	/// `from`.
+	///
+	/// `book` is the state of this connection.
+	pub fn handle_packet(
+		&mut self, from: Side, book: &data::Connection, packet: InAudioBuf,
+	) -> Vec<BridgeItem> {
+		let client = match packet.data().data() {
+			AudioData::S2C { from, .. } | AudioData::S2CWhisper { from, .. } => ClientId(*from),
+			_ => return Vec::new(),
+		};
+		let config = self.config.direction(from);
+		if !config.enabled || self.is_echo(book, client) {
+			return Vec::new();
+		}
+		let name = book.clients.get(&client).map(|c| c.name.as_str()).unwrap_or_default();
+		let name = config.speaker_name(name);
+		let dir = &mut self.directions[from.index()];
+		dir.names.insert(client, name);
+
+		match self.config.mode {
+			BridgeMode::PassThrough => dir.pass_through(from.other(), client, &packet),
+			#[cfg(feature = "audio")]
+			BridgeMode::Reencode => {
+				if let Err(error) = dir.handler.handle_packet(client, packet) {
+					debug!(%error, "Failed to relay audio packet");
+				}
+				Vec::new()
+			}
+		}
+	}
+
+	/// End passed through streams if the speaker sent no packet for some time.
+	///
+	/// Should be called regularly when passing audio through, e.g. every
+	/// 100 ms.
+	pub fn check_timeouts(&mut self) -> Vec<BridgeItem> { self.end_timed_out(Instant::now()) }
+
+	fn end_timed_out(&mut self, now: Instant) -> Vec<BridgeItem> {
+		let mut res = Vec::new();
+		for from in [Side::A, Side::B].iter().copied() {
+			let dir = &mut self.directions[from.index()];
+			if dir.speaker.is_some() && dir.is_timed_out(now) {
+				let codec = dir.codec.unwrap_or(CodecType::OpusVoice);
+				dir.end(from.other(), codec, &mut res);
+			}
+		}
+		res
+	}
+
+	/// Encode the next 20 ms of relayed audio in both directions.
+	///
+	/// Has to be called every 20 ms when re-encoding, does nothing when audio
+	/// is passed through.
+	#[cfg(feature = "audio")]
+	pub fn tick(&mut self) -> Result<Vec<BridgeItem>> {
+		let mut res = Vec::new();
+		if self.config.mode != BridgeMode::Reencode {
+			return Ok(res);
+		}
+		for from in [Side::A, Side::B].iter().copied() {
+			let volume = self.config.direction(from).volume;
+			self.directions[from.index()].reencode(from.other(), volume, &mut res)?;
+		}
+		Ok(res)
+	}
+}
+
+impl Direction {
+	fn pass_through(&mut self, to: Side, client: ClientId, packet: &InAudioBuf) -> Vec<BridgeItem> {
+		let data = packet.data().data();
+		let is_end = data.data().is_empty();
+		let now = Instant::now();
+		let mut res = Vec::new();
+		if let Some(speaker) = self.speaker {
+			if speaker != client && self.is_timed_out(now) {
+				// The speaker did not end its stream, end it for the other side
+				self.end(to, data.codec(), &mut res);
+			} else if speaker != client {
+				return res;
+			}
+		}
+		if self.speaker.is_none() {
+			if is_end {
+				return res;
+			}
+			self.speaker = Some(client);
+			self.codec = Some(data.codec());
+			let name = self.names.get(&client).cloned();
+			res.extend(self.report(to, name));
+		}
+
+		self.last_packet = Some(now);
+		if is_end {
+			self.end(to, data.codec(), &mut res);
+		} else {
+			let packet = whisper::create_audio_packet(None, data.codec(), data.data());
+			res.push(BridgeItem::Audio { to, packet });
+		}
+		res
+	}
+
+	fn is_timed_out(&self, now: Instant) -> bool {
+		self.last_packet.map(|t| now.saturating_duration_since(t) > SPEAKER_TIMEOUT).unwrap_or(true)
+	}
+
+	#[cfg(feature = "audio")]
+	fn reencode(&mut self, to: Side, volume: f32, res: &mut Vec<BridgeItem>) -> Result<()> {
+		if self.handler.get_queues().is_empty() {
+			if self.reported.is_some() {
+				self.end(to, CodecType::OpusMusic, res);
+			}
+			return Ok(());
+		}
+
+		let mut buf = [0.0; FRAME_SIZE];
+		let mut names = self
+			.handler
+			.get_queues()
+			.keys()
+			.filter_map(|id| self.names.get(id).cloned())
+			.collect::<Vec<_>>();
+		names.sort();
+		for id in self.handler.fill_buffer(&mut buf) {
+			self.names.remove(&id);
+		}
+		if volume != 1.0 {
+			for s in &mut buf[..] {
+				*s *= volume;
+			}
+		}
+		if self.encoder.is_none() {
+			let encoder = Encoder::new(
+				audiopus::SampleRate::Hz48000,
+				audiopus::Channels::Stereo,
+				audiopus::Application::Audio,
+			)
+			.map_err(Error::CreateEncoder)?;
+			self.encoder = Some(encoder);
+		}
+		let encoder = self.encoder.as_mut().unwrap();
+		let mut output = [0; MAX_OPUS_FRAME_SIZE];
+		let len = encoder.encode_float(&buf[..], &mut output[..]).map_err(Error::Encode)?;
+
+		res.extend(self.report(to, Some(names.join(", "))));
+		let packet = whisper::create_audio_packet(None, CodecType::OpusMusic, &output[..len]);
+		res.push(BridgeItem::Audio { to, packet });
+		Ok(())
+	}
+
+	/// Stop relaying, send the end of the stream and report that nobody is
+	/// speaking anymore.
+	fn end(&mut self, to: Side, codec: CodecType, res: &mut Vec<BridgeItem>) {
+		self.speaker = None;
+		self.codec = None;
+		self.last_packet = None;
+		res.push(BridgeItem::Audio { to, packet: whisper::create_audio_packet(None, codec, &[]) });
+		res.extend(self.report(to, None));
+	}
+
+	/// Report the speaker name if it changed.
+	fn report(&mut self, to: Side, name: Option<String>) -> Option<BridgeItem> {
+		if self.reported == name {
+			return None;
+		}
+		self.reported = name.clone();
+		Some(BridgeItem::Speaker { to, name })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ts_bookkeeping::test_util::{create_populated_connection, enter_view, handle};
+	use tsproto_packets::packets::{Direction as PacketDirection, OutAudio};
+
+	use super::*;
+
+	fn packet(from: u16, id: u16, data: &[u8]) -> InAudioBuf {
+		let packet =
+			OutAudio::new(&AudioData::S2C { id, codec: CodecType::OpusVoice, from, data });
+		InAudioBuf::try_new(PacketDirection::S2C, packet.into_vec()).unwrap()
+	}
+
+	fn create_book() -> data::Connection {
+		let mut book = create_populated_connection();
+		handle(&mut book, &enter_view(3, 1, "Alice", 0));
+		handle(&mut book, &enter_view(4, 1, "Bob", 0));
+		handle(&mut book, &enter_view(5, 1, "Relay", 0));
+		book
+	}
+
+	fn speakers(items: &[BridgeItem]) -> Vec<Option<&str>> {
+		items
+			.iter()
+			.filter_map(|i| match i {
+				BridgeItem::Speaker { to: Side::B, name } => Some(name.as_deref()),
+				_ => None,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn pass_through() {
+		let book = create_book();
+		let mut config = BridgeConfig::default();
+		config.a_to_b.name_format = "[A] {name}".into();
+		config.a_to_b.names.insert("Bob".into(), "Robert".into());
+		let mut bridge = Bridge::new(config);
+		// The client of connection B is in the same channel
+		bridge.set_own_uid(Side::B, book.clients[&ClientId(5)].uid.clone());
+
+		let items = bridge.handle_packet(Side::A, &book, packet(3, 0, &[1, 2]));
+		assert_eq!(speakers(&items), vec![Some("[A] Alice")]);
+		assert!(matches!(items.last(), Some(BridgeItem::Audio { to: Side::B, .. })));
+		// Only one speaker at a time
+		assert!(bridge.handle_packet(Side::A, &book, packet(4, 0, &[1, 2])).is_empty());
+		// No echo
+		assert!(bridge.handle_packet(Side::A, &book, packet(5, 0, &[1, 2])).is_empty());
+		assert!(bridge.handle_packet(Side::A, &book, packet(2, 0, &[1, 2])).is_empty());
+
+		let items = bridge.handle_packet(Side::A, &book, packet(3, 1, &[]));
+		assert_eq!(speakers(&items), vec![None]);
+		let items = bridge.handle_packet(Side::A, &book, packet(4, 1, &[1, 2]));
+		assert_eq!(speakers(&items), vec![Some("Robert")]);
+	}
+	#[test]
+	fn pass_through_timeout() {
+		let book = create_book();
+		let mut bridge = Bridge::new(Default::default());
+		let items = bridge.handle_packet(Side::A, &book, packet(3, 0, &[1, 2]));
+		assert_eq!(speakers(&items), vec![Some("Alice")]);
+		assert!(bridge.check_timeouts().is_empty());
+
+		// Alice stops without sending the end of the stream
+		let items = bridge.end_timed_out(Instant::now() + SPEAKER_TIMEOUT * 2);
+		assert_eq!(speakers(&items), vec![None]);
+		assert!(matches!(items.first(), Some(BridgeItem::Audio { to: Side::B, .. })));
+		assert!(bridge.check_timeouts().is_empty());
+
+		let items = bridge.handle_packet(Side::A, &book, packet(4, 0, &[1, 2]));
+		assert_eq!(speakers(&items), vec![Some("Bob")]);
+	}
+
+	/// Relay a few packets of a sine wave and return the decoded audio.
+	#[cfg(feature = "audio")]
+	fn reencode(volume: f32) -> (Vec<BridgeItem>, Vec<f32>) {
+		use std::convert::TryInto;
+		use std::f32::consts::PI;
+
+		use audiopus::coder::Decoder;
+		use tsproto_packets::packets::InPacket;
+
+		let book = create_book();
+		let mut config = BridgeConfig { mode: BridgeMode::Reencode, ..Default::default() };
+		config.a_to_b.volume = volume;
+		let mut bridge = Bridge::new(config);
+
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Audio,
+		)
+		.unwrap();
+		let mut opus_output = [0; MAX_OPUS_FRAME_SIZE];
+		for i in 0..5 {
+			// 1 kHz
+			let samples = (0..FRAME_SIZE / 2)
+				.map(|s| ((i * FRAME_SIZE / 2 + s) as f32 / 48.0 * 2.0 * PI).sin() * 0.5)
+				.collect::<Vec<_>>();
+			let len = encoder.encode_float(&samples, &mut opus_output[..]).unwrap();
+			let p = packet(3, i as u16, &opus_output[..len]);
+			assert!(bridge.handle_packet(Side::A, &book, p).is_empty());
+		}
+
+		let mut items = Vec::new();
+		for _ in 0..30 {
+			items.extend(bridge.tick().unwrap());
+		}
+
+		let mut decoder =
+			Decoder::new(audiopus::SampleRate::Hz48000, audiopus::Channels::Stereo).unwrap();
+		let mut decoded = Vec::new();
+		for i in &items {
+			if let BridgeItem::Audio { to: Side::B, packet } = i {
+				let packet = InPacket::try_new(PacketDirection::C2S, packet.data()).unwrap();
+				let audio = packet.into_audio().unwrap();
+				let data = audio.data().data().data();
+				if data.is_empty() {
+					continue;
+				}
+				let mut buf = [0.0; FRAME_SIZE];
+				let input = Some(data.try_into().unwrap());
+				let output = (&mut buf[..]).try_into().unwrap();
+				let len = decoder.decode_float(input, output, false).unwrap();
+				decoded.extend_from_slice(&buf[..len * 2]);
+			}
+		}
+		(items, decoded)
+	}
+
+	#[cfg(feature = "audio")]
+	#[test]
+	fn reencode_volume() {
+		let (items, decoded) = reencode(1.0);
+		assert_eq!(speakers(&items), vec![Some("Alice"), None]);
+		assert!(items.iter().all(|i| match i {
+			BridgeItem::Audio { to, .. } | BridgeItem::Speaker { to, .. } => *to == Side::B,
+		}));
+		let loud = decoded.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+		assert!(loud > 0.1, "Relayed audio is too quiet ({})", loud);
+
+		let (_, decoded) = reencode(0.0);
+		let muted = decoded.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+		assert!(muted < 0.01, "Muted audio is too loud ({})", muted);
+	}
+}
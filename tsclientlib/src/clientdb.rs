@@ -0,0 +1,173 @@
+//! Browse the client database of a server.
+//!
+//! The database contains all clients that ever connected to the server, also
+//! the ones which are currently offline. It can be queried with the methods of
+//! [`SyncConnectionHandle`], e.g. [`client_db_list`] or [`client_db_info`].
+//!
+//! Unique ids, database ids and names are cached in a [`ClientDbCache`] when a
+//! query returns them, so repeated lookups do not need to ask the server.
+//!
+//! # Example
+//!
+//! Print the names of all clients in the database.
+//!
+//! ```no_run
+//! # use futures::prelude::*;
+//! # use tsclientlib::sync::SyncConnectionHandle;
+//! # async fn f(handle: SyncConnectionHandle) -> Result<(), tsclientlib::Error> {
+//! let mut pages = handle.client_db_pages(100);
+//! while let Some(page) = pages.try_next().await? {
+//!     for client in page {
+//!         println!("{} ({})", client.name, client.client_db_id.0);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`SyncConnectionHandle`]: crate::sync::SyncConnectionHandle
+//! [`client_db_list`]: crate::sync::SyncConnectionHandle::client_db_list
+//! [`client_db_info`]: crate::sync::SyncConnectionHandle::client_db_info
+
+use std::collections::HashMap;
+
+use ts_bookkeeping::messages::s2c;
+
+use crate::{data, ClientDbId, InMessage, Uid, UidBuf};
+
+/// A client in the database, returned by
+/// [`client_db_list`](crate::sync::SyncConnectionHandle::client_db_list).
+pub type DbClient = s2c::InClientDbListPart;
+/// A client found by
+/// [`client_db_find`](crate::sync::SyncConnectionHandle::client_db_find).
+pub type FoundDbClient = s2c::InClientDbFindPart;
+/// Details about a client in the database, returned by
+/// [`client_db_info`](crate::sync::SyncConnectionHandle::client_db_info).
+pub type DbClientInfo = s2c::InClientDbInfoPart;
+
+/// Maps between unique ids, database ids and names of clients.
+#[derive(Clone, Debug, Default)]
+pub struct ClientDbCache {
+	db_ids: HashMap<UidBuf, ClientDbId>,
+	uids: HashMap<ClientDbId, UidBuf>,
+	names: HashMap<ClientDbId, String>,
+}
+
+impl ClientDbCache {
+	pub fn new() -> Self { Self::default() }
+
+	/// Add a client to the cache.
+	///
+	/// The name is only updated if it is set.
+	pub fn insert(&mut self, db_id: ClientDbId, uid: UidBuf, name: Option<String>) {
+		if let Some(old) = self.uids.insert(db_id, uid.clone()) {
+			if old != uid {
+				self.db_ids.remove(&old);
+			}
+		}
+		self.db_ids.insert(uid, db_id);
+		if let Some(name) = name {
+			self.names.insert(db_id, name);
+		}
+	}
+
+	/// Remove a client, e.g. after it was deleted from the database.
+	pub fn remove(&mut self, db_id: ClientDbId) {
+		if let Some(uid) = self.uids.remove(&db_id) {
+			self.db_ids.remove(&uid);
+		}
+		self.names.remove(&db_id);
+	}
+
+	pub fn clear(&mut self) { *self = Self::default(); }
+
+	pub fn get_db_id(&self, uid: &Uid) -> Option<ClientDbId> { self.db_ids.get(uid).copied() }
+
+	pub fn get_uid(&self, db_id: ClientDbId) -> Option<&Uid> {
+		self.uids.get(&db_id).map(|u| u.as_ref())
+	}
+
+	/// The last known name of a client.
+	pub fn get_name(&self, db_id: ClientDbId) -> Option<&str> {
+		self.names.get(&db_id).map(|n| n.as_str())
+	}
+
+	/// Add all clients contained in a message.
+	pub fn update(&mut self, msg: &InMessage) {
+		match msg {
+			InMessage::ClientDbList(msg) => {
+				for c in msg.iter() {
+					self.insert(c.client_db_id, c.uid.clone(), Some(c.name.clone()));
+				}
+			}
+			InMessage::ClientDbFind(msg) => {
+				for c in msg.iter() {
+					self.insert(c.client_db_id, c.uid.clone(), Some(c.name.clone()));
+				}
+			}
+			InMessage::ClientDbInfo(msg) => {
+				for c in msg.iter() {
+					self.insert(c.database_id, c.uid.clone(), Some(c.name.clone()));
+				}
+			}
+			InMessage::ClientNameFromUid(msg) => {
+				for c in msg.iter() {
+					self.insert(c.client_db_id, c.client_uid.clone(), Some(c.name.clone()));
+				}
+			}
+			InMessage::ClientDbIdFromUid(msg) => {
+				for c in msg.iter() {
+					self.insert(c.client_db_id, c.client_uid.clone(), None);
+				}
+			}
+			_ => {}
+		}
+	}
+
+	/// Add all clients which are currently visible.
+	pub fn update_from_book(&mut self, book: &data::Connection) {
+		for c in book.clients.values() {
+			if let Some(uid) = &c.uid {
+				if c.database_id.0 != 0 {
+					self.insert(c.database_id, uid.clone(), Some(c.name.clone()));
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use base64::prelude::*;
+	use tsproto_packets::packets::{Direction, Flags, OutPacket, PacketType};
+
+	use super::*;
+
+	fn parse_msg(msg: &str) -> InMessage {
+		let header = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
+		InMessage::new(&header.header(), msg.as_bytes()).unwrap()
+	}
+
+	#[test]
+	fn cache() {
+		let mut cache = ClientDbCache::new();
+		cache.update(&parse_msg(concat!(
+			"notifyclientdblist cldbid=2 client_unique_identifier=uA0U7t4PBxdJ5TLnarsOHQh4\\/tY= ",
+			"client_nickname=Splamy client_created=1537603744 client_lastconnected=1539260951 ",
+			"client_totalconnections=20 client_description client_lastip=::1"
+		)));
+		let uid = UidBuf(BASE64_STANDARD.decode("uA0U7t4PBxdJ5TLnarsOHQh4/tY=").unwrap());
+		assert_eq!(cache.get_db_id(&uid), Some(ClientDbId(2)));
+		assert_eq!(cache.get_uid(ClientDbId(2)), Some(uid.as_ref()));
+		assert_eq!(cache.get_name(ClientDbId(2)), Some("Splamy"));
+
+		cache.update(&parse_msg(
+			"notifyclientnamefromuid cluid=uA0U7t4PBxdJ5TLnarsOHQh4\\/tY= cldbid=2 name=Bob",
+		));
+		assert_eq!(cache.get_name(ClientDbId(2)), Some("Bob"));
+
+		cache.remove(ClientDbId(2));
+		assert_eq!(cache.get_db_id(&uid), None);
+		assert_eq!(cache.get_name(ClientDbId(2)), None);
+	}
+}
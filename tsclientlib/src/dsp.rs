@@ -0,0 +1,366 @@
+//! Process raw audio before it is sent or played.
+//!
+//! A [`DspChain`] runs a list of [`Processor`]s on interleaved 48 kHz samples.
+//! The usual chain can be built from a [`DspConfig`], which contains a
+//! high-pass filter, a noise gate, automatic gain control, a fixed gain and a
+//! soft limiter. Own processors can be added with [`DspChain::push`].
+//!
+//! # Example
+//!
+//! ```
+//! use tsclientlib::dsp::{DspChain, DspConfig, Processor};
+//!
+//! let mut chain = DspChain::new(&DspConfig::capture());
+//! let mut frame = vec![0.0; 960];
+//! chain.process(&mut frame, 1);
+//! ```
+
+use std::f32::consts::PI;
+use std::fmt::Debug;
+
+use crate::vad;
+
+/// The sample rate of all processed audio.
+pub const SAMPLE_RATE: u32 = 48_000;
+
+/// Modifies audio samples in place.
+pub trait Processor: Debug + Send {
+	/// Process interleaved samples with the given number of channels.
+	fn process(&mut self, samples: &mut [f32], channels: usize);
+	/// Forget the state, e.g. when a new stream starts.
+	fn reset(&mut self) {}
+}
+
+/// Parameters for the processors of a [`DspChain`].
+///
+/// # Default
+///
+/// All processors are disabled and the gain is 0 dB, so the audio is not
+/// changed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DspConfig {
+	/// The cutoff frequency of the high-pass filter in Hz.
+	pub high_pass: Option<f32>,
+	/// Mute audio below this level in dBFS.
+	pub noise_gate: Option<f32>,
+	/// The level in dBFS which automatic gain control tries to reach.
+	pub agc: Option<f32>,
+	/// A fixed gain in dB.
+	pub gain: f32,
+	/// Softly limit samples above this amplitude, between 0 and 1.
+	pub limiter: Option<f32>,
+}
+
+/// Runs processors after each other.
+#[derive(Debug, Default)]
+pub struct DspChain {
+	processors: Vec<Box<dyn Processor>>,
+}
+
+/// Multiplies all samples with a fixed factor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gain {
+	pub factor: f32,
+}
+
+/// First order high-pass filter, removes rumble and DC offsets.
+#[derive(Clone, Debug)]
+pub struct HighPass {
+	alpha: f32,
+	/// The last input and output sample per channel.
+	last: Vec<(f32, f32)>,
+}
+
+/// Mutes audio while it is quieter than a threshold.
+#[derive(Clone, Debug)]
+pub struct NoiseGate {
+	/// The threshold in dBFS.
+	pub threshold: f32,
+	/// How long the gate stays open after the last loud frame in seconds.
+	pub hold: f32,
+	gain: f32,
+	quiet_time: f32,
+}
+
+/// Automatic gain control, slowly adjusts the gain to reach a target level.
+#[derive(Clone, Debug)]
+pub struct Agc {
+	/// The target level in dBFS.
+	pub target: f32,
+	/// The maximum gain in dB.
+	pub max_gain: f32,
+	/// Frames quieter than this level in dBFS do not increase the gain.
+	pub noise_floor: f32,
+	/// The current gain in dB.
+	gain: f32,
+}
+
+/// Compresses samples above a threshold so they never reach full scale.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoftLimiter {
+	pub threshold: f32,
+}
+
+impl DspConfig {
+	/// A chain for microphone audio: high-pass at 80 Hz, noise gate at
+	/// -55 dBFS, gain control to -20 dBFS and a limiter at 0.9.
+	pub fn capture() -> Self {
+		Self {
+			high_pass: Some(80.0),
+			noise_gate: Some(-55.0),
+			agc: Some(-20.0),
+			gain: 0.0,
+			limiter: Some(0.9),
+		}
+	}
+
+	/// A chain for played audio, only limits to 0.9 to prevent clipping.
+	pub fn playback() -> Self { Self { limiter: Some(0.9), ..Self::default() } }
+}
+
+impl DspChain {
+	/// Create the processors for a configuration.
+	pub fn new(config: &DspConfig) -> Self {
+		let mut res = Self::default();
+		if let Some(cutoff) = config.high_pass {
+			res.push(HighPass::new(cutoff));
+		}
+		if let Some(threshold) = config.noise_gate {
+			res.push(NoiseGate::new(threshold));
+		}
+		if let Some(target) = config.agc {
+			res.push(Agc::new(target));
+		}
+		if config.gain != 0.0 {
+			res.push(Gain::from_db(config.gain));
+		}
+		if let Some(threshold) = config.limiter {
+			res.push(SoftLimiter { threshold });
+		}
+		res
+	}
+
+	/// Append a processor to the end of the chain.
+	pub fn push<P: Processor + 'static>(&mut self, processor: P) {
+		self.processors.push(Box::new(processor));
+	}
+
+	pub fn is_empty(&self) -> bool { self.processors.is_empty() }
+	pub fn clear(&mut self) { self.processors.clear(); }
+}
+
+impl Processor for DspChain {
+	fn process(&mut self, samples: &mut [f32], channels: usize) {
+		for p in &mut self.processors {
+			p.process(samples, channels);
+		}
+	}
+
+	fn reset(&mut self) {
+		for p in &mut self.processors {
+			p.reset();
+		}
+	}
+}
+
+impl Gain {
+	pub fn from_db(db: f32) -> Self { Self { factor: db_to_factor(db) } }
+}
+
+impl Processor for Gain {
+	fn process(&mut self, samples: &mut [f32], _: usize) {
+		for s in samples {
+			*s *= self.factor;
+		}
+	}
+}
+
+impl HighPass {
+	pub fn new(cutoff: f32) -> Self {
+		let rc = 1.0 / (2.0 * PI * cutoff);
+		let dt = 1.0 / SAMPLE_RATE as f32;
+		Self { alpha: rc / (rc + dt), last: Vec::new() }
+	}
+}
+
+impl Processor for HighPass {
+	fn process(&mut self, samples: &mut [f32], channels: usize) {
+		self.last.resize(channels, (0.0, 0.0));
+		for frame in samples.chunks_mut(channels) {
+			for (s, (last_in, last_out)) in frame.iter_mut().zip(&mut self.last) {
+				let out = self.alpha * (*last_out + *s - *last_in);
+				*last_in = *s;
+				*last_out = out;
+				*s = out;
+			}
+		}
+	}
+
+	fn reset(&mut self) { self.last.clear(); }
+}
+
+impl NoiseGate {
+	/// Create a closed gate with 200 ms hold time.
+	pub fn new(threshold: f32) -> Self {
+		Self { threshold, hold: 0.2, gain: 0.0, quiet_time: f32::INFINITY }
+	}
+}
+
+impl Processor for NoiseGate {
+	fn process(&mut self, samples: &mut [f32], channels: usize) {
+		if vad::level(samples) >= self.threshold {
+			self.quiet_time = 0.0;
+		} else {
+			self.quiet_time += duration(samples, channels);
+		}
+		let target = if self.quiet_time <= self.hold { 1.0 } else { 0.0 };
+		ramp(samples, channels, self.gain, target);
+		self.gain = target;
+	}
+
+	fn reset(&mut self) {
+		self.gain = 0.0;
+		self.quiet_time = f32::INFINITY;
+	}
+}
+
+impl Agc {
+	/// Create an automatic gain control with at most 30 dB gain.
+	pub fn new(target: f32) -> Self {
+		Self { target, max_gain: 30.0, noise_floor: -60.0, gain: 0.0 }
+	}
+
+	/// The current gain in dB.
+	pub fn get_gain(&self) -> f32 { self.gain }
+}
+
+impl Processor for Agc {
+	fn process(&mut self, samples: &mut [f32], channels: usize) {
+		let level = vad::level(samples);
+		let wanted = (self.target - level).clamp(-self.max_gain, self.max_gain);
+		// Reduce the gain fast (20 dB/s) and increase it slowly (5 dB/s)
+		let time = duration(samples, channels);
+		let old = self.gain;
+		if wanted < self.gain {
+			self.gain = (self.gain - 20.0 * time).max(wanted);
+		} else if level > self.noise_floor {
+			self.gain = (self.gain + 5.0 * time).min(wanted);
+		}
+		ramp(samples, channels, db_to_factor(old), db_to_factor(self.gain));
+	}
+
+	fn reset(&mut self) { self.gain = 0.0; }
+}
+
+impl Processor for SoftLimiter {
+	fn process(&mut self, samples: &mut [f32], _: usize) {
+		let t = self.threshold.clamp(0.0, 0.99);
+		for s in samples {
+			let abs = s.abs();
+			if abs > t {
+				*s = s.signum() * (t + (1.0 - t) * ((abs - t) / (1.0 - t)).tanh());
+			}
+		}
+	}
+}
+
+/// Convert samples to 16 bit, clipping values outside of `-1` to `1`.
+pub fn to_i16(samples: &[f32]) -> Vec<i16> {
+	samples.iter().map(|s| (s.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16).collect()
+}
+
+pub fn db_to_factor(db: f32) -> f32 { 10f32.powf(db / 20.0) }
+
+/// Resample mono audio with linear interpolation.
+pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+	if from == to || samples.is_empty() {
+		return samples.to_vec();
+	}
+	let step = f64::from(from) / f64::from(to);
+	let len = (samples.len() as f64 / step) as usize;
+	(0..len)
+		.map(|i| {
+			let pos = i as f64 * step;
+			let index = pos as usize;
+			let frac = (pos - index as f64) as f32;
+			let next = samples.get(index + 1).unwrap_or(&samples[index]);
+			samples[index] * (1.0 - frac) + next * frac
+		})
+		.collect()
+}
+
+/// The duration of samples in seconds.
+fn duration(samples: &[f32], channels: usize) -> f32 {
+	(samples.len() / channels.max(1)) as f32 / SAMPLE_RATE as f32
+}
+
+/// Multiply with a gain that changes linearly over the samples to prevent
+/// clicks.
+fn ramp(samples: &mut [f32], channels: usize, from: f32, to: f32) {
+	if from == 1.0 && to == 1.0 {
+		return;
+	}
+	let frames = (samples.len() / channels.max(1)).max(1) as f32;
+	for (i, frame) in samples.chunks_mut(channels.max(1)).enumerate() {
+		let gain = from + (to - from) * (i + 1) as f32 / frames;
+		for s in frame {
+			*s *= gain;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sine(amplitude: f32, len: usize) -> Vec<f32> {
+		let step = 2.0 * PI * 440.0 / SAMPLE_RATE as f32;
+		(0..len).map(|i| amplitude * (i as f32 * step).sin()).collect()
+	}
+
+	#[test]
+	fn limiter_and_gain() {
+		let mut chain = DspChain::new(&DspConfig { gain: 12.0, ..DspConfig::playback() });
+		let mut samples = sine(0.8, 960);
+		chain.process(&mut samples, 1);
+		assert!(samples.iter().all(|s| s.abs() <= 1.0));
+		assert!(samples.iter().any(|s| s.abs() > 0.95));
+
+		assert_eq!(to_i16(&[0.0, 1.0, -2.0]), vec![0, i16::MAX, -i16::MAX]);
+		assert_eq!(resample(&[0.0, 1.0], 24_000, 48_000), vec![0.0, 0.5, 1.0, 1.0]);
+		assert_eq!(resample(&[0.0, 1.0, 2.0, 3.0], 48_000, 24_000), vec![0.0, 2.0]);
+	}
+
+	#[test]
+	fn high_pass_removes_offset() {
+		let mut filter = HighPass::new(80.0);
+		let mut samples = vec![0.5; 4800];
+		filter.process(&mut samples, 2);
+		assert!(samples[samples.len() - 1].abs() < 0.01);
+	}
+
+	#[test]
+	fn noise_gate() {
+		let mut gate = NoiseGate::new(-50.0);
+		let mut quiet = sine(0.001, 960);
+		gate.process(&mut quiet, 1);
+		assert!(quiet.iter().all(|s| *s == 0.0));
+
+		let mut loud = sine(0.5, 960);
+		gate.process(&mut loud, 1);
+		assert!(loud[480..].iter().any(|s| s.abs() > 0.4));
+	}
+
+	#[test]
+	fn agc_amplifies_quiet_audio() {
+		let mut agc = Agc::new(-20.0);
+		for _ in 0..100 {
+			agc.process(&mut sine(0.01, 960), 1);
+		}
+		assert!(agc.get_gain() > 9.0);
+		// Noise is not amplified
+		let mut agc = Agc::new(-20.0);
+		agc.process(&mut sine(0.0001, 960), 1);
+		assert_eq!(agc.get_gain(), 0.0);
+	}
+}
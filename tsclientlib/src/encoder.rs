@@ -0,0 +1,203 @@
+//! Settings of the opus encoder for sent audio.
+//!
+//! An [`EncoderConfig`] contains the bitrate, complexity and other options of
+//! the encoder. The expected packet loss can be adapted to the
+//! [`ConnectionStats`] and the bitrate can follow the codec quality of the
+//! channel we are in, like the TeamSpeak client does.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # #[cfg(feature = "audio")]
+//! # fn f(con: &tsclientlib::Connection, encoder: &mut audiopus::coder::Encoder)
+//! # -> Result<(), Box<dyn std::error::Error>> {
+//! use tsclientlib::encoder::EncoderConfig;
+//!
+//! let mut config = EncoderConfig::default();
+//! // On StreamItem::NetworkStatsUpdated and when changing the channel
+//! let changed = config.adapt_packet_loss(con.get_network_stats()?)
+//!     | config.follow_channel(con.get_state()?);
+//! if changed {
+//!     config.apply(encoder)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "audio")]
+use audiopus::coder::Encoder;
+#[cfg(feature = "audio")]
+use audiopus::{Application, Bitrate};
+use tsproto_packets::packets::CodecType;
+
+use crate::{data, Codec, ConnectionStats};
+
+/// The bitrate in bit/s of the opus voice codec for every quality from 0 to 10.
+const VOICE_BITRATES: [i32; 11] =
+	[4_096, 6_144, 8_192, 10_240, 12_288, 16_384, 20_480, 24_576, 28_672, 32_768, 40_960];
+/// The bitrate in bit/s of the opus music codec for every quality from 0 to 10.
+const MUSIC_BITRATES: [i32; 11] =
+	[8_192, 12_288, 16_384, 20_480, 24_576, 32_768, 40_960, 49_152, 65_536, 81_920, 98_304];
+
+/// The length of an opus frame.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FrameSize {
+	Ms10,
+	Ms20,
+	Ms40,
+	Ms60,
+}
+
+/// Settings of the opus encoder.
+///
+/// # Default
+///
+/// Voice with codec quality 7 (24.5 kbit/s), complexity 10, variable bitrate,
+/// forward error correction, no discontinuous transmission and 20 ms frames.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct EncoderConfig {
+	/// Optimize for music instead of voice and send as [`CodecType::OpusMusic`].
+	pub music: bool,
+	/// The bitrate in bit/s.
+	pub bitrate: i32,
+	/// The computational complexity from 0 to 10.
+	pub complexity: u8,
+	/// Use a variable bitrate.
+	pub vbr: bool,
+	/// Add forward error correction, so lost packets can be reconstructed.
+	pub fec: bool,
+	/// Discontinuous transmission, send less data in silence.
+	pub dtx: bool,
+	/// The length of a frame.
+	pub frame_size: FrameSize,
+	/// The expected packet loss in percent.
+	pub packet_loss: u8,
+}
+
+impl Default for EncoderConfig {
+	fn default() -> Self {
+		Self {
+			music: false,
+			bitrate: VOICE_BITRATES[7],
+			complexity: 10,
+			vbr: true,
+			fec: true,
+			dtx: false,
+			frame_size: FrameSize::Ms20,
+			packet_loss: 0,
+		}
+	}
+}
+
+impl FrameSize {
+	/// The length in milliseconds.
+	pub fn millis(self) -> usize {
+		match self {
+			Self::Ms10 => 10,
+			Self::Ms20 => 20,
+			Self::Ms40 => 40,
+			Self::Ms60 => 60,
+		}
+	}
+}
+
+impl EncoderConfig {
+	/// The amount of samples per channel in a frame.
+	pub fn frame_samples(&self) -> usize { 48_000 / 1000 * self.frame_size.millis() }
+
+	/// The codec which should be used for sent packets.
+	pub fn codec_type(&self) -> CodecType {
+		if self.music { CodecType::OpusMusic } else { CodecType::OpusVoice }
+	}
+
+	/// Set the bitrate for a TeamSpeak codec and quality from 0 to 10.
+	///
+	/// Returns `true` if something changed. Codecs other than opus are
+	/// ignored.
+	pub fn set_codec_quality(&mut self, codec: Codec, quality: u8) -> bool {
+		let (music, bitrates) = match codec {
+			Codec::OpusVoice => (false, &VOICE_BITRATES),
+			Codec::OpusMusic => (true, &MUSIC_BITRATES),
+			_ => return false,
+		};
+		let old = *self;
+		self.music = music;
+		self.bitrate = bitrates[usize::from(quality.min(10))];
+		old != *self
+	}
+
+	/// Use the codec and quality of the channel our own client is in.
+	///
+	/// Returns `true` if something changed.
+	pub fn follow_channel(&mut self, book: &data::Connection) -> bool {
+		let channel = book
+			.clients
+			.get(&book.own_client)
+			.and_then(|c| book.channels.get(&c.channel));
+		if let Some(channel) = channel {
+			self.set_codec_quality(channel.codec, channel.codec_quality.unwrap_or(7))
+		} else {
+			false
+		}
+	}
+
+	/// Set the expected packet loss to the loss of received audio packets.
+	///
+	/// Returns `true` if it changed.
+	pub fn adapt_packet_loss(&mut self, stats: &ConnectionStats) -> bool {
+		self.set_packet_loss(stats.get_packetloss_s2c_speech())
+	}
+
+	/// Set the expected packet loss from a ratio between 0 and 1.
+	///
+	/// Returns `true` if it changed.
+	pub fn set_packet_loss(&mut self, loss: f32) -> bool {
+		let loss = (loss * 100.0).round().clamp(0.0, 100.0) as u8;
+		let changed = self.packet_loss != loss;
+		self.packet_loss = loss;
+		changed
+	}
+
+	/// Change the settings of an encoder.
+	///
+	/// The frame size is not a setting of the encoder, the input has to be
+	/// split into [`frame_samples`](Self::frame_samples).
+	#[cfg(feature = "audio")]
+	pub fn apply(&self, encoder: &mut Encoder) -> Result<(), audiopus::Error> {
+		encoder.set_application(if self.music { Application::Audio } else { Application::Voip })?;
+		encoder.set_bitrate(Bitrate::BitsPerSecond(self.bitrate))?;
+		encoder.set_complexity(self.complexity.min(10))?;
+		encoder.set_vbr(self.vbr)?;
+		encoder.set_inband_fec(self.fec)?;
+		encoder.set_dtx(self.dtx)?;
+		encoder.set_packet_loss_perc(self.packet_loss.min(100))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ts_bookkeeping::test_util::{create_populated_connection, handle};
+
+	use super::*;
+
+	#[test]
+	fn follow_channel_and_loss() {
+		let mut config = EncoderConfig::default();
+		let mut book = create_populated_connection();
+		assert!(!config.follow_channel(&book));
+		assert_eq!(config.frame_samples(), 960);
+		config.frame_size = FrameSize::Ms60;
+		assert_eq!(config.frame_samples(), 2880);
+
+		handle(&mut book, "notifychanneledited cid=1 reasonid=10 channel_codec=5 \
+			channel_codec_quality=10");
+		assert!(config.follow_channel(&book));
+		assert_eq!(config.codec_type(), CodecType::OpusMusic);
+		assert_eq!(config.bitrate, 98_304);
+
+		assert!(config.set_packet_loss(0.123));
+		assert_eq!(config.packet_loss, 12);
+		assert!(!config.set_packet_loss(0.12));
+		assert!(!config.set_codec_quality(Codec::SpeexWideband, 3));
+	}
+}
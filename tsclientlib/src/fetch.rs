@@ -0,0 +1,267 @@
+//! Fetch optional data of channels and clients on demand.
+//!
+//! Some data is only sent by the server when it is requested:
+//! - The description of a channel in [`data::OptionalChannelData`]
+//! - Client variables like the version in [`data::OptionalClientData`]
+//! - Ping and traffic of a client in [`data::ConnectionClientData`]
+//!
+//! It can be fetched with the methods of [`SyncConnectionHandle`], e.g.
+//! [`fetch_optional_channel_data`]. The answer is stored in the book and the
+//! [`FetchTracker`] remembers when it was fetched. Data can also be refreshed
+//! in an interval with [`FetchTracker::set_auto_refresh`].
+//!
+//! The connection info of a list of clients or of all clients can be polled
+//! with [`FetchTracker::set_connection_info_polling`]. New data creates a
+//! `PropertyChanged` event for [`PropertyId::ConnectionClientData`]. Polling
+//! sends one command per client at once and costs anti-flood points, so the
+//! [`scheduler`](crate::scheduler) should be enabled to pace the commands. A
+//! warning is logged when polling all clients without it.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use tsclientlib::ClientId;
+//! # use tsclientlib::fetch::OptionalData;
+//! # use tsclientlib::sync::SyncConnectionHandle;
+//! # async fn f(mut handle: SyncConnectionHandle) -> Result<(), tsclientlib::Error> {
+//! let info = handle.fetch_connection_client_data(ClientId(2)).await?;
+//! println!("Ping: {:?} (at {})", info.data.ping, info.fetched);
+//!
+//! // Keep the ping up to date
+//! let data = OptionalData::ConnectionInfo(ClientId(2));
+//! handle.set_auto_refresh(data, Some(Duration::from_secs(5))).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`SyncConnectionHandle`]: crate::sync::SyncConnectionHandle
+//! [`PropertyId::ConnectionClientData`]: crate::events::PropertyId::ConnectionClientData
+//! [`fetch_optional_channel_data`]: crate::sync::SyncConnectionHandle::fetch_optional_channel_data
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::Context;
+use std::time::Duration;
+
+use futures::prelude::*;
+use time::OffsetDateTime;
+use tokio::time::{Instant, Sleep};
+use ts_bookkeeping::messages::{c2s, OutMessageTrait};
+use tsproto_packets::packets::OutCommand;
+
+use crate::{data, ChannelId, ClientId};
+
+/// Optional data which is only sent by the server on request.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OptionalData {
+	/// The description of a channel, stored in [`data::OptionalChannelData`].
+	ChannelDescription(ChannelId),
+	/// Variables of a client, stored in [`data::OptionalClientData`].
+	ClientVariables(ClientId),
+	/// Connection information of a client, stored in
+	/// [`data::ConnectionClientData`].
+	ConnectionInfo(ClientId),
+}
+
+/// The clients whose connection info is polled.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClientSelection {
+	/// All visible clients except our own client.
+	All,
+	Clients(Vec<ClientId>),
+}
+
+/// Fetched data together with the time it was received.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fetched<T> {
+	pub data: T,
+	pub fetched: OffsetDateTime,
+}
+
+/// Remembers when optional data was fetched and refreshes it.
+#[derive(Debug, Default)]
+pub struct FetchTracker {
+	fetched: HashMap<OptionalData, OffsetDateTime>,
+	/// The interval and the next time when data should be refreshed.
+	refresh: HashMap<OptionalData, (Duration, Instant)>,
+	/// Poll the connection info of all clients.
+	poll_all: Option<(Duration, Instant)>,
+	timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl OptionalData {
+	/// The command which requests this data.
+	pub fn request(&self) -> OutCommand {
+		match *self {
+			OptionalData::ChannelDescription(channel_id) => {
+				c2s::OutChannelDescriptionRequestPart { channel_id }.to_packet()
+			}
+			OptionalData::ClientVariables(client_id) => {
+				c2s::OutClientVariablesRequestPart { client_id }.to_packet()
+			}
+			OptionalData::ConnectionInfo(client_id) => {
+				c2s::OutClientConnectionInfoRequestPart { client_id }.to_packet()
+			}
+		}
+	}
+
+	/// If the channel or client of this data exists.
+	pub fn exists(&self, book: &data::Connection) -> bool {
+		match self {
+			OptionalData::ChannelDescription(id) => book.channels.contains_key(id),
+			OptionalData::ClientVariables(id) | OptionalData::ConnectionInfo(id) => {
+				book.clients.contains_key(id)
+			}
+		}
+	}
+}
+
+impl FetchTracker {
+	#[inline]
+	pub fn new() -> Self { Self::default() }
+
+	/// When the data was fetched the last time.
+	pub fn get_fetched(&self, data: OptionalData) -> Option<OffsetDateTime> {
+		self.fetched.get(&data).copied()
+	}
+
+	/// If the data was fetched less than `max_age` ago.
+	pub fn is_fresh(&self, data: OptionalData, max_age: Duration) -> bool {
+		self.get_fetched(data)
+			.map(|t| OffsetDateTime::now_utc() - t < max_age)
+			.unwrap_or_default()
+	}
+
+	pub fn get_auto_refresh(&self, data: OptionalData) -> Option<Duration> {
+		self.refresh.get(&data).map(|r| r.0)
+	}
+
+	/// Fetch the data every `interval` or stop refreshing it with `None`.
+	///
+	/// Refreshing stops when the channel or client is removed.
+	pub fn set_auto_refresh(&mut self, data: OptionalData, interval: Option<Duration>) {
+		if let Some(interval) = interval {
+			self.refresh.insert(data, (interval, Instant::now()));
+		} else {
+			self.refresh.remove(&data);
+		}
+	}
+
+	/// Poll the connection info of clients every `interval` or stop polling
+	/// with `None`.
+	///
+	/// Selecting single clients is the same as calling
+	/// [`set_auto_refresh`](Self::set_auto_refresh) for their
+	/// [`OptionalData::ConnectionInfo`].
+	pub fn set_connection_info_polling(
+		&mut self, clients: ClientSelection, interval: Option<Duration>,
+	) {
+		match clients {
+			ClientSelection::All => self.poll_all = interval.map(|i| (i, Instant::now())),
+			ClientSelection::Clients(clients) => {
+				for client in clients {
+					self.set_auto_refresh(OptionalData::ConnectionInfo(client), interval);
+				}
+			}
+		}
+	}
+
+	/// The interval in which the connection info of all clients is polled.
+	pub fn get_connection_info_polling(&self) -> Option<Duration> { self.poll_all.map(|p| p.0) }
+
+	pub(crate) fn set_fetched(&mut self, data: OptionalData, time: OffsetDateTime) {
+		self.fetched.insert(data, time);
+	}
+
+	/// Forget data of removed channels and clients.
+	pub(crate) fn retain(&mut self, book: &data::Connection) {
+		self.fetched.retain(|d, _| d.exists(book));
+		self.refresh.retain(|d, _| d.exists(book));
+	}
+
+	/// The data which should be refreshed now.
+	pub(crate) fn due(&mut self, now: Instant) -> Vec<OptionalData> {
+		let mut res = Vec::new();
+		for (data, (interval, next)) in &mut self.refresh {
+			if *next <= now {
+				res.push(*data);
+				*next = now + *interval;
+			}
+		}
+		res
+	}
+
+	/// If the connection info of all clients should be polled now.
+	pub(crate) fn poll_all_due(&mut self, now: Instant) -> bool {
+		if let Some((interval, next)) = &mut self.poll_all {
+			if *next <= now {
+				*next = now + *interval;
+				return true;
+			}
+		}
+		false
+	}
+
+	/// Wake up the task when the next data should be refreshed.
+	pub(crate) fn poll_timer(&mut self, cx: &mut Context, now: Instant) {
+		let poll_all = self.poll_all.map(|p| p.1);
+		if let Some(next) = self.refresh.values().map(|r| r.1).chain(poll_all).min() {
+			let deadline = next.max(now);
+			let timer = match &mut self.timer {
+				Some(timer) => {
+					if timer.deadline() != deadline {
+						timer.as_mut().reset(deadline);
+					}
+					timer
+				}
+				None => self.timer.insert(Box::pin(tokio::time::sleep_until(deadline))),
+			};
+			if timer.poll_unpin(cx).is_ready() {
+				cx.waker().wake_by_ref();
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn refresh() {
+		let mut tracker = FetchTracker::new();
+		let channel = OptionalData::ChannelDescription(ChannelId(1));
+		let client = OptionalData::ConnectionInfo(ClientId(2));
+		assert!(!tracker.is_fresh(channel, Duration::from_secs(10)));
+		tracker.set_fetched(channel, OffsetDateTime::now_utc());
+		assert!(tracker.is_fresh(channel, Duration::from_secs(10)));
+
+		tracker.set_auto_refresh(client, Some(Duration::from_secs(5)));
+		let now = Instant::now();
+		assert_eq!(tracker.due(now), vec![client]);
+		assert!(tracker.due(now + Duration::from_secs(4)).is_empty());
+		assert_eq!(tracker.due(now + Duration::from_secs(5)), vec![client]);
+
+		tracker.set_auto_refresh(client, None);
+		assert!(tracker.due(now + Duration::from_secs(20)).is_empty());
+		assert_eq!(tracker.get_auto_refresh(client), None);
+	}
+
+	#[test]
+	fn poll_connection_info() {
+		let mut tracker = FetchTracker::new();
+		let interval = Some(Duration::from_secs(2));
+		tracker.set_connection_info_polling(ClientSelection::Clients(vec![ClientId(3)]), interval);
+		tracker.set_connection_info_polling(ClientSelection::All, interval);
+		let now = Instant::now();
+		assert!(tracker.poll_all_due(now));
+		assert_eq!(tracker.due(now), vec![OptionalData::ConnectionInfo(ClientId(3))]);
+		assert!(!tracker.poll_all_due(now + Duration::from_secs(1)));
+		assert!(tracker.poll_all_due(now + Duration::from_secs(2)));
+
+		tracker.set_connection_info_polling(ClientSelection::All, None);
+		assert_eq!(tracker.get_connection_info_polling(), None);
+		assert!(!tracker.poll_all_due(now + Duration::from_secs(10)));
+	}
+}
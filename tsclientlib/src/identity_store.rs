@@ -0,0 +1,512 @@
+//! Load and save identities from and to disk.
+//!
+//! An [`IdentityStore`] is a directory where every identity is stored in its
+//! own `<name>.toml` file. The file contains the private key, the hash cash
+//! counter and an optional nickname. The private key can optionally be
+//! encrypted with a passphrase.
+//!
+//! Identities can also be imported from and exported to the `.ini` format that
+//! is used by the TeamSpeak client when exporting an identity.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use tsclientlib::identity_store::IdentityStore;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let store = IdentityStore::open("identities")?;
+//! let bot = store.load_or_create("bot", None)?;
+//! let options = tsclientlib::Connection::build("localhost").identity(bot.identity);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use aes::Aes256;
+use base64::prelude::*;
+use eax::{AeadInPlace, Eax, KeyInit};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use tsproto_types::crypto::EccKeyPrivP256;
+
+use crate::Identity;
+
+/// File extension of stored identities.
+const EXTENSION: &str = "toml";
+/// The number of pbkdf2 rounds used for new encrypted identities.
+const PBKDF2_ROUNDS: u32 = 100_000;
+/// Files with more rounds are rejected, so a corrupted file cannot block
+/// loading.
+const MAX_PBKDF2_ROUNDS: u32 = 10_000_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+	#[error("Wrong passphrase or corrupted identity {0:?}")]
+	Decrypt(String),
+	#[error("Failed to parse identity {0:?}: {1}")]
+	Deserialize(String, #[source] toml::de::Error),
+	#[error("Identity {0:?} already exists")]
+	Exists(String),
+	#[error("Invalid identity {0:?}: {1}")]
+	InvalidIdentity(String, &'static str),
+	#[error("Invalid identity key: {0}")]
+	InvalidKey(#[source] tsproto::Error),
+	#[error("Invalid identity name {0:?}")]
+	InvalidName(String),
+	#[error("Io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("Identity {0:?} not found")]
+	NotFound(String),
+	#[error("Identity {0:?} is encrypted but no passphrase was given")]
+	PassphraseRequired(String),
+	#[error("Failed to serialize identity {0:?}: {1}")]
+	Serialize(String, #[source] toml::ser::Error),
+}
+
+/// An identity together with the name it is stored under.
+#[derive(Clone, Debug)]
+pub struct StoredIdentity {
+	/// The name of the identity in the store.
+	///
+	/// Names may only contain ascii letters, digits, `-`, `_` and `.` and may
+	/// not start with a `.`.
+	pub name: String,
+	/// The nickname which should be used when connecting with this identity.
+	pub nickname: Option<String>,
+	pub identity: Identity,
+}
+
+/// A directory which contains identities.
+#[derive(Clone, Debug)]
+pub struct IdentityStore {
+	dir: PathBuf,
+}
+
+/// The content of an identity file.
+#[derive(Deserialize, Serialize)]
+struct IdentityFile {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	nickname: Option<String>,
+	counter: u64,
+	#[serde(default)]
+	max_counter: u64,
+	/// The base64 encoded private key, if it is not encrypted.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	key: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	encrypted: Option<EncryptedKey>,
+}
+
+/// A private key, encrypted with AES-256-EAX and a key derived by
+/// PBKDF2-HMAC-SHA256 from a passphrase.
+#[derive(Deserialize, Serialize)]
+struct EncryptedKey {
+	rounds: u32,
+	salt: String,
+	nonce: String,
+	/// The encrypted key, followed by the authentication tag.
+	key: String,
+}
+
+impl StoredIdentity {
+	pub fn new(name: String, identity: Identity) -> Self {
+		Self { name, nickname: None, identity }
+	}
+
+	/// Parse an identity in the format of the TeamSpeak client export.
+	///
+	/// The name of the identity is taken from the `id` entry.
+	pub fn from_ts_export(content: &str) -> Result<Self> {
+		let mut name = None;
+		let mut nickname = None;
+		let mut identity = None;
+		for line in content.lines() {
+			let line = line.trim();
+			if let Some((key, value)) = line.split_once('=') {
+				let value = value.trim();
+				let value = value
+					.strip_prefix('"')
+					.and_then(|v| v.strip_suffix('"'))
+					.unwrap_or(value);
+				match key.trim() {
+					"id" => name = Some(value.to_string()),
+					"nickname" if !value.is_empty() => nickname = Some(value.to_string()),
+					"identity" => identity = Some(value.to_string()),
+					_ => {}
+				}
+			}
+		}
+
+		let name = name.unwrap_or_default();
+		let identity = identity.ok_or_else(|| {
+			Error::InvalidIdentity(name.clone(), "Missing identity entry in TeamSpeak export")
+		})?;
+		let identity = Identity::new_from_ts_str(&identity).map_err(Error::InvalidKey)?;
+		Ok(Self { name, nickname, identity })
+	}
+
+	/// Export the identity in the format that can be imported by the
+	/// TeamSpeak client.
+	pub fn to_ts_export(&self) -> String {
+		format!(
+			"[Identity]\nid={}\nidentity=\"{}\"\nnickname={}\nphonetic_nickname=\n",
+			self.name,
+			self.identity.to_ts_str(),
+			self.nickname.as_deref().unwrap_or_default(),
+		)
+	}
+}
+
+impl IdentityStore {
+	/// Open the store in the given directory, the directory is created if it
+	/// does not exist.
+	pub fn open<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+		let dir = dir.into();
+		fs::create_dir_all(&dir)?;
+		Ok(Self { dir })
+	}
+
+	#[inline]
+	pub fn dir(&self) -> &Path { &self.dir }
+
+	/// The names of all stored identities, sorted alphabetically.
+	pub fn list(&self) -> Result<Vec<String>> {
+		let mut res = Vec::new();
+		for entry in fs::read_dir(&self.dir)? {
+			let path = entry?.path();
+			if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+				continue;
+			}
+			if let Some(name) = path.file_stem().and_then(|n| n.to_str()) {
+				if is_valid_name(name) {
+					res.push(name.to_string());
+				}
+			}
+		}
+		res.sort();
+		Ok(res)
+	}
+
+	pub fn contains(&self, name: &str) -> bool {
+		is_valid_name(name) && self.path(name).is_file()
+	}
+
+	/// If the stored identity needs a passphrase to be loaded.
+	pub fn is_encrypted(&self, name: &str) -> Result<bool> {
+		Ok(self.read_file(name)?.encrypted.is_some())
+	}
+
+	/// Load an identity.
+	///
+	/// The passphrase is only needed if the identity is encrypted.
+	pub fn load(&self, name: &str, passphrase: Option<&str>) -> Result<StoredIdentity> {
+		let file = self.read_file(name)?;
+		let key = match (&file.key, &file.encrypted) {
+			(_, Some(encrypted)) => {
+				let passphrase =
+					passphrase.ok_or_else(|| Error::PassphraseRequired(name.to_string()))?;
+				decrypt_key(name, encrypted, passphrase)?
+			}
+			(Some(key), None) => BASE64_STANDARD
+				.decode(key)
+				.map_err(|_| Error::InvalidIdentity(name.to_string(), "Key is not base64"))?,
+			(None, None) => {
+				return Err(Error::InvalidIdentity(name.to_string(), "Missing key"));
+			}
+		};
+		let key = EccKeyPrivP256::from_short(&key)
+			.map_err(|e| Error::InvalidKey(tsproto::Error::IdentityCrypto(e)))?;
+		let max_counter = file.max_counter.max(file.counter);
+		Ok(StoredIdentity {
+			name: name.to_string(),
+			nickname: file.nickname,
+			identity: Identity::new_with_max_counter(key, file.counter, max_counter),
+		})
+	}
+
+	/// Save an identity, overwriting an existing identity with the same name.
+	///
+	/// If a passphrase is given, the private key gets encrypted.
+	pub fn save(&self, identity: &StoredIdentity, passphrase: Option<&str>) -> Result<()> {
+		let name = &identity.name;
+		check_name(name)?;
+		let key = identity.identity.key().to_short();
+		let (key, encrypted) = match passphrase {
+			Some(passphrase) => (None, Some(encrypt_key(&key, passphrase))),
+			None => (Some(BASE64_STANDARD.encode(key)), None),
+		};
+		let file = IdentityFile {
+			nickname: identity.nickname.clone(),
+			counter: identity.identity.counter(),
+			max_counter: identity.identity.max_counter(),
+			key,
+			encrypted,
+		};
+		let content =
+			toml::to_string(&file).map_err(|e| Error::Serialize(name.to_string(), e))?;
+
+		// Write to a temporary file first so an existing identity is never lost
+		let path = self.path(name);
+		let tmp_path = self.dir.join(format!(".{}.{}.tmp", name, EXTENSION));
+		write_private(&tmp_path, content.as_bytes())?;
+		fs::rename(&tmp_path, &path)?;
+		Ok(())
+	}
+
+	/// Load an identity or create and save a new one if it does not exist.
+	pub fn load_or_create(&self, name: &str, passphrase: Option<&str>) -> Result<StoredIdentity> {
+		check_name(name)?;
+		if self.contains(name) {
+			return self.load(name, passphrase);
+		}
+		let identity = StoredIdentity::new(name.to_string(), Identity::create());
+		self.save(&identity, passphrase)?;
+		Ok(identity)
+	}
+
+	pub fn remove(&self, name: &str) -> Result<()> {
+		check_name(name)?;
+		fs::remove_file(self.path(name)).map_err(|e| not_found(name, e))
+	}
+
+	/// Rename a stored identity, fails if the new name is already used.
+	pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+		check_name(from)?;
+		check_name(to)?;
+		if self.contains(to) {
+			return Err(Error::Exists(to.to_string()));
+		}
+		fs::rename(self.path(from), self.path(to)).map_err(|e| not_found(from, e))
+	}
+
+	/// Import an identity from a TeamSpeak client export.
+	///
+	/// If no name is given, the name from the export is used. Characters that
+	/// are not allowed in names, like spaces, are replaced by `_`.
+	pub fn import_ts(
+		&self, content: &str, name: Option<&str>, passphrase: Option<&str>,
+	) -> Result<StoredIdentity> {
+		let mut identity = StoredIdentity::from_ts_export(content)?;
+		identity.name = match name {
+			Some(name) => name.to_string(),
+			None => sanitize_name(&identity.name),
+		};
+		check_name(&identity.name)?;
+		if self.contains(&identity.name) {
+			return Err(Error::Exists(identity.name));
+		}
+		self.save(&identity, passphrase)?;
+		Ok(identity)
+	}
+
+	/// Export a stored identity in the TeamSpeak client format.
+	pub fn export_ts(&self, name: &str, passphrase: Option<&str>) -> Result<String> {
+		Ok(self.load(name, passphrase)?.to_ts_export())
+	}
+
+	fn path(&self, name: &str) -> PathBuf { self.dir.join(format!("{}.{}", name, EXTENSION)) }
+
+	fn read_file(&self, name: &str) -> Result<IdentityFile> {
+		check_name(name)?;
+		let content = fs::read_to_string(self.path(name)).map_err(|e| not_found(name, e))?;
+		toml::from_str(&content).map_err(|e| Error::Deserialize(name.to_string(), e))
+	}
+}
+
+fn is_valid_name(name: &str) -> bool {
+	!name.is_empty()
+		&& !name.starts_with('.')
+		&& name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Replace characters which are not allowed in names.
+fn sanitize_name(name: &str) -> String {
+	let name = name
+		.trim()
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+		.collect::<String>();
+	name.trim_start_matches('.').to_string()
+}
+
+fn check_name(name: &str) -> Result<()> {
+	if is_valid_name(name) { Ok(()) } else { Err(Error::InvalidName(name.to_string())) }
+}
+
+fn not_found(name: &str, error: io::Error) -> Error {
+	if error.kind() == io::ErrorKind::NotFound {
+		Error::NotFound(name.to_string())
+	} else {
+		Error::Io(error)
+	}
+}
+
+/// Write a file that is only readable by the current user.
+fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+	let mut options = fs::OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	io::Write::write_all(&mut options.open(path)?, content)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+	let mut key = [0; 32];
+	pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, rounds, &mut key);
+	key
+}
+
+fn encrypt_key(key: &[u8], passphrase: &str) -> EncryptedKey {
+	let mut rng = rand::thread_rng();
+	let mut salt = [0; SALT_LEN];
+	let mut nonce = [0; NONCE_LEN];
+	rng.fill_bytes(&mut salt);
+	rng.fill_bytes(&mut nonce);
+
+	let cipher_key = derive_key(passphrase, &salt, PBKDF2_ROUNDS);
+	let cipher = Eax::<Aes256>::new(&cipher_key.into());
+	let mut data = key.to_vec();
+	let tag = cipher
+		.encrypt_in_place_detached(&nonce.into(), &[], &mut data)
+		.expect("Encrypting a private key cannot fail");
+	data.extend_from_slice(&tag);
+
+	EncryptedKey {
+		rounds: PBKDF2_ROUNDS,
+		salt: BASE64_STANDARD.encode(salt),
+		nonce: BASE64_STANDARD.encode(nonce),
+		key: BASE64_STANDARD.encode(data),
+	}
+}
+
+fn decrypt_key(name: &str, encrypted: &EncryptedKey, passphrase: &str) -> Result<Vec<u8>> {
+	let invalid = |msg| Error::InvalidIdentity(name.to_string(), msg);
+	let salt = BASE64_STANDARD.decode(&encrypted.salt).map_err(|_| invalid("Salt is not base64"))?;
+	let nonce =
+		BASE64_STANDARD.decode(&encrypted.nonce).map_err(|_| invalid("Nonce is not base64"))?;
+	let mut data =
+		BASE64_STANDARD.decode(&encrypted.key).map_err(|_| invalid("Key is not base64"))?;
+	if nonce.len() != NONCE_LEN {
+		return Err(invalid("Nonce has a wrong length"));
+	}
+	if data.len() < TAG_LEN {
+		return Err(invalid("Encrypted key is too short"));
+	}
+	if encrypted.rounds > MAX_PBKDF2_ROUNDS {
+		return Err(invalid("Too many key derivation rounds"));
+	}
+
+	let cipher_key = derive_key(passphrase, &salt, encrypted.rounds);
+	let cipher = Eax::<Aes256>::new(&cipher_key.into());
+	let tag = data.split_off(data.len() - TAG_LEN);
+	cipher
+		.decrypt_in_place_detached(nonce.as_slice().into(), &[], &mut data, tag.as_slice().into())
+		.map_err(|_| Error::Decrypt(name.to_string()))?;
+	Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+
+	const TEST_PRIV_KEY: &str =
+		"MG8DAgeAAgEgAiEA6rtKxDn/o/Bo50rNtAE5Ph3h2RKLHQ0gbFkvm2yA79kCIQCrfzAZts/\
+		 vHP+3MOetKLjNnpZXt4c6U3UB4gWLKR4H9AIgYTyJofmztcTBjq3KZcDdxu+G4RPVwE5vg8VaN2jbQao=";
+	const TEST_UID: &str = "test/9PZ9vww/Bpf5vJxtJhpz80=";
+
+	fn temp_store() -> IdentityStore {
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+		let dir = std::env::temp_dir().join(format!(
+			"tsclientlib-identity-store-{}-{}",
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		let _ = fs::remove_dir_all(&dir);
+		IdentityStore::open(dir).unwrap()
+	}
+
+	fn test_identity(name: &str) -> StoredIdentity {
+		let key = EccKeyPrivP256::import_str(TEST_PRIV_KEY).unwrap();
+		let mut identity = StoredIdentity::new(name.into(), Identity::new(key, 2792354));
+		identity.nickname = Some("Bot".into());
+		identity
+	}
+
+	#[test]
+	fn save_load_list() {
+		let store = temp_store();
+		store.save(&test_identity("bot-1"), None).unwrap();
+		store.save(&test_identity("bot-0"), None).unwrap();
+		assert_eq!(store.list().unwrap(), vec!["bot-0".to_string(), "bot-1".into()]);
+
+		let loaded = store.load("bot-1", None).unwrap();
+		assert_eq!(loaded.nickname.as_deref(), Some("Bot"));
+		assert_eq!(loaded.identity.counter(), 2792354);
+		assert_eq!(loaded.identity.key().to_pub().get_uid(), TEST_UID);
+
+		store.rename("bot-1", "bot-2").unwrap();
+		assert!(matches!(store.rename("bot-0", "bot-2"), Err(Error::Exists(_))));
+		store.remove("bot-0").unwrap();
+		assert_eq!(store.list().unwrap(), vec!["bot-2".to_string()]);
+		assert!(matches!(store.load("bot-0", None), Err(Error::NotFound(_))));
+		assert!(matches!(store.load("../bot-2", None), Err(Error::InvalidName(_))));
+		fs::remove_dir_all(store.dir()).unwrap();
+	}
+
+	#[test]
+	fn encrypted() {
+		let store = temp_store();
+		store.save(&test_identity("bot"), Some("secret")).unwrap();
+		assert!(store.is_encrypted("bot").unwrap());
+		let content = fs::read_to_string(store.path("bot")).unwrap();
+		assert!(!content.contains(&BASE64_STANDARD.encode(
+			EccKeyPrivP256::import_str(TEST_PRIV_KEY).unwrap().to_short()
+		)));
+
+		assert!(matches!(store.load("bot", None), Err(Error::PassphraseRequired(_))));
+		assert!(matches!(store.load("bot", Some("wrong")), Err(Error::Decrypt(_))));
+		let loaded = store.load("bot", Some("secret")).unwrap();
+		assert_eq!(loaded.identity.key().to_pub().get_uid(), TEST_UID);
+
+		// A corrupted file must not hang the key derivation
+		assert!(content.contains("rounds = 100000"));
+		let content = content.replace("rounds = 100000", &format!("rounds = {}", u32::MAX));
+		fs::write(store.path("bot"), content).unwrap();
+		assert!(matches!(store.load("bot", Some("secret")), Err(Error::InvalidIdentity(..))));
+		fs::remove_dir_all(store.dir()).unwrap();
+	}
+
+	#[test]
+	fn ts_export_roundtrip() {
+		let store = temp_store();
+		let export = test_identity("Exported").to_ts_export();
+		let imported = store.import_ts(&export, None, None).unwrap();
+		assert_eq!(imported.name, "Exported");
+		assert_eq!(imported.nickname.as_deref(), Some("Bot"));
+		assert!(matches!(store.import_ts(&export, None, None), Err(Error::Exists(_))));
+
+		let exported = store.export_ts("Exported", None).unwrap();
+		let identity = StoredIdentity::from_ts_export(&exported).unwrap();
+		assert_eq!(identity.identity.counter(), 2792354);
+		assert_eq!(identity.identity.key().to_pub().get_uid(), TEST_UID);
+
+		let export = test_identity(" My Bot ").to_ts_export();
+		assert_eq!(store.import_ts(&export, None, None).unwrap().name, "My_Bot");
+		fs::remove_dir_all(store.dir()).unwrap();
+	}
+}
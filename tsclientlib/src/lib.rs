@@ -14,13 +14,12 @@
 #![recursion_limit = "128"]
 
 use std::borrow::Cow;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
 use std::iter;
 use std::mem;
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
@@ -36,17 +35,37 @@ use ts_bookkeeping::messages::c2s;
 use ts_bookkeeping::messages::OutMessageTrait;
 use tsproto::client;
 use tsproto::connection::StreamItem as ProtoStreamItem;
+use tsproto::miner::{Miner, MinerControl};
 use tsproto::resend::ResenderState;
 use tsproto_packets::commands::{CommandItem, CommandParser};
 #[cfg(feature = "audio")]
 use tsproto_packets::packets::InAudioBuf;
 use tsproto_packets::packets::{InCommandBuf, OutCommand, OutPacket, PacketType};
 
+use crate::scheduler::{Priority, Scheduler, SchedulerConfig};
+
 #[cfg(feature = "audio")]
 pub mod audio;
+pub mod bridge;
+pub mod clientdb;
+pub mod dsp;
+pub mod encoder;
+pub mod fetch;
+#[cfg(feature = "identity-store")]
+pub mod identity_store;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "audio")]
+pub mod mixer;
 pub mod prelude;
 pub mod resolver;
+pub mod scheduler;
+pub mod soundboard;
 pub mod sync;
+pub mod transcribe;
+pub mod tts;
+pub mod vad;
+pub mod whisper;
 
 // The build environment of tsclientlib.
 git_testament::git_testament!(TESTAMENT);
@@ -59,11 +78,17 @@ pub use ts_bookkeeping::messages::s2c::InMessage;
 // TODO This is bad because it re-exports ConnectOptions
 pub use ts_bookkeeping::*;
 pub use tsproto::resend::{ConnectionStats, PacketStat};
+pub use tsproto::miner::Progress as IdentityLevelProgress;
 pub use tsproto::Identity;
-pub use tsproto_types::errors::Error as TsError;
+pub use tsproto_types::errors::{Error as TsError, ErrorCategory};
 
 /// Wait this time for initserver, in seconds.
 const INITSERVER_TIMEOUT: u64 = 5;
+/// Wait this time before retrying a command after a flood error, if the server
+/// does not tell us how long to wait.
+const FLOOD_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// Wait this time before retrying a command after other retryable errors.
+const RETRY_AFTER: Duration = Duration::from_secs(1);
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -78,6 +103,91 @@ pub struct CommandError {
 	#[source]
 	pub error: TsError,
 	pub missing_permission: Option<Permission>,
+	/// Additional information from the server.
+	pub extra_message: Option<String>,
+}
+
+impl CommandError {
+	pub fn new(error: TsError) -> Self {
+		Self { error, missing_permission: None, extra_message: None }
+	}
+
+	#[inline]
+	pub fn category(&self) -> ErrorCategory { self.error.category() }
+
+	#[inline]
+	pub fn is_retryable(&self) -> bool { self.error.is_retryable() }
+
+	/// How long to wait before sending the command again.
+	///
+	/// Returns `None` if the command should not be retried. For flood errors,
+	/// the time is taken from the server message if it contains one.
+	pub fn retry_after(&self) -> Option<Duration> {
+		if self.category() == ErrorCategory::Flood {
+			let secs = self.extra_message.as_deref().and_then(parse_seconds);
+			Some(secs.map(Duration::from_secs).unwrap_or(FLOOD_RETRY_AFTER))
+		} else if self.is_retryable() {
+			Some(RETRY_AFTER)
+		} else {
+			None
+		}
+	}
+
+	/// A message that explains the error to a user.
+	pub fn user_message(&self) -> String {
+		let message = self.error.get_message();
+		let mut res = match self.category() {
+			ErrorCategory::Flood => {
+				let secs = self.retry_after().unwrap_or(FLOOD_RETRY_AFTER).as_secs();
+				format!("Too many actions, please wait {} seconds and try again", secs)
+			}
+			ErrorCategory::Permission => match self.missing_permission {
+				Some(perm) => {
+					format!("You do not have the permission to do this (permission id {})", perm.0)
+				}
+				None => "You do not have the permission to do this".into(),
+			},
+			ErrorCategory::Auth => format!("Authentication failed: {}", message),
+			ErrorCategory::NotFound => format!("Not found: {}", message),
+			ErrorCategory::ServerSide => format!("The server failed to do this: {}", message),
+			_ => {
+				let mut chars = message.chars();
+				chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default()
+			}
+		};
+		if let Some(extra) = self.extra_message.as_deref().filter(|e| !e.is_empty()) {
+			if self.category() != ErrorCategory::Flood {
+				res.push_str(" (");
+				res.push_str(extra);
+				res.push(')');
+			}
+		}
+		res
+	}
+}
+
+/// Find a duration in seconds in a message like `please wait 10 seconds`.
+fn parse_seconds(message: &str) -> Option<u64> {
+	let mut words = message.split_whitespace().peekable();
+	while let Some(word) = words.next() {
+		if let Ok(secs) = word.parse() {
+			if words.peek().map(|w| w.starts_with("second")).unwrap_or_default() {
+				return Some(secs);
+			}
+		}
+	}
+	None
+}
+
+/// Undo channel changes which were applied to the book, newest first.
+fn revert_channel_edits(
+	book: &mut data::Connection, edits: &[data::PendingChannelEdit],
+	stream_items: &mut VecDeque<Result<StreamItem>>,
+) {
+	let events: Vec<_> = edits.iter().rev().flat_map(|e| book.revert_channel_edit(e)).collect();
+	if !events.is_empty() {
+		stream_items.push_back(Ok(StreamItem::BookEvents(events)));
+	}
 }
 
 #[derive(Debug, Error)]
@@ -105,6 +215,8 @@ pub enum Error {
 	IdentityLevel(u8),
 	#[error("The server requested an identity of level {needed}, but we already have level {have}")]
 	IdentityLevelCorrupted { needed: u8, have: u8 },
+	#[error("Increasing the identity level was canceled")]
+	IdentityLevelIncreaseCanceled,
 	#[error("Failed to increase identity level: Thread died")]
 	IdentityLevelIncreaseFailedThread,
 	#[error("We should be connected but the connection params do not exist")]
@@ -120,6 +232,10 @@ pub enum Error {
 	/// The connection is currently not connected to a server but is in the process of connecting.
 	#[error("Currently not connected")]
 	NotConnected,
+	/// Fetched data does not exist in the book, e.g. because the channel or
+	/// client is gone.
+	#[error("{0:?} not found")]
+	NotFound(fetch::OptionalData),
 	#[error("Failed to resolve address: {0}")]
 	ResolveAddress(#[source] Box<resolver::Error>),
 	#[error("Failed to send clientinit: {0}")]
@@ -149,6 +265,13 @@ pub trait OutCommandExt {
 
 	/// Sends the command without asking for an answer.
 	fn send(self, con: &mut Connection) -> Result<()>;
+
+	/// Like [`send_with_result`](Self::send_with_result), but puts the command
+	/// into the command queue with the given priority.
+	///
+	/// If no [`scheduler`](ConnectOptions::scheduler) is set, the command is
+	/// sent immediately.
+	fn send_with_priority(self, con: &mut Connection, priority: Priority) -> Result<MessageHandle>;
 }
 
 /// The result of a download request.
@@ -249,7 +372,8 @@ pub enum StreamItem {
 	/// The network statistics were updated.
 	///
 	/// This means e.g. the packet loss got a new value. Clients with audio probably want to update
-	/// the packet loss option of opus.
+	/// the packet loss option of opus, e.g. with
+	/// [`EncoderConfig::adapt_packet_loss`](encoder::EncoderConfig::adapt_packet_loss).
 	NetworkStatsUpdated,
 	/// A change related to audio.
 	AudioChange(AudioEvent),
@@ -279,6 +403,11 @@ struct ConnectedConnection {
 	/// Afterwards we can directly return a `TcpStream` in the event stream.
 	filetransfers: Vec<future::BoxFuture<'static, StreamItem>>,
 	connection_time: time::OffsetDateTime,
+	/// The command queue, if enabled.
+	scheduler: Option<Scheduler>,
+	/// Channel changes which are already applied to the book, indexed by the
+	/// return code of their command. They are reverted if the command fails.
+	pending_channel_edits: HashMap<u16, Vec<data::PendingChannelEdit>>,
 }
 
 enum ConnectionState {
@@ -290,24 +419,21 @@ enum ConnectionState {
 	IdentityLevelIncreasing {
 		/// We get the improved identity here.
 		recv: oneshot::Receiver<Identity>,
-		state: Arc<Mutex<IdentityIncreaseLevelState>>,
+		miner: MinerControl,
 	},
 	Connected {
 		con: ConnectedConnection,
 		book: data::Connection,
 	},
+	/// Connecting was aborted, e.g. because increasing the identity level was
+	/// canceled. The stream of events ends.
+	Stopped,
 }
 
 /// A wrapper to poll events from a connection. This is used so a user can drop
 /// and filter the stream of events without problems.
 struct EventStream<'a>(&'a mut Connection);
 
-enum IdentityIncreaseLevelState {
-	Computing,
-	/// Set to this state to cancel the computation.
-	Canceled,
-}
-
 /// The main type of this crate, which represents a connection to a server.
 ///
 /// After creating a connection with [`Connection::new`], the main way to interact with it is
@@ -391,6 +517,7 @@ impl Connection {
 			log_commands: false,
 			log_packets: false,
 			log_udp_packets: false,
+			scheduler: None,
 		}
 	}
 
@@ -636,27 +763,43 @@ impl Connection {
 		}
 
 		// Increase identity level
-		let state = Arc::new(Mutex::new(IdentityIncreaseLevelState::Computing));
+		let handle = Miner::new(identity, needed).start();
+		let miner = handle.control();
 		let (send, recv) = oneshot::channel();
-		// TODO Use tokio::blocking
-		// TODO Time estimate
 		std::thread::spawn(move || {
-			let mut identity = identity;
-			// TODO Check if canceled in between
-			identity.upgrade_level(needed);
-			let _ = send.send(identity);
+			let _ = send.send(handle.wait());
 		});
 
-		self.state = ConnectionState::IdentityLevelIncreasing { recv, state };
+		self.state = ConnectionState::IdentityLevelIncreasing { recv, miner };
 		Ok(())
 	}
 
 	/// Adds a `return_code` to the command and returns if the corresponding
 	/// answer is received. If an error occurs, the future will return an error.
-	fn send_command_with_result(&mut self, packet: OutCommand) -> Result<MessageHandle> {
+	fn send_command_with_result(
+		&mut self, packet: OutCommand, priority: Priority,
+	) -> Result<MessageHandle> {
 		self.update_on_outgoing_command(&packet);
-		if let ConnectionState::Connected { con, .. } = &mut self.state {
-			con.send_command_with_result(packet)
+		let edits = self.apply_channel_changes(&packet);
+		if let ConnectionState::Connected { con, book } = &mut self.state {
+			let handle = if let Some(scheduler) = &mut con.scheduler {
+				let code = con.cur_return_code;
+				con.cur_return_code = code.wrapping_add(1);
+				scheduler.push(packet, priority, Some(code));
+				MessageHandle(code)
+			} else {
+				match con.send_command_with_result(packet) {
+					Ok(r) => r,
+					Err(e) => {
+						revert_channel_edits(book, &edits, &mut self.stream_items);
+						return Err(e);
+					}
+				}
+			};
+			if !edits.is_empty() {
+				con.pending_channel_edits.insert(handle.0, edits);
+			}
+			Ok(handle)
 		} else {
 			Err(Error::NotConnected)
 		}
@@ -665,12 +808,61 @@ impl Connection {
 	fn send_command(&mut self, packet: OutCommand) -> Result<()> {
 		self.update_on_outgoing_command(&packet);
 		if let ConnectionState::Connected { con, .. } = &mut self.state {
-			con.send_command(packet)
+			if let Some(scheduler) = &mut con.scheduler {
+				scheduler.push(packet, Priority::Normal, None);
+				Ok(())
+			} else {
+				con.send_command(packet)
+			}
 		} else {
 			Err(Error::NotConnected)
 		}
 	}
 
+	/// Apply sent channel edits and moves to the book.
+	///
+	/// Only commands with a return code are applied, the returned changes are
+	/// reverted if the command fails.
+	fn apply_channel_changes(&mut self, cmd: &OutCommand) -> Vec<data::PendingChannelEdit> {
+		let mut pending = Vec::new();
+		let (cmd_name, _) = CommandParser::new(cmd.0.content());
+		if cmd_name != b"channeledit" && cmd_name != b"channelmove" {
+			return pending;
+		}
+		if let ConnectionState::Connected { book, .. } = &mut self.state {
+			let msg = match c2s::InMessage::new(&cmd.0.header(), cmd.0.content()) {
+				Ok(r) => r,
+				Err(error) => {
+					warn!(%error, "Failed to parse sent channel change");
+					return pending;
+				}
+			};
+			let results: Vec<_> = match &msg {
+				c2s::InMessage::ChannelEdit(msg) => {
+					msg.iter().map(|m| book.apply_channel_edit(m)).collect()
+				}
+				c2s::InMessage::ChannelMove(msg) => {
+					msg.iter().map(|m| book.apply_channel_move(m)).collect()
+				}
+				_ => return pending,
+			};
+			let mut events = Vec::new();
+			for res in results {
+				match res {
+					Ok((evs, edit)) => {
+						events.extend(evs);
+						pending.push(edit);
+					}
+					Err(error) => warn!(%error, "Failed to apply sent channel change"),
+				}
+			}
+			if !events.is_empty() {
+				self.stream_items.push_back(Ok(StreamItem::BookEvents(events)));
+			}
+		}
+		pending
+	}
+
 	/// Update for outgoing commands.
 	///
 	/// Updates subscription and muted state.
@@ -804,9 +996,24 @@ impl Connection {
 	/// does not wait until the background thread quits.
 	///
 	/// Does nothing if the identity level is currently not increased.
+	///
+	/// The stream of events returns an [`Error::IdentityLevelIncreaseCanceled`]
+	/// afterwards and ends. The identity in the [`ConnectOptions`] contains the progress
+	/// so far, so the computation can be resumed later.
 	pub fn cancel_identity_level_increase(&mut self) {
-		if let ConnectionState::IdentityLevelIncreasing { state, .. } = &mut self.state {
-			*state.lock().unwrap() = IdentityIncreaseLevelState::Canceled;
+		if let ConnectionState::IdentityLevelIncreasing { miner, .. } = &mut self.state {
+			miner.cancel();
+		}
+	}
+
+	/// The progress of increasing the identity level.
+	///
+	/// Returns `None` if the identity level is currently not increased.
+	pub fn get_identity_level_progress(&self) -> Option<IdentityLevelProgress> {
+		if let ConnectionState::IdentityLevelIncreasing { miner, .. } = &self.state {
+			Some(miner.progress())
+		} else {
+			None
 		}
 	}
 
@@ -846,6 +1053,18 @@ impl Connection {
 		})
 	}
 
+	/// The number of commands in the command queue which were not sent yet.
+	///
+	/// Returns `0` if no [`scheduler`](ConnectOptions::scheduler) is set or
+	/// the connection is currently not connected.
+	pub fn get_queued_commands(&self) -> usize {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			con.scheduler.as_ref().map(|s| s.len()).unwrap_or_default()
+		} else {
+			0
+		}
+	}
+
 	/// Get the current state of clients and channels of this connection.
 	///
 	/// Fails if the connection is currently not connected to the server.
@@ -1152,6 +1371,8 @@ impl Connection {
 						subscribed: false,
 						filetransfers: Default::default(),
 						connection_time: OffsetDateTime::now_utc(),
+						scheduler: self.options.scheduler.clone().map(Scheduler::new),
+						pending_channel_edits: Default::default(),
 					};
 					if Self::intern_can_send_audio(&book, &self.options) {
 						self.stream_items
@@ -1172,13 +1393,20 @@ impl Connection {
 					]))))
 				}
 			},
-			ConnectionState::IdentityLevelIncreasing { recv, .. } => match recv.poll_unpin(cx) {
+			ConnectionState::IdentityLevelIncreasing { recv, miner } => match recv.poll_unpin(cx) {
 				Poll::Pending => Poll::Pending,
 				Poll::Ready(Err(_)) => {
+					self.state = ConnectionState::Stopped;
 					Poll::Ready(Some(Err(Error::IdentityLevelIncreaseFailedThread)))
 				}
 				Poll::Ready(Ok(identity)) => {
+					let canceled =
+						miner.is_canceled() && identity.level() < miner.progress().target;
 					self.options.identity = Some(identity);
+					if canceled {
+						self.state = ConnectionState::Stopped;
+						return Poll::Ready(Some(Err(Error::IdentityLevelIncreaseCanceled)));
+					}
 					let fut = Self::connect(self.options.clone(), false);
 					self.state =
 						ConnectionState::Connecting(Box::pin(fut.in_current_span()), false);
@@ -1186,6 +1414,9 @@ impl Connection {
 				}
 			},
 			ConnectionState::Connected { con, book } => match loop {
+				if let Err(e) = con.poll_scheduler(cx, book) {
+					break Poll::Ready(Some(Err(e)));
+				}
 				match con.client.poll_next_unpin(cx) {
 					Poll::Pending => break Poll::Pending,
 					Poll::Ready(None) => break Poll::Ready(None),
@@ -1201,6 +1432,7 @@ impl Connection {
 						if let client::Error::TsProto(tsproto::Error::Timeout(reason)) = e {
 							// Reconnect on timeout
 							warn!(timeout = reason, "Connection failed, reconnecting");
+							con.connection_lost(book, &mut self.stream_items);
 							let fut = Self::connect(self.options.clone(), true);
 							self.state =
 								ConnectionState::Connecting(Box::pin(fut.in_current_span()), true);
@@ -1251,6 +1483,7 @@ impl Connection {
 								cmd,
 							) {
 								warn!("Server shut down, reconnecting");
+								con.connection_lost(book, &mut self.stream_items);
 								let fut = Self::connect(self.options.clone(), true);
 								self.state = ConnectionState::Connecting(
 									Box::pin(fut.in_current_span()),
@@ -1312,16 +1545,21 @@ impl Connection {
 					}
 				}
 			},
+			ConnectionState::Stopped => Poll::Ready(None),
 		}
 	}
 }
 
 impl<T: OutMessageTrait> OutCommandExt for T {
 	fn send_with_result(self, con: &mut Connection) -> Result<MessageHandle> {
-		con.send_command_with_result(self.to_packet())
+		con.send_command_with_result(self.to_packet(), Priority::Normal)
 	}
 
 	fn send(self, con: &mut Connection) -> Result<()> { con.send_command(self.to_packet()) }
+
+	fn send_with_priority(self, con: &mut Connection, priority: Priority) -> Result<MessageHandle> {
+		con.send_command_with_result(self.to_packet(), priority)
+	}
 }
 
 impl Drop for Connection {
@@ -1360,10 +1598,17 @@ impl ConnectedConnection {
 						Err(CommandError {
 							error: msg.id,
 							missing_permission: msg.missing_permission_id,
+							extra_message: msg.extra_message.clone(),
 						})
 					};
-					stream_items
-						.push_back(Ok(StreamItem::MessageResult(MessageHandle(ret_code), res)));
+					let handles = self
+						.scheduler
+						.as_mut()
+						.and_then(|s| s.handle_result(ret_code, &res))
+						.unwrap_or_else(|| vec![ret_code]);
+					for handle in handles {
+						self.command_finished(book, stream_items, handle, res.clone());
+					}
 				} else {
 					handled = false;
 				}
@@ -1423,7 +1668,11 @@ impl ConnectedConnection {
 		} else if let InMessage::FiletransferStatus(msg) = &msg {
 			for msg in msg.iter() {
 				let ft_id = FiletransferHandle(msg.client_filetransfer_id);
-				let err = CommandError { error: msg.status, missing_permission: None };
+				let err = CommandError {
+					error: msg.status,
+					missing_permission: None,
+					extra_message: None,
+				};
 				stream_items.push_back(Ok(StreamItem::FiletransferFailed(ft_id, err.into())));
 			}
 		} else if let InMessage::ClientConnectionInfoUpdateRequest(_) = &msg {
@@ -1617,7 +1866,7 @@ impl ConnectedConnection {
 
 	fn send_command_with_result(&mut self, mut packet: OutCommand) -> Result<MessageHandle> {
 		let code = self.cur_return_code;
-		self.cur_return_code += 1;
+		self.cur_return_code = code.wrapping_add(1);
 		packet.write_arg("return_code", &code);
 
 		self.send_command(packet).map(|_| MessageHandle(code))
@@ -1627,6 +1876,50 @@ impl ConnectedConnection {
 		self.client.send_packet(packet.into_packet()).map(|_| ()).map_err(Error::SendPacket)
 	}
 
+	/// Fail everything that waits for an answer of the server, before
+	/// reconnecting.
+	fn connection_lost(
+		&mut self, book: &mut data::Connection, stream_items: &mut VecDeque<Result<StreamItem>>,
+	) {
+		let handles = self.scheduler.as_mut().map(|s| s.connection_lost()).unwrap_or_default();
+		for handle in handles {
+			let error = CommandError::new(TsError::ConnectionLost);
+			self.command_finished(book, stream_items, handle, Err(error));
+		}
+		// Sent commands which are not answered anymore
+		let mut pending: Vec<_> = self.pending_channel_edits.drain().collect();
+		pending.sort_unstable_by_key(|(code, _)| std::cmp::Reverse(*code));
+		for (_, edits) in pending {
+			revert_channel_edits(book, &edits, stream_items);
+		}
+	}
+
+	/// Revert channel changes of a failed command and return the result.
+	fn command_finished(
+		&mut self, book: &mut data::Connection, stream_items: &mut VecDeque<Result<StreamItem>>,
+		handle: u16, res: std::result::Result<(), CommandError>,
+	) {
+		if let Some(edits) = self.pending_channel_edits.remove(&handle) {
+			if res.is_err() {
+				revert_channel_edits(book, &edits, stream_items);
+			}
+		}
+		stream_items.push_back(Ok(StreamItem::MessageResult(MessageHandle(handle), res)));
+	}
+
+	/// Send the commands from the queue that the anti-flood limits allow.
+	fn poll_scheduler(&mut self, cx: &mut Context, book: &data::Connection) -> Result<()> {
+		if let Some(scheduler) = &mut self.scheduler {
+			scheduler.update_limits(book);
+			let now = tokio::time::Instant::now();
+			while let Some(packet) = scheduler.pop(now, &mut self.cur_return_code) {
+				self.client.send_packet(packet.into_packet()).map_err(Error::SendPacket)?;
+			}
+			scheduler.poll_timer(cx, now);
+		}
+		Ok(())
+	}
+
 	fn download_file(
 		&mut self, channel_id: ChannelId, path: &str, channel_password: Option<&str>,
 		seek_position: Option<u64>,
@@ -1705,6 +1998,7 @@ pub struct ConnectOptions {
 	log_commands: bool,
 	log_packets: bool,
 	log_udp_packets: bool,
+	scheduler: Option<SchedulerConfig>,
 }
 
 impl ConnectOptions {
@@ -2001,6 +2295,19 @@ impl ConnectOptions {
 		self
 	}
 
+	/// Put commands into a queue that respects the anti-flood limits of the
+	/// server.
+	///
+	/// See the [`scheduler`] module for details.
+	///
+	/// # Default
+	/// Commands are sent immediately.
+	#[inline]
+	pub fn scheduler(mut self, scheduler: SchedulerConfig) -> Self {
+		self.scheduler = Some(scheduler);
+		self
+	}
+
 	#[inline]
 	pub fn get_address(&self) -> &ServerAddress { &self.address }
 	#[inline]
@@ -2039,4 +2346,6 @@ impl ConnectOptions {
 	pub fn get_log_packets(&self) -> bool { self.log_packets }
 	#[inline]
 	pub fn get_log_udp_packets(&self) -> bool { self.log_udp_packets }
+	#[inline]
+	pub fn get_scheduler(&self) -> Option<&SchedulerConfig> { self.scheduler.as_ref() }
 }
@@ -0,0 +1,428 @@
+//! Export statistics of connections in the Prometheus text format.
+//!
+//! [`Metrics`] collects numbers for multiple connections, each one identified by an id that is
+//! chosen by the user. It gets fed with the [`StreamItem`]s of a connection and with its
+//! [`ConnectionStats`]. The collected numbers can be served on an http `/metrics` endpoint with
+//! [`Metrics::serve`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use futures::prelude::*;
+//! # use tsclientlib::metrics::Metrics;
+//! # use tsclientlib::{Connection, StreamItem};
+//! # #[tokio::main]
+//! # async fn main() {
+//! let metrics = Metrics::<u64>::new();
+//! tokio::spawn(metrics.clone().serve("127.0.0.1:9100".parse().unwrap()));
+//!
+//! let mut con = Connection::build("localhost").connect().unwrap();
+//! loop {
+//!     let item = match con.events().next().await {
+//!         Some(item) => item.unwrap(),
+//!         None => break,
+//!     };
+//!     metrics.handle_item(&0, &item);
+//!     if let StreamItem::NetworkStatsUpdated = item {
+//!         metrics.update_network_stats(&0, con.get_network_stats().unwrap());
+//!     }
+//! }
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Write as _};
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::debug;
+
+use crate::{ConnectionStats, PacketStat, StreamItem, TsError};
+
+/// Wait this time for a scraper to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The maximum size of an http request that is read.
+const MAX_REQUEST_SIZE: usize = 4096;
+
+const PACKET_STATS: [(PacketStat, &str); 6] = [
+	(PacketStat::InControl, "in_control"),
+	(PacketStat::InKeepalive, "in_keepalive"),
+	(PacketStat::InSpeech, "in_speech"),
+	(PacketStat::OutControl, "out_control"),
+	(PacketStat::OutKeepalive, "out_keepalive"),
+	(PacketStat::OutSpeech, "out_speech"),
+];
+
+/// Collected numbers of a single connection.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionMetrics {
+	/// The last network statistics that were passed to [`Metrics::update_network_stats`].
+	pub network_stats: Option<ConnectionStats>,
+	/// Count of received `BookEvents` items.
+	pub book_events: u64,
+	/// Count of received `MessageEvent` items.
+	pub message_events: u64,
+	/// Count of received audio packets.
+	pub audio_packets: u64,
+	/// How often the connection was temporarily disconnected and reconnects.
+	pub reconnects: u64,
+	/// Count of commands that were answered successfully.
+	pub command_successes: u64,
+	/// Count of failed commands per error.
+	pub command_errors: HashMap<TsError, u64>,
+	/// Count of failed file transfers.
+	pub filetransfer_errors: u64,
+	/// The number of clients that have a queue in the audio handler.
+	pub audio_queues: usize,
+	/// The number of packets that are buffered in all audio queues.
+	pub audio_queue_packets: usize,
+}
+
+/// A registry of metrics for multiple connections.
+///
+/// The registry can be cloned cheaply, all clones share the same data.
+#[derive(Clone, Debug)]
+pub struct Metrics<Id: Clone + Display + Eq + Hash = u64> {
+	connections: Arc<Mutex<HashMap<Id, ConnectionMetrics>>>,
+}
+
+/// Escape a label value for the Prometheus text format.
+struct Label<'a>(&'a str);
+
+impl Display for Label<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for c in self.0.chars() {
+			match c {
+				'\\' => f.write_str("\\\\")?,
+				'"' => f.write_str("\\\"")?,
+				'\n' => f.write_str("\\n")?,
+				c => f.write_char(c)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<Id: Clone + Display + Eq + Hash> Default for Metrics<Id> {
+	fn default() -> Self { Self { connections: Default::default() } }
+}
+
+impl<Id: Clone + Display + Eq + Hash> Metrics<Id> {
+	pub fn new() -> Self { Default::default() }
+
+	/// Run a function on the metrics of a connection, the entry is created if it does not exist.
+	pub fn with_connection<T, F: FnOnce(&mut ConnectionMetrics) -> T>(&self, id: &Id, f: F) -> T {
+		let mut connections = self.connections.lock().unwrap();
+		if let Some(con) = connections.get_mut(id) {
+			f(con)
+		} else {
+			f(connections.entry(id.clone()).or_default())
+		}
+	}
+
+	/// Get a copy of the current metrics of a connection.
+	pub fn get(&self, id: &Id) -> Option<ConnectionMetrics> {
+		self.connections.lock().unwrap().get(id).cloned()
+	}
+
+	/// Stop exporting metrics for a connection.
+	pub fn remove(&self, id: &Id) { self.connections.lock().unwrap().remove(id); }
+
+	/// Count an item of the event stream of a connection.
+	pub fn handle_item(&self, id: &Id, item: &StreamItem) {
+		self.with_connection(id, |con| match item {
+			StreamItem::BookEvents(_) => con.book_events += 1,
+			StreamItem::MessageEvent(_) => con.message_events += 1,
+			#[cfg(feature = "audio")]
+			StreamItem::Audio(_) => con.audio_packets += 1,
+			StreamItem::DisconnectedTemporarily(_) => con.reconnects += 1,
+			StreamItem::MessageResult(_, Ok(())) => con.command_successes += 1,
+			StreamItem::MessageResult(_, Err(e)) => {
+				*con.command_errors.entry(e.error).or_default() += 1;
+			}
+			StreamItem::FiletransferFailed(..) => con.filetransfer_errors += 1,
+			_ => {}
+		})
+	}
+
+	/// Store the current network statistics of a connection.
+	///
+	/// This should be called whenever the connection emits a
+	/// [`StreamItem::NetworkStatsUpdated`].
+	pub fn update_network_stats(&self, id: &Id, stats: &ConnectionStats) {
+		self.with_connection(id, |con| con.network_stats = Some(stats.clone()))
+	}
+
+	/// Store the current queue depth of an audio handler.
+	#[cfg(feature = "audio")]
+	pub fn update_audio_handler<AId: Clone + fmt::Debug + Eq + Hash + PartialEq>(
+		&self, id: &Id, handler: &crate::audio::AudioHandler<AId>,
+	) {
+		let queues = handler.get_queues();
+		let packets = queues.values().map(|q| q.get_buffered_packets()).sum();
+		self.with_connection(id, |con| {
+			con.audio_queues = queues.len();
+			con.audio_queue_packets = packets;
+		})
+	}
+
+	/// Write all metrics in the Prometheus text exposition format.
+	pub fn render(&self) -> String {
+		let connections = self.connections.lock().unwrap();
+		let mut res = String::new();
+
+		macro_rules! family {
+			($name:expr, $typ:expr, $help:expr, |$id:ident, $con:ident| $body:expr) => {
+				let _ = writeln!(res, "# HELP tsclientlib_{} {}", $name, $help);
+				let _ = writeln!(res, "# TYPE tsclientlib_{} {}", $name, $typ);
+				for ($id, $con) in connections.iter() {
+					let $id = Label(&$id.to_string()).to_string();
+					$body;
+				}
+			};
+		}
+
+		macro_rules! simple {
+			($name:expr, $typ:expr, $help:expr, |$con:ident| $value:expr) => {
+				family!($name, $typ, $help, |id, $con| {
+					let _ = writeln!(res, "tsclientlib_{}{{connection=\"{}\"}} {}", $name, id, $value);
+				});
+			};
+		}
+
+		simple!("book_events_total", "counter", "Received book events.", |con| con.book_events);
+		simple!("message_events_total", "counter", "Received messages that are not handled by \
+			the book.", |con| con.message_events);
+		simple!("audio_packets_total", "counter", "Received audio packets.", |con| con
+			.audio_packets);
+		simple!("reconnects_total", "counter", "Temporary disconnects of the connection.", |con| con
+			.reconnects);
+		simple!("command_successes_total", "counter", "Commands that succeeded.", |con| con
+			.command_successes);
+		family!("command_errors_total", "counter", "Commands that failed, by error.", |id, con| {
+			for (error, count) in &con.command_errors {
+				let _ = writeln!(
+					res,
+					"tsclientlib_command_errors_total{{connection=\"{}\",error=\"{}\"}} {}",
+					id, error, count
+				);
+			}
+		});
+		simple!("filetransfer_errors_total", "counter", "File transfers that failed.", |con| con
+			.filetransfer_errors);
+		simple!("audio_queues", "gauge", "Clients with an audio queue.", |con| con.audio_queues);
+		simple!("audio_queue_packets", "gauge", "Audio packets buffered in all queues.", |con| con
+			.audio_queue_packets);
+
+		family!("rtt_seconds", "gauge", "Round trip time of the connection.", |id, con| {
+			if let Some(stats) = &con.network_stats {
+				let _ = writeln!(
+					res,
+					"tsclientlib_rtt_seconds{{connection=\"{}\"}} {}",
+					id,
+					stats.rtt.as_secs_f64()
+				);
+			}
+		});
+		family!("rtt_deviation_seconds", "gauge", "Deviation of the round trip time.", |id, con| {
+			if let Some(stats) = &con.network_stats {
+				let _ = writeln!(
+					res,
+					"tsclientlib_rtt_deviation_seconds{{connection=\"{}\"}} {}",
+					id,
+					stats.rtt_dev.as_secs_f64()
+				);
+			}
+		});
+		family!("packets_total", "counter", "Packets since the start of the connection.", |id, con| {
+			if let Some(stats) = &con.network_stats {
+				for (stat, name) in &PACKET_STATS {
+					let _ = writeln!(
+						res,
+						"tsclientlib_packets_total{{connection=\"{}\",type=\"{}\"}} {}",
+						id, name, stats.total_packets[*stat as usize]
+					);
+				}
+			}
+		});
+		family!("bytes_total", "counter", "Bytes since the start of the connection.", |id, con| {
+			if let Some(stats) = &con.network_stats {
+				for (stat, name) in &PACKET_STATS {
+					let _ = writeln!(
+						res,
+						"tsclientlib_bytes_total{{connection=\"{}\",type=\"{}\"}} {}",
+						id, name, stats.total_bytes[*stat as usize]
+					);
+				}
+			}
+		});
+		family!("last_second_bytes", "gauge", "Bytes in the last second.", |id, con| {
+			if let Some(stats) = &con.network_stats {
+				let bytes = stats.get_last_second_bytes();
+				for (stat, name) in &PACKET_STATS {
+					let _ = writeln!(
+						res,
+						"tsclientlib_last_second_bytes{{connection=\"{}\",type=\"{}\"}} {}",
+						id, name, bytes[*stat as usize]
+					);
+				}
+			}
+		});
+		family!("last_minute_bytes", "gauge", "Bytes in the last minute.", |id, con| {
+			if let Some(stats) = &con.network_stats {
+				let bytes = stats.get_last_minute_bytes();
+				for (stat, name) in &PACKET_STATS {
+					let _ = writeln!(
+						res,
+						"tsclientlib_last_minute_bytes{{connection=\"{}\",type=\"{}\"}} {}",
+						id, name, bytes[*stat as usize]
+					);
+				}
+			}
+		});
+		family!("packetloss_s2c", "gauge", "Fraction of lost packets from the server.", |id, con| {
+			if let Some(stats) = &con.network_stats {
+				for (name, loss) in &[
+					("speech", stats.get_packetloss_s2c_speech()),
+					("keepalive", stats.get_packetloss_s2c_keepalive()),
+					("control", stats.get_packetloss_s2c_control()),
+					("total", stats.get_packetloss_s2c_total()),
+				] {
+					let _ = writeln!(
+						res,
+						"tsclientlib_packetloss_s2c{{connection=\"{}\",type=\"{}\"}} {}",
+						id, name, loss
+					);
+				}
+			}
+		});
+		family!("packetloss", "gauge", "Average incoming and outgoing packet loss.", |id, con| {
+			if let Some(stats) = &con.network_stats {
+				let _ = writeln!(
+					res,
+					"tsclientlib_packetloss{{connection=\"{}\"}} {}",
+					id,
+					stats.get_packetloss()
+				);
+			}
+		});
+
+		res
+	}
+
+	/// Serve the metrics over http on `/metrics`.
+	///
+	/// Requests are answered one after another, which is enough for a Prometheus scraper. The
+	/// returned future only finishes if accepting a connection fails.
+	pub async fn serve(self, address: SocketAddr) -> std::io::Result<()> {
+		let listener = TcpListener::bind(address).await?;
+		debug!(%address, "Serving metrics");
+		loop {
+			let (stream, _) = listener.accept().await?;
+			match tokio::time::timeout(REQUEST_TIMEOUT, self.answer_request(stream)).await {
+				Ok(Ok(())) => {}
+				Ok(Err(error)) => debug!(%error, "Failed to answer metrics request"),
+				Err(_) => debug!("Timeout while waiting for metrics request"),
+			}
+		}
+	}
+
+	async fn answer_request(&self, mut stream: TcpStream) -> std::io::Result<()> {
+		let mut request = Vec::new();
+		let mut buf = [0; 512];
+		while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+			let len = stream.read(&mut buf).await?;
+			if len == 0 {
+				break;
+			}
+			request.extend_from_slice(&buf[..len]);
+		}
+
+		let mut parts = request.split(|b| *b == b' ');
+		let method = parts.next().unwrap_or_default();
+		let path = parts.next().unwrap_or_default();
+		let response = if method != b"GET" {
+			"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+				.to_string()
+		} else if path == b"/metrics" {
+			let body = self.render();
+			format!(
+				"HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+				 {}\r\nConnection: close\r\n\r\n{}",
+				body.len(),
+				body
+			)
+		} else {
+			"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+		};
+
+		stream.write_all(response.as_bytes()).await?;
+		stream.shutdown().await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{CommandError, MessageHandle, TemporaryDisconnectReason};
+
+	#[test]
+	fn count_items() {
+		let metrics = Metrics::<u64>::new();
+		metrics.handle_item(&1, &StreamItem::BookEvents(Vec::new()));
+		metrics.handle_item(
+			&1,
+			&StreamItem::DisconnectedTemporarily(TemporaryDisconnectReason::Serverstop),
+		);
+		for _ in 0..2 {
+			metrics.handle_item(
+				&1,
+				&StreamItem::MessageResult(
+					MessageHandle(0),
+					Err(CommandError::new(TsError::ClientIsFlooding)),
+				),
+			);
+		}
+		metrics.handle_item(&2, &StreamItem::MessageResult(MessageHandle(1), Ok(())));
+
+		let con = metrics.get(&1).unwrap();
+		assert_eq!(con.book_events, 1);
+		assert_eq!(con.reconnects, 1);
+		assert_eq!(con.command_errors[&TsError::ClientIsFlooding], 2);
+		assert_eq!(metrics.get(&2).unwrap().command_successes, 1);
+
+		metrics.remove(&2);
+		assert!(metrics.get(&2).is_none());
+	}
+
+	#[test]
+	fn render_text_format() {
+		let metrics = Metrics::<String>::new();
+		let id = "bot \"1\"".to_string();
+		metrics.handle_item(&id, &StreamItem::BookEvents(Vec::new()));
+		metrics.update_network_stats(&id, &ConnectionStats::default());
+		metrics.handle_item(
+			&id,
+			&StreamItem::MessageResult(
+				MessageHandle(0),
+				Err(CommandError::new(TsError::ClientIsFlooding)),
+			),
+		);
+
+		let text = metrics.render();
+		assert!(text.contains("# TYPE tsclientlib_book_events_total counter\n"));
+		assert!(text.contains("tsclientlib_book_events_total{connection=\"bot \\\"1\\\"\"} 1\n"));
+		assert!(text.contains(
+			"tsclientlib_command_errors_total{connection=\"bot \\\"1\\\"\",\
+			 error=\"ClientIsFlooding\"} 1\n"
+		));
+		assert!(text.contains(
+			"tsclientlib_bytes_total{connection=\"bot \\\"1\\\"\",type=\"in_speech\"} 0\n"
+		));
+		assert!(text.contains("tsclientlib_packetloss{connection=\"bot \\\"1\\\"\"} 0\n"));
+	}
+}
@@ -0,0 +1,189 @@
+//! Mix the audio of several connections into one output.
+//!
+//! Every connection hears the clients in its own channel. A [`Mixer`] queues
+//! the received audio of all connections in one [`AudioHandler`] and merges it
+//! into a single buffer, e.g. for a moderator who wants to listen to all
+//! channels at once. The gain of every connection can be changed and
+//! connections can be muted.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use tsclientlib::mixer::Mixer;
+//!
+//! // Connections are identified by an own key
+//! let mut mixer = Mixer::<u32>::new();
+//! mixer.set_gain(1, 0.5);
+//! mixer.set_muted(2, true);
+//!
+//! // In the audio callback
+//! let mut buffer = vec![0.0; 960 * 2];
+//! mixer.fill_buffer(&mut buffer);
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use tsproto_packets::packets::InAudioBuf;
+
+use crate::audio::{AudioHandler, AudioHandlerConfig, Error};
+use crate::ClientId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Settings of one connection in a [`Mixer`].
+///
+/// # Default
+///
+/// Not muted and a gain of 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionMix {
+	/// The factor all audio of this connection is multiplied with.
+	pub gain: f32,
+	/// Drop all audio of this connection.
+	pub muted: bool,
+}
+
+/// Merges the audio that is received by several connections.
+///
+/// The output is interleaved stereo with 48 kHz, like for the
+/// [`AudioHandler`].
+pub struct Mixer<C: Clone + Debug + Eq + Hash + PartialEq> {
+	handler: AudioHandler<(C, ClientId)>,
+	connections: HashMap<C, ConnectionMix>,
+}
+
+impl Default for ConnectionMix {
+	fn default() -> Self { Self { gain: 1.0, muted: false } }
+}
+
+impl<C: Clone + Debug + Eq + Hash + PartialEq> Default for Mixer<C> {
+	fn default() -> Self { Self::with_config(Default::default()) }
+}
+
+impl<C: Clone + Debug + Eq + Hash + PartialEq> Mixer<C> {
+	pub fn new() -> Self { Default::default() }
+
+	pub fn with_config(config: AudioHandlerConfig) -> Self {
+		Self { handler: AudioHandler::with_config(config), connections: HashMap::new() }
+	}
+
+	/// The queues of all speakers, the volume of single clients can be changed
+	/// here.
+	pub fn get_handler(&self) -> &AudioHandler<(C, ClientId)> { &self.handler }
+	pub fn get_mut_handler(&mut self) -> &mut AudioHandler<(C, ClientId)> { &mut self.handler }
+
+	/// The settings of a connection, connections which were never changed use
+	/// the default.
+	pub fn get_connection(&self, con: &C) -> ConnectionMix {
+		self.connections.get(con).copied().unwrap_or_default()
+	}
+
+	pub fn set_connection(&mut self, con: C, mix: ConnectionMix) {
+		if mix.muted {
+			self.remove_queues(&con);
+		}
+		self.connections.insert(con, mix);
+	}
+
+	pub fn set_gain(&mut self, con: C, gain: f32) {
+		let mix = ConnectionMix { gain, ..self.get_connection(&con) };
+		self.set_connection(con, mix);
+	}
+
+	/// A muted connection drops received audio, so it uses no resources.
+	pub fn set_muted(&mut self, con: C, muted: bool) {
+		let mix = ConnectionMix { muted, ..self.get_connection(&con) };
+		self.set_connection(con, mix);
+	}
+
+	/// Forget the settings and queues of a connection, e.g. when it
+	/// disconnected.
+	pub fn remove_connection(&mut self, con: &C) {
+		self.connections.remove(con);
+		self.remove_queues(con);
+	}
+
+	/// Add a packet which was received by a connection.
+	///
+	/// If a new client started talking, returns the id of this client.
+	pub fn handle_packet(
+		&mut self, con: C, client: ClientId, packet: InAudioBuf,
+	) -> Result<Option<(C, ClientId)>> {
+		if self.get_connection(&con).muted {
+			return Ok(None);
+		}
+		self.handler.handle_packet((con, client), packet)
+	}
+
+	/// Mix the audio of all connections into `buf`.
+	///
+	/// `buf` is not cleared before filling it. Returns the clients that are not
+	/// talking anymore.
+	pub fn fill_buffer(&mut self, buf: &mut [f32]) -> Vec<(C, ClientId)> {
+		// The queue is removed when the speaker ends, so get the volume before
+		let volumes = self
+			.handler
+			.get_queues()
+			.iter()
+			.map(|(id, q)| (id.clone(), q.volume))
+			.collect::<HashMap<_, _>>();
+		let mut ended = Vec::new();
+		for audio in self.handler.fill_speakers(buf.len(), None) {
+			let volume = volumes.get(&audio.id).copied().unwrap_or(1.0);
+			let gain = self.get_connection(&audio.id.0).gain * volume;
+			for (b, s) in buf.iter_mut().zip(&audio.samples) {
+				*b += s * gain;
+			}
+			if audio.is_end {
+				ended.push(audio.id);
+			}
+		}
+		ended
+	}
+
+	fn remove_queues(&mut self, con: &C) {
+		self.handler.get_mut_queues().retain(|(c, _), _| c != con);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tsproto_packets::packets::{AudioData, CodecType, Direction, OutAudio};
+
+	use super::*;
+
+	fn receive(mixer: &mut Mixer<u8>, con: u8, id: u16) {
+		let data = [0, 0, 0, 0, 0, 0, 0];
+		let packet = OutAudio::new(&AudioData::S2C {
+			id,
+			codec: CodecType::OpusMusic,
+			from: 1,
+			data: &data,
+		});
+		let input = InAudioBuf::try_new(Direction::S2C, packet.into_vec()).unwrap();
+		mixer.handle_packet(con, ClientId(1), input).unwrap();
+	}
+
+	#[test]
+	fn gain_and_mute() {
+		let mut mixer = Mixer::new();
+		receive(&mut mixer, 1, 0);
+		receive(&mut mixer, 2, 0);
+		assert_eq!(mixer.get_handler().get_queues().len(), 2);
+
+		mixer.set_gain(1, 0.5);
+		assert_eq!(mixer.get_connection(&1), ConnectionMix { gain: 0.5, muted: false });
+		mixer.set_muted(2, true);
+		assert_eq!(mixer.get_handler().get_queues().len(), 1);
+		receive(&mut mixer, 2, 1);
+		assert_eq!(mixer.get_handler().get_queues().len(), 1);
+
+		let mut buf = vec![0.0; 48_000 / 100 * 2];
+		assert!(mixer.fill_buffer(&mut buf).is_empty());
+		mixer.remove_connection(&1);
+		assert!(mixer.get_handler().get_queues().is_empty());
+		assert_eq!(mixer.get_connection(&2).gain, 1.0);
+	}
+}
@@ -0,0 +1,645 @@
+//! Send commands without getting blocked by the anti-flood protection of the
+//! server.
+//!
+//! TeamSpeak servers count points for every command a client sends and reduce
+//! them every second. If the points reach a limit, the client gets the
+//! `client_is_flooding` error and is eventually banned.
+//!
+//! When a [`SchedulerConfig`] is set with [`ConnectOptions::scheduler`], commands
+//! are not sent immediately but put into a queue. The queue sends commands as
+//! fast as the anti-flood protection allows, high priority commands first.
+//! Similar commands are combined when possible:
+//! - Queued `clientupdate`s are coalesced into a single command.
+//! - Commands that accept a list, like `clientmove` to the same channel, are
+//!   batched into one command.
+//!
+//! If the server still answers with a flood error, the command is retried after
+//! the time the server asks for.
+//!
+//! When the connection is lost, all queued commands fail with
+//! [`TsError::ConnectionLost`](crate::TsError::ConnectionLost).
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use tsclientlib::Connection;
+//! use tsclientlib::scheduler::SchedulerConfig;
+//! let con = Connection::build("localhost")
+//!     .scheduler(SchedulerConfig::new().command_cost("sendtextmessage", 15))
+//!     .connect()
+//!     .unwrap();
+//! ```
+//!
+//! [`ConnectOptions::scheduler`]: crate::ConnectOptions::scheduler
+
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Waker};
+use std::time::Duration;
+
+use futures::prelude::*;
+use tokio::time::{Instant, Sleep};
+use tracing::{debug, warn};
+use tsproto_packets::commands::{CommandItem, CommandParser};
+use tsproto_packets::packets::{OutCommand, OutPacket};
+
+use crate::{data, CommandError, ErrorCategory};
+
+/// Commands which accept a list of values for one argument and the name of
+/// this argument.
+const BATCH_ARGUMENTS: &[(&[u8], &[u8])] = &[
+	(b"channelsubscribe", b"cid"),
+	(b"channelunsubscribe", b"cid"),
+	(b"clientkick", b"clid"),
+	(b"clientmove", b"clid"),
+];
+
+/// The arguments of a command with escaped values.
+type Arguments<'a> = Vec<(&'a [u8], &'a [u8])>;
+
+/// The priority of a queued command.
+///
+/// Commands with a higher priority are sent first.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+	Low,
+	Normal,
+	High,
+}
+
+/// The configuration of the command queue.
+#[derive(Clone, Debug)]
+pub struct SchedulerConfig {
+	tick_reduce: u32,
+	command_block: u32,
+	fill: f32,
+	default_cost: u32,
+	costs: HashMap<String, u32>,
+	max_batch: usize,
+	max_retries: u8,
+	use_server_limits: bool,
+}
+
+/// A command in the queue.
+#[derive(Debug)]
+struct Queued {
+	/// The command without return code.
+	packet: OutPacket,
+	priority: Priority,
+	/// The return codes of the handles that should get the result of this
+	/// command.
+	handles: Vec<u16>,
+	retries: u8,
+}
+
+/// The command queue of a connection.
+pub(crate) struct Scheduler {
+	config: SchedulerConfig,
+	/// The estimated anti-flood points on the server.
+	points: f32,
+	last_update: Instant,
+	/// Do not send anything until this time after getting a flood error.
+	blocked_until: Option<Instant>,
+	/// One queue per priority.
+	queues: [VecDeque<Queued>; 3],
+	/// Sent commands by their return code.
+	in_flight: HashMap<u16, Queued>,
+	timer: Option<Pin<Box<Sleep>>>,
+	waker: Option<Waker>,
+}
+
+impl Default for SchedulerConfig {
+	fn default() -> Self {
+		Self {
+			tick_reduce: 5,
+			command_block: 150,
+			fill: 0.8,
+			default_cost: 5,
+			costs: Default::default(),
+			max_batch: 100,
+			max_retries: 3,
+			use_server_limits: true,
+		}
+	}
+}
+
+impl SchedulerConfig {
+	#[inline]
+	pub fn new() -> Self { Self::default() }
+
+	/// The anti-flood points the server removes every second.
+	///
+	/// # Default
+	///
+	/// 5, the default of TeamSpeak servers.
+	#[inline]
+	pub fn tick_reduce(mut self, points: u32) -> Self {
+		self.tick_reduce = points;
+		self
+	}
+
+	/// The anti-flood points at which the server blocks commands.
+	///
+	/// # Default
+	///
+	/// 150, the default of TeamSpeak servers.
+	#[inline]
+	pub fn command_block(mut self, points: u32) -> Self {
+		self.command_block = points;
+		self
+	}
+
+	/// Use only this fraction of the points until commands are blocked, to
+	/// leave room for commands that are not sent through the queue.
+	///
+	/// # Default
+	///
+	/// 0.8
+	#[inline]
+	pub fn fill(mut self, fill: f32) -> Self {
+		self.fill = fill.clamp(0.0, 1.0);
+		self
+	}
+
+	/// The estimated cost of commands without a cost set by
+	/// [`command_cost`](Self::command_cost).
+	///
+	/// # Default
+	///
+	/// 5
+	#[inline]
+	pub fn default_cost(mut self, points: u32) -> Self {
+		self.default_cost = points;
+		self
+	}
+
+	/// Set the estimated cost of a command.
+	#[inline]
+	pub fn command_cost<S: Into<String>>(mut self, command: S, points: u32) -> Self {
+		self.costs.insert(command.into(), points);
+		self
+	}
+
+	/// The maximum number of commands that are batched into one.
+	///
+	/// # Default
+	///
+	/// 100
+	#[inline]
+	pub fn max_batch(mut self, max_batch: usize) -> Self {
+		self.max_batch = max_batch.max(1);
+		self
+	}
+
+	/// How often a command is resent after a flood error before the error is
+	/// returned.
+	///
+	/// # Default
+	///
+	/// 3
+	#[inline]
+	pub fn max_retries(mut self, max_retries: u8) -> Self {
+		self.max_retries = max_retries;
+		self
+	}
+
+	/// Use the anti-flood limits of the server if they are known.
+	///
+	/// The limits are part of the optional server data, which needs the
+	/// `b_virtualserver_info_view` permission.
+	///
+	/// # Default
+	///
+	/// true
+	#[inline]
+	pub fn use_server_limits(mut self, use_server_limits: bool) -> Self {
+		self.use_server_limits = use_server_limits;
+		self
+	}
+
+	pub fn get_tick_reduce(&self) -> u32 { self.tick_reduce }
+	pub fn get_command_block(&self) -> u32 { self.command_block }
+	pub fn get_fill(&self) -> f32 { self.fill }
+	pub fn get_default_cost(&self) -> u32 { self.default_cost }
+	pub fn get_max_batch(&self) -> usize { self.max_batch }
+	pub fn get_max_retries(&self) -> u8 { self.max_retries }
+	pub fn get_use_server_limits(&self) -> bool { self.use_server_limits }
+
+	/// The estimated cost of a command.
+	pub fn get_command_cost(&self, command: &str) -> u32 {
+		self.costs.get(command).copied().unwrap_or(self.default_cost)
+	}
+}
+
+impl Scheduler {
+	pub(crate) fn new(config: SchedulerConfig) -> Self {
+		Self {
+			config,
+			points: 0.0,
+			last_update: Instant::now(),
+			blocked_until: None,
+			queues: Default::default(),
+			in_flight: Default::default(),
+			timer: None,
+			waker: None,
+		}
+	}
+
+	/// The number of commands which are not sent yet.
+	pub(crate) fn len(&self) -> usize { self.queues.iter().map(|q| q.len()).sum() }
+
+	/// Add a command to the queue.
+	///
+	/// `handle` is the return code for the result, `None` if the result is not
+	/// needed.
+	pub(crate) fn push(&mut self, packet: OutCommand, priority: Priority, handle: Option<u16>) {
+		let mut cmd = Queued {
+			packet: packet.into_packet(),
+			priority,
+			handles: handle.into_iter().collect(),
+			retries: 0,
+		};
+		if name(&cmd.packet) == b"clientupdate" {
+			cmd = self.coalesce(cmd);
+		}
+		self.queues[cmd.priority as usize].push_back(cmd);
+		if let Some(waker) = self.waker.take() {
+			waker.wake();
+		}
+	}
+
+	/// Merge a `clientupdate` with a queued one, the values of the new command
+	/// win.
+	fn coalesce(&mut self, mut cmd: Queued) -> Queued {
+		let new_args = match parse_single(cmd.packet.content()) {
+			Some((_, args)) => args.into_iter().map(|(k, v)| (k.to_vec(), v.to_vec())),
+			None => return cmd,
+		};
+		let new_args: Vec<_> = new_args.collect();
+		for queue in &mut self.queues {
+			let pos = queue.iter().position(|c| {
+				name(&c.packet) == b"clientupdate" && parse_single(c.packet.content()).is_some()
+			});
+			if let Some(pos) = pos {
+				let old = queue.remove(pos).unwrap();
+				let mut args: Vec<_> = parse_single(old.packet.content())
+					.unwrap()
+					.1
+					.into_iter()
+					.map(|(k, v)| (k.to_vec(), v.to_vec()))
+					.collect();
+				for (k, v) in &new_args {
+					if let Some(arg) = args.iter_mut().find(|(k2, _)| k2 == k) {
+						arg.1 = v.clone();
+					} else {
+						args.push((k.clone(), v.clone()));
+					}
+				}
+				set_content(&mut cmd.packet, b"clientupdate", &args);
+				cmd.priority = cmd.priority.max(old.priority);
+				let mut handles = old.handles;
+				handles.append(&mut cmd.handles);
+				cmd.handles = handles;
+				debug!("Coalesced clientupdate");
+				break;
+			}
+		}
+		cmd
+	}
+
+	/// Update the anti-flood limits from the server data.
+	pub(crate) fn update_limits(&mut self, book: &data::Connection) {
+		if !self.config.use_server_limits {
+			return;
+		}
+		if let Some(data) = &book.server.optional_data {
+			if data.antiflood_points_tick_reduce != 0 {
+				self.config.tick_reduce = data.antiflood_points_tick_reduce;
+			}
+			if data.antiflood_points_to_command_block != 0 {
+				self.config.command_block = data.antiflood_points_to_command_block;
+			}
+		}
+	}
+
+	fn limit(&self) -> f32 { self.config.command_block as f32 * self.config.fill }
+
+	fn update_points(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last_update).as_secs_f32();
+		self.points = (self.points - elapsed * self.config.tick_reduce as f32).max(0.0);
+		self.last_update = now;
+	}
+
+	fn cost(&self, cmd: &Queued) -> f32 {
+		let name = std::str::from_utf8(name(&cmd.packet)).unwrap_or_default();
+		self.config.get_command_cost(name) as f32
+	}
+
+	/// How long to wait until the next command can be sent, `None` if the
+	/// queue is empty.
+	fn next_send(&self, now: Instant) -> Option<Duration> {
+		let cmd = self.queues.iter().rev().find_map(|q| q.front())?;
+		let mut wait = Duration::from_secs(0);
+		if let Some(blocked) = self.blocked_until {
+			wait = blocked.saturating_duration_since(now);
+		}
+		let missing = self.points + self.cost(cmd) - self.limit();
+		if missing > 0.0 {
+			let secs = missing / (self.config.tick_reduce.max(1) as f32);
+			wait = wait.max(Duration::from_secs_f32(secs));
+		}
+		Some(wait)
+	}
+
+	/// Get the next command to send, if the anti-flood limits allow it.
+	///
+	/// A command with a single handle is sent with the handle as return code,
+	/// so answers of the server can be matched to it. Otherwise `return_code`
+	/// is the next free return code and gets increased.
+	pub(crate) fn pop(&mut self, now: Instant, return_code: &mut u16) -> Option<OutCommand> {
+		self.update_points(now);
+		if self.blocked_until.map(|b| b > now).unwrap_or_default() {
+			return None;
+		}
+		self.blocked_until = None;
+		if self.next_send(now)? > Duration::from_secs(0) {
+			return None;
+		}
+
+		let queue = self.queues.iter_mut().rev().find(|q| !q.is_empty())?;
+		let mut cmd = queue.pop_front().unwrap();
+		batch(&mut cmd, queue, self.config.max_batch);
+		self.points += self.cost(&cmd);
+
+		let code = if let [handle] = cmd.handles.as_slice() {
+			*handle
+		} else {
+			let code = *return_code;
+			*return_code = return_code.wrapping_add(1);
+			code
+		};
+		let mut packet = OutCommand(cmd.packet.clone());
+		packet.write_arg("return_code", &code);
+		self.in_flight.insert(code, cmd);
+		Some(packet)
+	}
+
+	/// Wake the connection when the next command can be sent.
+	pub(crate) fn poll_timer(&mut self, cx: &mut Context, now: Instant) {
+		self.waker = Some(cx.waker().clone());
+		if let Some(wait) = self.next_send(now) {
+			let deadline = now + wait;
+			let timer = match &mut self.timer {
+				Some(timer) => {
+					if timer.deadline() != deadline {
+						timer.as_mut().reset(deadline);
+					}
+					timer
+				}
+				None => self.timer.insert(Box::pin(tokio::time::sleep_until(deadline))),
+			};
+			if timer.poll_unpin(cx).is_ready() {
+				cx.waker().wake_by_ref();
+			}
+		}
+	}
+
+	/// Drop all queued and sent commands because the connection was lost.
+	///
+	/// Returns the handles of the dropped commands, they should fail with
+	/// [`TsError::ConnectionLost`](crate::TsError::ConnectionLost).
+	pub(crate) fn connection_lost(&mut self) -> Vec<u16> {
+		let in_flight = self.in_flight.drain().map(|(_, c)| c);
+		let queued = self.queues.iter_mut().flat_map(|q| q.drain(..));
+		let handles = in_flight.chain(queued).flat_map(|c| c.handles).collect();
+		self.points = 0.0;
+		self.blocked_until = None;
+		handles
+	}
+
+	/// Handle the result of a command.
+	///
+	/// Returns `None` if the result does not belong to a queued command.
+	/// Otherwise returns the handles which get this result, the list is empty
+	/// if the command is retried.
+	pub(crate) fn handle_result(
+		&mut self, return_code: u16, res: &std::result::Result<(), CommandError>,
+	) -> Option<Vec<u16>> {
+		let now = Instant::now();
+		if let Err(error) = res {
+			if error.category() == ErrorCategory::Flood {
+				// We were too fast, also for commands which were not queued
+				self.update_points(now);
+				self.points = self.points.max(self.limit());
+				let retry = error.retry_after().unwrap_or_default();
+				self.blocked_until = Some(self.blocked_until.unwrap_or(now).max(now + retry));
+				if let Some(waker) = self.waker.take() {
+					waker.wake();
+				}
+			}
+		}
+
+		let mut cmd = self.in_flight.remove(&return_code)?;
+		if let Err(error) = res {
+			if error.category() == ErrorCategory::Flood && cmd.retries < self.config.max_retries {
+				warn!(retries = cmd.retries, "Command was blocked by flood protection, retrying");
+				cmd.retries += 1;
+				self.queues[cmd.priority as usize].push_front(cmd);
+				return Some(Vec::new());
+			}
+		}
+		Some(cmd.handles)
+	}
+}
+
+/// Append the commands from the front of the queue which can be batched with
+/// `cmd`.
+fn batch(cmd: &mut Queued, queue: &mut VecDeque<Queued>, max_batch: usize) {
+	let batch_arg = match BATCH_ARGUMENTS.iter().find(|(n, _)| *n == name(&cmd.packet)) {
+		Some((_, arg)) => *arg,
+		None => return,
+	};
+	let mut count = 1;
+	while count < max_batch {
+		let next = match queue.front() {
+			Some(r) => r,
+			None => break,
+		};
+		let value = match batch_value(&cmd.packet, &next.packet, batch_arg) {
+			Some(r) => r,
+			None => break,
+		};
+		let data = cmd.packet.data_mut();
+		data.push(b'|');
+		data.extend_from_slice(batch_arg);
+		data.push(b'=');
+		data.extend_from_slice(&value);
+
+		let mut next = queue.pop_front().unwrap();
+		cmd.handles.append(&mut next.handles);
+		count += 1;
+	}
+	if count > 1 {
+		debug!(count, "Batched commands");
+	}
+}
+
+/// If `next` only differs in the value of `arg` from the first part of `cmd`,
+/// return the value of `arg` in `next`.
+fn batch_value(cmd: &OutPacket, next: &OutPacket, arg: &[u8]) -> Option<Vec<u8>> {
+	let (cmd_name, mut cmd_args) = parse_first(cmd.content());
+	let (next_name, mut next_args) = parse_single(next.content())?;
+	if cmd_name != next_name {
+		return None;
+	}
+	let value = next_args.iter().find(|(k, _)| *k == arg)?.1.to_vec();
+	cmd_args.retain(|(k, _)| *k != arg);
+	next_args.retain(|(k, _)| *k != arg);
+	cmd_args.sort_unstable();
+	next_args.sort_unstable();
+	if cmd_args == next_args {
+		Some(value)
+	} else {
+		None
+	}
+}
+
+fn name(packet: &OutPacket) -> &[u8] { CommandParser::new(packet.content()).0 }
+
+/// The name and the arguments with escaped values of the first part of a
+/// command.
+fn parse_first(content: &[u8]) -> (&[u8], Arguments<'_>) {
+	let (name, parser) = CommandParser::new(content);
+	let mut args = Vec::new();
+	for item in parser {
+		match item {
+			CommandItem::Argument(arg) => args.push((arg.name(), arg.value().get_raw())),
+			CommandItem::NextCommand => break,
+		}
+	}
+	(name, args)
+}
+
+/// Like [`parse_first`] but returns `None` if the command has multiple parts.
+fn parse_single(content: &[u8]) -> Option<(&[u8], Arguments<'_>)> {
+	let (_, parser) = CommandParser::new(content);
+	if parser.clone().any(|i| matches!(i, CommandItem::NextCommand)) {
+		return None;
+	}
+	Some(parse_first(content))
+}
+
+/// Replace the content of a command, the values have to be escaped already.
+fn set_content(packet: &mut OutPacket, name: &[u8], args: &[(Vec<u8>, Vec<u8>)]) {
+	let header_len = packet.data().len() - packet.content().len();
+	let data = packet.data_mut();
+	data.truncate(header_len);
+	data.extend_from_slice(name);
+	for (k, v) in args {
+		data.push(b' ');
+		data.extend_from_slice(k);
+		if !v.is_empty() {
+			data.push(b'=');
+			data.extend_from_slice(v);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tsproto_packets::packets::{Direction, Flags, PacketType};
+
+	use super::*;
+
+	fn command(s: &str) -> OutCommand {
+		let (name, args) = s.split_once(' ').unwrap_or((s, ""));
+		let mut cmd = OutCommand::new(Direction::C2S, Flags::empty(), PacketType::Command, name);
+		cmd.0.data_mut().extend_from_slice(format!(" {}", args).trim_end().as_bytes());
+		cmd
+	}
+
+	fn content(cmd: &OutCommand) -> &str { std::str::from_utf8(cmd.0.content()).unwrap() }
+
+	#[test]
+	fn priority_and_rate() {
+		let mut scheduler = Scheduler::new(SchedulerConfig::new().command_block(10).fill(1.0));
+		let now = scheduler.last_update;
+		let mut code = 0;
+		scheduler.push(command("clientpoke clid=1 msg=a"), Priority::Low, None);
+		scheduler.push(command("clientpoke clid=2 msg=b"), Priority::High, Some(7));
+		scheduler.push(command("clientpoke clid=3 msg=c"), Priority::Normal, None);
+
+		let first = scheduler.pop(now, &mut code).unwrap();
+		assert_eq!(content(&first), "clientpoke clid=2 msg=b return_code=7");
+		let second = scheduler.pop(now, &mut code).unwrap();
+		assert_eq!(content(&second), "clientpoke clid=3 msg=c return_code=0");
+		// Limit reached
+		assert!(scheduler.pop(now, &mut code).is_none());
+		assert_eq!(scheduler.next_send(now), Some(Duration::from_secs(1)));
+		let later = now + Duration::from_secs(1);
+		assert!(scheduler.pop(later, &mut code).is_some());
+		assert_eq!(scheduler.len(), 0);
+		assert_eq!(code, 2);
+	}
+
+	#[test]
+	fn coalesce_and_batch() {
+		let mut scheduler = Scheduler::new(SchedulerConfig::new());
+		let now = scheduler.last_update;
+		let mut code = 10;
+		let update = command("clientupdate client_nickname=A client_away=1");
+		scheduler.push(update, Priority::Low, Some(1));
+		scheduler.push(command("clientmove clid=1 cid=5"), Priority::Normal, Some(2));
+		scheduler.push(command("clientmove clid=2 cid=5"), Priority::Normal, Some(3));
+		scheduler.push(command("clientmove clid=3 cid=6"), Priority::Normal, Some(4));
+		scheduler.push(command("clientupdate client_nickname=B"), Priority::Normal, Some(5));
+		assert_eq!(scheduler.len(), 4);
+
+		let cmd = scheduler.pop(now, &mut code).unwrap();
+		assert_eq!(content(&cmd), "clientmove clid=1 cid=5|clid=2 return_code=10");
+		let cmd = scheduler.pop(now, &mut code).unwrap();
+		assert_eq!(content(&cmd), "clientmove clid=3 cid=6 return_code=4");
+		let cmd = scheduler.pop(now, &mut code).unwrap();
+		assert_eq!(content(&cmd), "clientupdate client_nickname=B client_away=1 return_code=11");
+
+		assert_eq!(scheduler.handle_result(10, &Ok(())), Some(vec![2, 3]));
+		assert_eq!(scheduler.handle_result(11, &Ok(())), Some(vec![1, 5]));
+		assert_eq!(scheduler.handle_result(42, &Ok(())), None);
+	}
+
+	#[test]
+	fn flood_retry() {
+		let mut scheduler = Scheduler::new(SchedulerConfig::new().max_retries(1));
+		let now = scheduler.last_update;
+		let mut code = 0;
+		scheduler.push(command("sendtextmessage targetmode=3 msg=hi"), Priority::Normal, Some(0));
+		assert!(scheduler.pop(now, &mut code).is_some());
+
+		let mut error = CommandError::new(crate::TsError::ClientIsFlooding);
+		error.extra_message = Some("please wait 2 seconds".into());
+		assert_eq!(scheduler.handle_result(0, &Err(error.clone())), Some(Vec::new()));
+		assert_eq!(scheduler.len(), 1);
+		assert!(scheduler.pop(Instant::now(), &mut code).is_none());
+
+		let later = Instant::now() + Duration::from_secs(60);
+		let cmd = scheduler.pop(later, &mut code).unwrap();
+		assert_eq!(content(&cmd), "sendtextmessage targetmode=3 msg=hi return_code=0");
+		// Give up after the second flood error
+		assert_eq!(scheduler.handle_result(0, &Err(error)), Some(vec![0]));
+	}
+
+	#[test]
+	fn drop_on_connection_lost() {
+		let mut scheduler = Scheduler::new(SchedulerConfig::new());
+		let now = scheduler.last_update;
+		let mut code = 0;
+		scheduler.push(command("clientpoke clid=1 msg=a"), Priority::Normal, Some(4));
+		scheduler.push(command("clientpoke clid=2 msg=b"), Priority::Normal, None);
+		scheduler.push(command("clientpoke clid=3 msg=c"), Priority::Low, Some(5));
+		assert!(scheduler.pop(now, &mut code).is_some());
+
+		assert_eq!(scheduler.connection_lost(), [4, 5]);
+		assert_eq!(scheduler.len(), 0);
+		assert_eq!(scheduler.handle_result(4, &Ok(())), None);
+	}
+}
@@ -0,0 +1,461 @@
+//! Play short sound clips.
+//!
+//! A [`Clip`] is encoded with opus once when it is loaded, e.g. from a WAV
+//! file, and kept in memory as a list of 20 ms frames. Clips are collected in
+//! a [`Soundboard`].
+//!
+//! A [`ClipPlayer`] either sends the encoded frames of a clip instead of the
+//! live audio (interrupting it) or mixes the samples of a clip into the live
+//! audio before it is encoded. To play clips through a connection without
+//! live audio, a [`ClipStream`] returns the packets in a 20 ms interval.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # #[cfg(feature = "audio")]
+//! # async fn f(mut con: tsclientlib::Connection) -> anyhow::Result<()> {
+//! use futures::prelude::*;
+//! use tsclientlib::encoder::EncoderConfig;
+//! use tsclientlib::soundboard::{Clip, ClipStream, Soundboard};
+//!
+//! let mut soundboard = Soundboard::default();
+//! let wav = std::fs::read("jingle.wav")?;
+//! soundboard.add(Clip::from_wav("jingle", &wav, &EncoderConfig::default())?);
+//!
+//! let mut stream = ClipStream::new();
+//! stream.play(soundboard.get("jingle").unwrap());
+//! while let Some(packet) = stream.next().await {
+//!     con.send_audio(packet)?;
+//!     if !stream.is_playing() {
+//!         break;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+#[cfg(feature = "audio")]
+use audiopus::coder::Encoder;
+use futures::prelude::*;
+use thiserror::Error;
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tsproto_packets::packets::{CodecType, OutPacket};
+
+use crate::dsp;
+#[cfg(feature = "audio")]
+use crate::encoder::EncoderConfig;
+use crate::whisper::{self, WhisperTarget};
+
+/// The amount of samples in a 20 ms frame.
+#[cfg(feature = "audio")]
+const FRAME_SIZE: usize = 48_000 / 50;
+/// The maximum size of an opus frame is 1275 as from RFC6716.
+#[cfg(feature = "audio")]
+const MAX_OPUS_FRAME_SIZE: usize = 1275;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+	#[cfg(feature = "audio")]
+	#[error("Failed to create opus encoder: {0}")]
+	CreateEncoder(#[source] audiopus::Error),
+	#[cfg(feature = "audio")]
+	#[error("Opus encode failed: {0}")]
+	Encode(#[source] audiopus::Error),
+	#[error("Invalid WAV file: {0}")]
+	InvalidWav(&'static str),
+	#[error("Unsupported WAV format {format} with {bits} bits per sample")]
+	UnsupportedWav { format: u16, bits: u16 },
+}
+
+/// Decoded samples of a WAV file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wav {
+	pub sample_rate: u32,
+	pub channels: u16,
+	/// Interleaved samples between `-1` and `1`.
+	pub samples: Vec<f32>,
+}
+
+/// An encoded sound clip.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clip {
+	name: String,
+	codec: CodecType,
+	/// Opus frames of 20 ms.
+	frames: Vec<Vec<u8>>,
+	/// Mono samples with 48 kHz, used for mixing.
+	samples: Vec<f32>,
+}
+
+/// A collection of clips that can be played by name.
+#[derive(Clone, Debug, Default)]
+pub struct Soundboard {
+	clips: HashMap<String, Arc<Clip>>,
+}
+
+/// How a clip is played together with live audio.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PlayMode {
+	/// Add the clip to the live audio.
+	Mix,
+	/// Send the clip instead of the live audio.
+	Interrupt,
+}
+
+/// Plays clips together with live audio.
+///
+/// Interrupting clips are played after each other, mixed clips are played at
+/// the same time.
+#[derive(Clone, Debug)]
+pub struct ClipPlayer {
+	/// The volume of mixed clips.
+	pub volume: f32,
+	/// Clips that replace the live audio and the next frame.
+	queue: VecDeque<(Arc<Clip>, usize)>,
+	/// Clips that are mixed into the live audio and the next sample.
+	mixing: Vec<(Arc<Clip>, usize)>,
+}
+
+/// Returns the packets of clips in the right pace.
+///
+/// The stream never ends, it returns packets in a 20 ms interval while
+/// playing.
+pub struct ClipStream {
+	player: ClipPlayer,
+	whisper_target: Option<WhisperTarget>,
+	interval: Interval,
+	waker: Option<Waker>,
+}
+
+impl Wav {
+	/// Parse a WAV file with 8, 16, 24 or 32 bit integer or 32 bit float
+	/// samples.
+	pub fn parse(data: &[u8]) -> Result<Self> {
+		if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+			return Err(Error::InvalidWav("Missing RIFF header"));
+		}
+		let mut format = None;
+		let mut rest = &data[12..];
+		while rest.len() >= 8 {
+			let id = &rest[..4];
+			let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+			let body = rest.get(8..8 + len).ok_or(Error::InvalidWav("Chunk is too long"))?;
+			if id == b"fmt " {
+				if body.len() < 16 {
+					return Err(Error::InvalidWav("Format chunk is too short"));
+				}
+				let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+				let sample_rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+				format = Some((u16_at(0), u16_at(2), sample_rate, u16_at(14)));
+			} else if id == b"data" {
+				let (tag, channels, sample_rate, bits) =
+					format.ok_or(Error::InvalidWav("Data before format chunk"))?;
+				if channels == 0 {
+					return Err(Error::InvalidWav("No channels"));
+				}
+				let samples = decode_samples(tag, bits, body)?;
+				return Ok(Self { sample_rate, channels, samples });
+			}
+			// Chunks are padded to an even length
+			rest = rest.get(8 + len + len % 2..).unwrap_or_default();
+		}
+		Err(Error::InvalidWav("Missing data chunk"))
+	}
+
+	/// Mix to mono and resample to 48 kHz.
+	pub fn to_mono_48k(&self) -> Vec<f32> {
+		let channels = usize::from(self.channels);
+		let mono = self
+			.samples
+			.chunks(channels)
+			.map(|f| f.iter().sum::<f32>() / channels as f32)
+			.collect::<Vec<_>>();
+		dsp::resample(&mono, self.sample_rate, dsp::SAMPLE_RATE)
+	}
+}
+
+impl Clip {
+	/// Create a clip from opus frames of 20 ms and the mono 48 kHz samples
+	/// they were encoded from.
+	pub fn new(name: String, codec: CodecType, frames: Vec<Vec<u8>>, samples: Vec<f32>) -> Self {
+		Self { name, codec, frames, samples }
+	}
+
+	/// Encode mono samples with 48 kHz.
+	///
+	/// The frame length of the configuration is ignored, clips always use
+	/// 20 ms frames.
+	#[cfg(feature = "audio")]
+	pub fn encode<S: Into<String>>(
+		name: S, samples: Vec<f32>, config: &EncoderConfig,
+	) -> Result<Self> {
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Voip,
+		)
+		.map_err(Error::CreateEncoder)?;
+		config.apply(&mut encoder).map_err(Error::CreateEncoder)?;
+
+		let mut output = [0; MAX_OPUS_FRAME_SIZE];
+		let mut frames = Vec::with_capacity(samples.len() / FRAME_SIZE + 1);
+		for chunk in samples.chunks(FRAME_SIZE) {
+			let mut frame = [0.0; FRAME_SIZE];
+			frame[..chunk.len()].copy_from_slice(chunk);
+			let len = encoder.encode_float(&frame[..], &mut output[..]).map_err(Error::Encode)?;
+			frames.push(output[..len].to_vec());
+		}
+		Ok(Self::new(name.into(), config.codec_type(), frames, samples))
+	}
+
+	/// Decode and encode a WAV file.
+	#[cfg(feature = "audio")]
+	pub fn from_wav<S: Into<String>>(name: S, data: &[u8], config: &EncoderConfig) -> Result<Self> {
+		Self::encode(name, Wav::parse(data)?.to_mono_48k(), config)
+	}
+
+	pub fn get_name(&self) -> &str { &self.name }
+	pub fn get_codec(&self) -> CodecType { self.codec }
+	pub fn get_frames(&self) -> &[Vec<u8>] { &self.frames }
+	pub fn get_samples(&self) -> &[f32] { &self.samples }
+
+	pub fn duration(&self) -> Duration { Duration::from_millis(20 * self.frames.len() as u64) }
+
+	/// The packet for a frame, the packet after the last frame ends the
+	/// stream.
+	fn packet(&self, frame: usize, target: Option<&WhisperTarget>) -> Option<OutPacket> {
+		let data = match self.frames.get(frame) {
+			Some(d) => &d[..],
+			None if frame == self.frames.len() => &[],
+			None => return None,
+		};
+		Some(whisper::create_audio_packet(target, self.codec, data))
+	}
+}
+
+impl Soundboard {
+	/// Add a clip, replaces a clip with the same name.
+	pub fn add(&mut self, clip: Clip) { self.clips.insert(clip.name.clone(), Arc::new(clip)); }
+	pub fn get(&self, name: &str) -> Option<Arc<Clip>> { self.clips.get(name).cloned() }
+	pub fn remove(&mut self, name: &str) -> Option<Arc<Clip>> { self.clips.remove(name) }
+	pub fn names(&self) -> impl Iterator<Item = &str> { self.clips.keys().map(|n| n.as_str()) }
+}
+
+impl Default for ClipPlayer {
+	fn default() -> Self { Self { volume: 1.0, queue: VecDeque::new(), mixing: Vec::new() } }
+}
+
+impl ClipPlayer {
+	pub fn new() -> Self { Default::default() }
+
+	pub fn play(&mut self, clip: Arc<Clip>, mode: PlayMode) {
+		match mode {
+			PlayMode::Mix => self.mixing.push((clip, 0)),
+			PlayMode::Interrupt => self.queue.push_back((clip, 0)),
+		}
+	}
+
+	/// Stop all clips.
+	///
+	/// An interrupting clip that is currently playing still sends the end of
+	/// its stream.
+	pub fn stop(&mut self) {
+		self.mixing.clear();
+		let current = self.queue.pop_front().filter(|(_, frame)| *frame > 0);
+		self.queue.clear();
+		if let Some((clip, _)) = current {
+			let end = clip.frames.len();
+			self.queue.push_back((clip, end));
+		}
+	}
+
+	pub fn is_playing(&self) -> bool { self.is_interrupting() || !self.mixing.is_empty() }
+
+	/// If live audio should not be sent.
+	pub fn is_interrupting(&self) -> bool { !self.queue.is_empty() }
+
+	/// The next packet of the interrupting clips.
+	///
+	/// A clip is removed together with the packet that ends its stream.
+	pub fn next_packet(&mut self, target: Option<&WhisperTarget>) -> Option<OutPacket> {
+		while let Some((clip, frame)) = self.queue.front_mut() {
+			if let Some(packet) = clip.packet(*frame, target) {
+				*frame += 1;
+				if *frame > clip.frames.len() {
+					self.queue.pop_front();
+				}
+				return Some(packet);
+			}
+			self.queue.pop_front();
+		}
+		None
+	}
+
+	/// Add the mixed clips to interleaved 48 kHz samples.
+	pub fn mix(&mut self, samples: &mut [f32], channels: usize) {
+		let channels = channels.max(1);
+		let volume = self.volume;
+		for (clip, pos) in &mut self.mixing {
+			let clip_samples = clip.samples.get(*pos..).unwrap_or_default();
+			for (frame, s) in samples.chunks_mut(channels).zip(clip_samples) {
+				for d in frame {
+					*d += s * volume;
+				}
+			}
+			*pos += samples.len() / channels;
+		}
+		self.mixing.retain(|(clip, pos)| *pos < clip.samples.len());
+	}
+}
+
+impl Default for ClipStream {
+	fn default() -> Self { Self::new() }
+}
+
+impl ClipStream {
+	pub fn new() -> Self {
+		let mut interval = time::interval(Duration::from_millis(20));
+		interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		Self { player: ClipPlayer::new(), whisper_target: None, interval, waker: None }
+	}
+
+	/// Whisper to the given target instead of talking in the current channel.
+	pub fn set_whisper_target(&mut self, target: Option<WhisperTarget>) {
+		self.whisper_target = target;
+	}
+
+	/// Add a clip to the queue.
+	pub fn play(&mut self, clip: Arc<Clip>) {
+		if !self.player.is_playing() {
+			self.interval.reset();
+		}
+		self.player.play(clip, PlayMode::Interrupt);
+		if let Some(waker) = self.waker.take() {
+			waker.wake();
+		}
+	}
+
+	pub fn stop(&mut self) { self.player.stop(); }
+	pub fn is_playing(&self) -> bool { self.player.is_playing() }
+}
+
+impl Stream for ClipStream {
+	type Item = OutPacket;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		if !self.player.is_playing() {
+			self.waker = Some(cx.waker().clone());
+			return Poll::Pending;
+		}
+		if self.interval.poll_tick(cx).is_pending() {
+			return Poll::Pending;
+		}
+		let this = &mut *self;
+		if let Some(packet) = this.player.next_packet(this.whisper_target.as_ref()) {
+			Poll::Ready(Some(packet))
+		} else {
+			this.waker = Some(cx.waker().clone());
+			Poll::Pending
+		}
+	}
+}
+
+/// Convert little endian samples to floats.
+fn decode_samples(format: u16, bits: u16, data: &[u8]) -> Result<Vec<f32>> {
+	let res = match (format, bits) {
+		(1, 8) => data.iter().map(|s| (f32::from(*s) - 128.0) / 128.0).collect(),
+		(1, 16) => data
+			.chunks_exact(2)
+			.map(|s| f32::from(i16::from_le_bytes([s[0], s[1]])) / 32_768.0)
+			.collect(),
+		(1, 24) => data
+			.chunks_exact(3)
+			.map(|s| (i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8) as f32 / 8_388_608.0)
+			.collect(),
+		(1, 32) => data
+			.chunks_exact(4)
+			.map(|s| i32::from_le_bytes(s.try_into().unwrap()) as f32 / 2_147_483_648.0)
+			.collect(),
+		(3, 32) => {
+			data.chunks_exact(4).map(|s| f32::from_le_bytes(s.try_into().unwrap())).collect()
+		}
+		_ => return Err(Error::UnsupportedWav { format, bits }),
+	};
+	Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn create_wav(sample_rate: u32, channels: u16, samples: &[i16]) -> Vec<u8> {
+		let len = samples.len() as u32 * 2;
+		let mut res = Vec::new();
+		res.extend_from_slice(b"RIFF");
+		res.extend_from_slice(&(len + 36).to_le_bytes());
+		res.extend_from_slice(b"WAVEfmt ");
+		res.extend_from_slice(&16u32.to_le_bytes());
+		res.extend_from_slice(&1u16.to_le_bytes());
+		res.extend_from_slice(&channels.to_le_bytes());
+		res.extend_from_slice(&sample_rate.to_le_bytes());
+		res.extend_from_slice(&(sample_rate * u32::from(channels) * 2).to_le_bytes());
+		res.extend_from_slice(&(channels * 2).to_le_bytes());
+		res.extend_from_slice(&16u16.to_le_bytes());
+		res.extend_from_slice(b"data");
+		res.extend_from_slice(&len.to_le_bytes());
+		for s in samples {
+			res.extend_from_slice(&s.to_le_bytes());
+		}
+		res
+	}
+
+	#[test]
+	fn parse_wav() {
+		let wav = Wav::parse(&create_wav(24_000, 2, &[16_384, 0, -16_384, -16_384])).unwrap();
+		assert_eq!(wav.sample_rate, 24_000);
+		assert_eq!(wav.samples, vec![0.5, 0.0, -0.5, -0.5]);
+		assert_eq!(wav.to_mono_48k(), vec![0.25, -0.125, -0.5, -0.5]);
+
+		assert!(matches!(Wav::parse(b"RIFF"), Err(Error::InvalidWav(_))));
+		let mut float = create_wav(48_000, 1, &[]);
+		float[20] = 3;
+		assert!(matches!(Wav::parse(&float), Err(Error::UnsupportedWav { format: 3, bits: 16 })));
+	}
+
+	#[test]
+	fn interrupt_and_mix() {
+		let frames = vec![vec![1], vec![2]];
+		let clip = Arc::new(Clip::new("a".into(), CodecType::OpusVoice, frames, vec![0.5; 3]));
+		assert_eq!(clip.duration(), Duration::from_millis(40));
+		let mut player = ClipPlayer::new();
+		player.play(clip.clone(), PlayMode::Interrupt);
+		player.play(clip.clone(), PlayMode::Interrupt);
+		assert!(player.is_interrupting());
+		assert!(player.next_packet(None).is_some());
+		// The current clip ends, the queued one is dropped
+		player.stop();
+		let end = player.next_packet(None).unwrap();
+		assert_eq!(end, whisper::create_audio_packet(None, CodecType::OpusVoice, &[]));
+		assert!(!player.is_playing());
+		assert!(player.next_packet(None).is_none());
+
+		player.play(clip, PlayMode::Mix);
+		let mut samples = vec![0.25; 4];
+		player.mix(&mut samples, 2);
+		assert_eq!(samples, vec![0.75; 4]);
+		assert!(player.is_playing());
+		player.mix(&mut samples, 2);
+		assert_eq!(samples, vec![1.25, 1.25, 0.75, 0.75]);
+		assert!(!player.is_playing());
+	}
+}
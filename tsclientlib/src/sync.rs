@@ -2,29 +2,60 @@
 //!
 //! It makes it easier to use a connection from multiple threads and use
 //! `async`/`await` syntax for the cost of a little bit performance.
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use futures::prelude::*;
+use futures::stream::BoxStream;
+use time::OffsetDateTime;
 use tokio::sync::{mpsc, oneshot};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use ts_bookkeeping::messages::{c2s, s2c, OutMessageTrait};
 use ts_bookkeeping::ChannelId;
 #[cfg(feature = "audio")]
 use tsproto_packets::packets::InAudioBuf;
-#[cfg(feature = "unstable")]
 use tsproto_packets::packets::OutCommand;
 
+use crate::clientdb::{ClientDbCache, DbClient, DbClientInfo, FoundDbClient};
+use crate::fetch::{ClientSelection, FetchTracker, Fetched, OptionalData};
+use crate::scheduler::Priority;
 use crate::{
-	events, AudioEvent, DisconnectOptions, Error, InMessage, Result, StreamItem,
-	TemporaryDisconnectReason,
+	data, events, AudioEvent, ClientDbId, ClientId, DisconnectOptions, Error, InMessage, Result,
+	StreamItem, TemporaryDisconnectReason, TsError, Uid, UidBuf,
 };
 
+/// Collect the parts of all messages of a type.
+macro_rules! collect_parts {
+	($msgs:expr, $msg:ident) => {
+		$msgs
+			.iter()
+			.flat_map(|m| match m {
+				InMessage::$msg(m) => m.iter().cloned().collect(),
+				_ => Vec::new(),
+			})
+			.collect::<Vec<_>>()
+	};
+}
+
+/// Gets notified when requested optional data was received.
+type FetchSender = oneshot::Sender<Result<OffsetDateTime>>;
+
 enum SyncConMessage {
 	RunFn(Box<dyn FnOnce(&mut SyncConnection) + Send>),
 	#[cfg(feature = "unstable")]
 	SendCommand(OutCommand, oneshot::Sender<Result<()>>),
+	/// Send a command and collect the messages that answer it.
+	Query {
+		packet: OutCommand,
+		matches: fn(&InMessage) -> bool,
+		send: oneshot::Sender<Result<Vec<InMessage>>>,
+	},
+	/// Request optional data, returns when it was received.
+	Fetch(OptionalData, FetchSender),
 	WaitConnected(oneshot::Sender<Result<()>>),
 	Disconnect(DisconnectOptions, oneshot::Sender<Result<()>>),
 	DownloadFile {
@@ -90,12 +121,27 @@ pub struct SyncConnectionHandle {
 	send: mpsc::Sender<SyncConMessage>,
 }
 
+/// A command which waits for answers from the server.
+struct Query {
+	handle: super::MessageHandle,
+	/// If a message is an answer to this query.
+	matches: fn(&InMessage) -> bool,
+	results: Vec<InMessage>,
+	send: oneshot::Sender<Result<Vec<InMessage>>>,
+}
+
 pub struct SyncConnection {
 	con: super::Connection,
 	recv: mpsc::Receiver<SyncConMessage>,
 	send: mpsc::Sender<SyncConMessage>,
+	client_db: ClientDbCache,
+	fetch: FetchTracker,
 
 	commands: HashMap<super::MessageHandle, oneshot::Sender<Result<()>>>,
+	/// Queries in the order they were sent.
+	queries: Vec<Query>,
+	/// Requested optional data, with a sender if someone waits for it.
+	fetches: HashMap<super::MessageHandle, (OptionalData, Option<FetchSender>)>,
 	connects: Vec<oneshot::Sender<Result<()>>>,
 	disconnects: Vec<oneshot::Sender<Result<()>>>,
 	downloads:
@@ -110,8 +156,12 @@ impl From<super::Connection> for SyncConnection {
 			con,
 			recv,
 			send,
+			client_db: Default::default(),
+			fetch: Default::default(),
 
 			commands: Default::default(),
+			queries: Default::default(),
+			fetches: Default::default(),
 			connects: Default::default(),
 			disconnects: Default::default(),
 			downloads: Default::default(),
@@ -142,7 +192,8 @@ impl Stream for SyncConnection {
 						SyncConMessage::RunFn(f) => f(&mut self),
 						#[cfg(feature = "unstable")]
 						SyncConMessage::SendCommand(arg, send) => {
-							let handle = match self.con.send_command_with_result(arg) {
+							let res = self.con.send_command_with_result(arg, Priority::Normal);
+							let handle = match res {
 								Ok(r) => r,
 								Err(e) => {
 									let _ = send.send(Err(e));
@@ -151,6 +202,18 @@ impl Stream for SyncConnection {
 							};
 							self.commands.insert(handle, send);
 						}
+						SyncConMessage::Query { packet, matches, send } => {
+							let res = self.con.send_command_with_result(packet, Priority::Normal);
+							let handle = match res {
+								Ok(r) => r,
+								Err(e) => {
+									let _ = send.send(Err(e));
+									continue;
+								}
+							};
+							self.queries.push(Query { handle, matches, results: Vec::new(), send });
+						}
+						SyncConMessage::Fetch(data, send) => self.send_fetch(data, Some(send)),
 						SyncConMessage::WaitConnected(send) => {
 							if self.con.get_state().is_ok() {
 								let _ = send.send(Ok(()));
@@ -223,6 +286,8 @@ impl Stream for SyncConnection {
 			break;
 		}
 
+		self.poll_refresh(ctx);
+
 		loop {
 			break if let Poll::Ready(item) = self.con.poll_next(ctx) {
 				Poll::Ready(match item {
@@ -233,7 +298,19 @@ impl Stream for SyncConnection {
 							});
 							SyncStreamItem::BookEvents(i)
 						}
-						StreamItem::MessageEvent(i) => SyncStreamItem::MessageEvent(i),
+						StreamItem::MessageEvent(i) => {
+							self.client_db.update(&i);
+							// Servers which do not send a return code answer queries of the
+							// same type in order
+							let code = return_code(&i);
+							if let Some(query) = self.queries.iter_mut().find(|q| {
+								(q.matches)(&i) && code.map(|c| c == q.handle.0).unwrap_or(true)
+							}) {
+								query.results.push(i);
+								continue;
+							}
+							SyncStreamItem::MessageEvent(i)
+						}
 						#[cfg(feature = "audio")]
 						StreamItem::Audio(i) => SyncStreamItem::Audio(i),
 						StreamItem::IdentityLevelIncreasing(i) => {
@@ -243,10 +320,31 @@ impl Stream for SyncConnection {
 							SyncStreamItem::IdentityLevelIncreased
 						}
 						StreamItem::DisconnectedTemporarily(reason) => {
+							self.fail_queries();
 							SyncStreamItem::DisconnectedTemporarily(reason)
 						}
 						StreamItem::MessageResult(handle, res) => {
-							if let Some(send) = self.commands.remove(&handle) {
+							if let Some(i) = self.queries.iter().position(|q| q.handle == handle) {
+								let query = self.queries.remove(i);
+								let res = match res {
+									Ok(()) => Ok(query.results),
+									// No results
+									Err(e) if e.error == TsError::DatabaseEmptyResult => {
+										Ok(Vec::new())
+									}
+									Err(e) => Err(e.into()),
+								};
+								let _ = query.send.send(res);
+							} else if let Some((data, send)) = self.fetches.remove(&handle) {
+								let res = res.map(|()| {
+									let now = OffsetDateTime::now_utc();
+									self.fetch.set_fetched(data, now);
+									now
+								});
+								if let Some(send) = send {
+									let _ = send.send(res.map_err(|e| e.into()));
+								}
+							} else if let Some(send) = self.commands.remove(&handle) {
 								let _ = send.send(res.map_err(|e| e.into()));
 							} else {
 								info!("Got untracked message result");
@@ -284,6 +382,7 @@ impl Stream for SyncConnection {
 					})),
 					Some(Err(e)) => Some(Err(e)),
 					None => {
+						self.fail_queries();
 						self.disconnects.drain(..).for_each(|send| {
 							let _ = send.send(Ok(()));
 						});
@@ -303,6 +402,111 @@ impl SyncConnection {
 	pub fn get_handle(&self) -> SyncConnectionHandle {
 		SyncConnectionHandle { send: self.send.clone() }
 	}
+
+	/// The cache of unique ids, database ids and names.
+	#[inline]
+	pub fn get_client_db_cache(&self) -> &ClientDbCache { &self.client_db }
+
+	#[inline]
+	pub fn get_client_db_cache_mut(&mut self) -> &mut ClientDbCache { &mut self.client_db }
+
+	/// When optional data was fetched and which data is refreshed.
+	///
+	/// See the [`fetch`](crate::fetch) module for more details.
+	#[inline]
+	pub fn get_fetch_tracker(&self) -> &FetchTracker { &self.fetch }
+
+	#[inline]
+	pub fn get_fetch_tracker_mut(&mut self) -> &mut FetchTracker { &mut self.fetch }
+
+	fn send_fetch(&mut self, data: OptionalData, send: Option<FetchSender>) {
+		// Automatic refreshes should not delay other commands
+		let priority = if send.is_some() { Priority::Normal } else { Priority::Low };
+		match self.con.send_command_with_result(data.request(), priority) {
+			Ok(handle) => {
+				self.fetches.insert(handle, (data, send));
+			}
+			Err(error) => {
+				if let Some(send) = send {
+					let _ = send.send(Err(error));
+				} else {
+					warn!(%error, ?data, "Failed to refresh data");
+				}
+			}
+		}
+	}
+
+	/// Request data which should be refreshed.
+	fn poll_refresh(&mut self, ctx: &mut Context) {
+		let now = tokio::time::Instant::now();
+		let due = if let Ok(book) = self.con.get_state() {
+			self.fetch.retain(book);
+			let mut due = self.fetch.due(now);
+			if self.fetch.poll_all_due(now) {
+				let all = book
+					.clients
+					.keys()
+					.filter(|c| **c != book.own_client)
+					.map(|c| OptionalData::ConnectionInfo(*c))
+					.filter(|d| !due.contains(d))
+					.collect::<Vec<_>>();
+				due.extend(all);
+			}
+			due
+		} else {
+			return;
+		};
+		for data in due {
+			// Skip data which is still requested
+			if !self.fetches.values().any(|f| f.0 == data) {
+				self.send_fetch(data, None);
+			}
+		}
+		self.fetch.poll_timer(ctx, now);
+	}
+
+	/// Fail everything that waits for an answer of the server, the answers
+	/// are lost when the connection is reset.
+	///
+	/// Return codes start at zero again on a new connection, so old handles
+	/// would match answers to new commands.
+	fn fail_queries(&mut self) {
+		for query in self.queries.drain(..) {
+			let _ = query.send.send(Err(Error::NotConnected));
+		}
+		for (_, (_, send)) in self.fetches.drain() {
+			if let Some(send) = send {
+				let _ = send.send(Err(Error::NotConnected));
+			}
+		}
+		for (_, send) in self.commands.drain() {
+			let _ = send.send(Err(Error::NotConnected));
+		}
+	}
+
+	/// Look in the currently visible clients, then in the cache.
+	fn lookup_db_id(&self, uid: &Uid) -> Option<ClientDbId> {
+		let book = self.con.get_state().ok();
+		let client = book.and_then(|b| b.clients.values().find(|c| c.uid.as_deref() == Some(uid)));
+		client.map(|c| c.database_id).or_else(|| self.client_db.get_db_id(uid))
+	}
+
+	fn lookup_uid(&self, db_id: ClientDbId) -> Option<UidBuf> {
+		let book = self.con.get_state().ok();
+		let client = book.and_then(|b| b.clients.values().find(|c| c.database_id == db_id));
+		client
+			.and_then(|c| c.uid.clone())
+			.or_else(|| self.client_db.get_uid(db_id).map(|u| u.to_owned()))
+	}
+
+	fn lookup_name(&self, uid: &Uid) -> Option<String> {
+		let book = self.con.get_state().ok();
+		let client = book.and_then(|b| b.clients.values().find(|c| c.uid.as_deref() == Some(uid)));
+		client.map(|c| c.name.clone()).or_else(|| {
+			let db_id = self.client_db.get_db_id(uid)?;
+			self.client_db.get_name(db_id).map(|n| n.to_string())
+		})
+	}
 }
 
 impl SyncConnectionHandle {
@@ -467,4 +671,275 @@ impl SyncConnectionHandle {
 			.map_err(|_| Error::ConnectionGone)?;
 		recv.await.map_err(|_| Error::ConnectionGone)?
 	}
+
+	/// Send a command and collect the messages that answer it.
+	///
+	/// An empty result from the database is returned as an empty list.
+	async fn query(
+		&mut self, packet: OutCommand, matches: fn(&InMessage) -> bool,
+	) -> Result<Vec<InMessage>> {
+		let (send, recv) = oneshot::channel();
+		self.send
+			.send(SyncConMessage::Query { packet, matches, send })
+			.await
+			.map_err(|_| Error::ConnectionGone)?;
+		recv.await.map_err(|_| Error::ConnectionGone)?
+	}
+
+	/// List `limit` clients of the client database, starting at `offset`.
+	///
+	/// See the [`clientdb`](crate::clientdb) module for more details.
+	pub async fn client_db_list(&mut self, offset: u32, limit: u32) -> Result<Vec<DbClient>> {
+		let part = c2s::OutClientDbListRequestPart { offset: Some(offset), limit: Some(limit) };
+		let packet = part.to_packet();
+		let msgs = self.query(packet, |m| matches!(m, InMessage::ClientDbList(_))).await?;
+		Ok(collect_parts!(msgs, ClientDbList))
+	}
+
+	/// Page through the whole client database, `page_size` clients at a time.
+	pub fn client_db_pages(&self, page_size: u32) -> BoxStream<'static, Result<Vec<DbClient>>> {
+		let page_size = page_size.max(1);
+		stream::try_unfold((self.clone(), 0, false), move |(mut handle, offset, done)| async move {
+			if done {
+				return Ok(None);
+			}
+			let page = handle.client_db_list(offset, page_size).await?;
+			if page.is_empty() {
+				return Ok(None);
+			}
+			let len = page.len() as u32;
+			Ok(Some((page, (handle, offset + len, len < page_size))))
+		})
+		.boxed()
+	}
+
+	/// Search clients in the database.
+	///
+	/// Searches for names which contain `pattern`, `%` can be used as wildcard.
+	/// If `by_uid` is set, the pattern is matched against unique ids instead.
+	pub async fn client_db_find(
+		&mut self, pattern: String, by_uid: bool,
+	) -> Result<Vec<FoundDbClient>> {
+		let mut packet =
+			c2s::OutClientDbFindRequestPart { pattern: Cow::Borrowed(&pattern) }.to_packet();
+		if by_uid {
+			packet.write_arg("-uid", &"");
+		}
+		let msgs = self.query(packet, |m| matches!(m, InMessage::ClientDbFind(_))).await?;
+		Ok(collect_parts!(msgs, ClientDbFind))
+	}
+
+	/// Get details about a client in the database.
+	///
+	/// Returns `None` if the client does not exist.
+	pub async fn client_db_info(&mut self, db_id: ClientDbId) -> Result<Option<DbClientInfo>> {
+		let packet = c2s::OutClientDbInfoRequestPart { client_db_id: db_id }.to_packet();
+		let msgs = self.query(packet, |m| matches!(m, InMessage::ClientDbInfo(_))).await?;
+		Ok(collect_parts!(msgs, ClientDbInfo).into_iter().next())
+	}
+
+	/// Change the description of a client in the database.
+	pub async fn client_db_edit_description(
+		&mut self, db_id: ClientDbId, description: String,
+	) -> Result<()> {
+		let packet = c2s::OutClientDbEditPart {
+			client_db_id: db_id,
+			description: Some(Cow::Borrowed(&description)),
+		}
+		.to_packet();
+		self.query(packet, |_| false).await?;
+		Ok(())
+	}
+
+	/// Delete a client from the database.
+	pub async fn client_db_delete(&mut self, db_id: ClientDbId) -> Result<()> {
+		let packet = c2s::OutClientDbDeletePart { client_db_id: db_id }.to_packet();
+		self.query(packet, |_| false).await?;
+		self.with_connection(move |con| con.client_db.remove(db_id)).await
+	}
+
+	/// Get the database id of a client.
+	///
+	/// Returns `None` if the client does not exist.
+	pub async fn client_db_id_from_uid(&mut self, uid: UidBuf) -> Result<Option<ClientDbId>> {
+		let cached = {
+			let uid = uid.clone();
+			self.with_connection(move |con| con.lookup_db_id(&uid)).await?
+		};
+		if cached.is_some() {
+			return Ok(cached);
+		}
+		let packet =
+			c2s::OutClientDbIdFromUidRequestPart { client_uid: Cow::Borrowed(&uid) }.to_packet();
+		let msgs = self.query(packet, |m| matches!(m, InMessage::ClientDbIdFromUid(_))).await?;
+		let parts: Vec<s2c::InClientDbIdFromUidPart> = collect_parts!(msgs, ClientDbIdFromUid);
+		Ok(parts.into_iter().next().map(|p| p.client_db_id))
+	}
+
+	/// Get the last known name of a client.
+	///
+	/// Returns `None` if the client does not exist.
+	pub async fn client_name_from_uid(&mut self, uid: UidBuf) -> Result<Option<String>> {
+		let cached = {
+			let uid = uid.clone();
+			self.with_connection(move |con| con.lookup_name(&uid)).await?
+		};
+		if cached.is_some() {
+			return Ok(cached);
+		}
+		let packet =
+			c2s::OutClientNameFromUidRequestPart { client_uid: Cow::Borrowed(&uid) }.to_packet();
+		let msgs = self.query(packet, |m| matches!(m, InMessage::ClientNameFromUid(_))).await?;
+		let parts: Vec<s2c::InClientNameFromUidPart> = collect_parts!(msgs, ClientNameFromUid);
+		Ok(parts.into_iter().next().map(|p| p.name))
+	}
+
+	/// Get the unique id of a client in the database.
+	///
+	/// Returns `None` if the client does not exist.
+	pub async fn client_uid_from_db_id(&mut self, db_id: ClientDbId) -> Result<Option<UidBuf>> {
+		let cached = self.with_connection(move |con| con.lookup_uid(db_id)).await?;
+		if cached.is_some() {
+			return Ok(cached);
+		}
+		Ok(self.client_db_info(db_id).await?.map(|i| i.uid))
+	}
+
+	/// Request optional data and return when it was received.
+	async fn fetch(&mut self, data: OptionalData) -> Result<OffsetDateTime> {
+		let (send, recv) = oneshot::channel();
+		self.send
+			.send(SyncConMessage::Fetch(data, send))
+			.await
+			.map_err(|_| Error::ConnectionGone)?;
+		recv.await.map_err(|_| Error::ConnectionGone)?
+	}
+
+	/// Fetch the description of a channel.
+	///
+	/// See the [`fetch`](crate::fetch) module for more details.
+	pub async fn fetch_optional_channel_data(
+		&mut self, channel: ChannelId,
+	) -> Result<Fetched<data::OptionalChannelData>> {
+		let requested = OptionalData::ChannelDescription(channel);
+		let fetched = self.fetch(requested).await?;
+		let data = self
+			.with_connection(move |con| {
+				let channel_data = con.get_state().map_err(Box::new)?.channels.get(&channel);
+				channel_data
+					.and_then(|c| c.optional_data.clone())
+					.ok_or_else(|| Box::new(Error::NotFound(requested)))
+			})
+			.await?
+			.map_err(|e| *e)?;
+		Ok(Fetched { data, fetched })
+	}
+
+	/// Fetch the variables of a client, like its version and platform.
+	pub async fn fetch_optional_client_data(
+		&mut self, client: ClientId,
+	) -> Result<Fetched<data::OptionalClientData>> {
+		let requested = OptionalData::ClientVariables(client);
+		let fetched = self.fetch(requested).await?;
+		let data = self
+			.with_connection(move |con| {
+				let client_data = con.get_state().map_err(Box::new)?.clients.get(&client);
+				client_data
+					.and_then(|c| c.optional_data.clone())
+					.ok_or_else(|| Box::new(Error::NotFound(requested)))
+			})
+			.await?
+			.map_err(|e| *e)?;
+		Ok(Fetched { data, fetched })
+	}
+
+	/// Fetch the connection information of a client, like its ping.
+	pub async fn fetch_connection_client_data(
+		&mut self, client: ClientId,
+	) -> Result<Fetched<data::ConnectionClientData>> {
+		let requested = OptionalData::ConnectionInfo(client);
+		let fetched = self.fetch(requested).await?;
+		let data = self
+			.with_connection(move |con| {
+				let client_data = con.get_state().map_err(Box::new)?.clients.get(&client);
+				client_data
+					.and_then(|c| c.connection_data.clone())
+					.ok_or_else(|| Box::new(Error::NotFound(requested)))
+			})
+			.await?
+			.map_err(|e| *e)?;
+		Ok(Fetched { data, fetched })
+	}
+
+	/// Refresh optional data every `interval` or stop with `None`.
+	///
+	/// New data is stored in the book and creates events like other changes.
+	pub async fn set_auto_refresh(
+		&mut self, data: OptionalData, interval: Option<Duration>,
+	) -> Result<()> {
+		self.with_connection(move |con| con.fetch.set_auto_refresh(data, interval)).await
+	}
+
+	/// Poll the connection info of clients every `interval` or stop with `None`.
+	///
+	/// Every answer creates a `PropertyChanged` event for the
+	/// [`ConnectionClientData`](data::ConnectionClientData) of the client.
+	/// See the [`fetch`](crate::fetch) module for more details.
+	pub async fn poll_connection_info(
+		&mut self, clients: ClientSelection, interval: Option<Duration>,
+	) -> Result<()> {
+		self.with_connection(move |con| {
+			let all = matches!(clients, ClientSelection::All);
+			if all && interval.is_some() && con.con.options.get_scheduler().is_none() {
+				// Every poll sends one command per client at once
+				warn!("Polling the connection info of all clients without a scheduler");
+			}
+			con.fetch.set_connection_info_polling(clients, interval)
+		})
+		.await
+	}
+}
+
+/// The return code of a message which answers a query.
+fn return_code(msg: &InMessage) -> Option<u16> {
+	let code = match msg {
+		InMessage::ClientDbList(m) => &m.return_code,
+		InMessage::ClientDbFind(m) => &m.return_code,
+		InMessage::ClientDbInfo(m) => &m.return_code,
+		InMessage::ClientDbIdFromUid(m) => &m.return_code,
+		InMessage::ClientNameFromUid(m) => &m.return_code,
+		_ => return None,
+	};
+	code.as_ref()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use tracing::Span;
+
+	use super::*;
+	use crate::{Connection, ConnectionState, Identity, MessageHandle};
+
+	#[test]
+	fn fail_fetches_on_reconnect() {
+		let mut con = Connection {
+			state: ConnectionState::Stopped,
+			span: Span::none(),
+			options: Connection::build("localhost").identity(Identity::create()),
+			stream_items: Default::default(),
+		};
+		let reason = TemporaryDisconnectReason::Timeout("test");
+		con.stream_items.push_back(Ok(StreamItem::DisconnectedTemporarily(reason)));
+		let mut con = SyncConnection::from(con);
+		let (send, mut recv) = oneshot::channel();
+		let data = OptionalData::ConnectionInfo(ClientId(3));
+		con.fetches.insert(MessageHandle(0), (data, Some(send)));
+
+		let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+		let item = rt.block_on(con.next());
+		assert!(matches!(item, Some(Ok(SyncStreamItem::DisconnectedTemporarily(_)))));
+		assert!(matches!(recv.try_recv(), Ok(Err(Error::NotConnected))));
+		// The data is not requested anymore and can be refreshed again
+		assert!(con.fetches.is_empty());
+	}
 }
@@ -1,17 +1,11 @@
 use once_cell::sync::Lazy;
 use ts_bookkeeping::messages::s2c::InMessage;
-use tsproto_packets::packets::{Direction, Flags, OutPacket, PacketType};
+use ts_bookkeeping::test_util::parse_msg;
 
 static TRACING: Lazy<()> = Lazy::new(|| tracing_subscriber::fmt().with_test_writer().init());
 
 pub(crate) fn create_logger() { Lazy::force(&TRACING); }
 
-fn parse_msg(msg: &str) -> InMessage {
-	let header = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
-
-	InMessage::new(&header.header(), msg.as_bytes()).unwrap()
-}
-
 fn test_iconid(input: &str, expected: u32) {
 	create_logger();
 
@@ -35,3 +29,55 @@ fn negative_iconid() { test_iconid("-96136942", 4198830354); }
 
 #[test]
 fn big_iconid() { test_iconid("18446744073225738240", 3811153920); }
+
+#[test]
+fn command_error_retry() {
+	use std::time::Duration;
+
+	use crate::{CommandError, ErrorCategory, TsError};
+
+	let mut err = CommandError::new(TsError::ClientIsFlooding);
+	assert_eq!(err.category(), ErrorCategory::Flood);
+	assert_eq!(err.retry_after(), Some(Duration::from_secs(5)));
+	err.extra_message = Some("please wait 12 seconds".into());
+	assert_eq!(err.retry_after(), Some(Duration::from_secs(12)));
+	assert!(err.user_message().contains("12 seconds"));
+
+	let err = CommandError::new(TsError::ChannelInvalidId);
+	assert_eq!(err.category(), ErrorCategory::NotFound);
+	assert_eq!(err.retry_after(), None);
+
+	let err = CommandError {
+		error: TsError::PermissionsClientInsufficient,
+		missing_permission: Some(crate::Permission(139)),
+		extra_message: None,
+	};
+	assert_eq!(err.category(), ErrorCategory::Permission);
+	assert!(err.user_message().contains("139"));
+}
+
+#[test]
+fn cancel_identity_level_increase() {
+	use futures::future;
+	use tracing::Span;
+
+	use crate::{Connection, ConnectionState, Error, Identity};
+
+	let mut con = Connection {
+		state: ConnectionState::Stopped,
+		span: Span::none(),
+		options: Connection::build("localhost").identity(Identity::create()),
+		stream_items: Default::default(),
+	};
+	con.increase_identity_level(20).unwrap();
+	assert!(con.get_identity_level_progress().is_some());
+	con.cancel_identity_level_increase();
+
+	let res = futures::executor::block_on(future::poll_fn(|cx| con.poll_next(cx)));
+	assert!(matches!(res, Some(Err(Error::IdentityLevelIncreaseCanceled))));
+	assert!(con.options.identity.is_some());
+	assert!(con.get_identity_level_progress().is_none());
+	// The stream ended, polling again must not touch the finished miner
+	let res = futures::executor::block_on(future::poll_fn(|cx| con.poll_next(cx)));
+	assert!(res.is_none());
+}
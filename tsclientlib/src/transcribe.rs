@@ -0,0 +1,296 @@
+//! Turn the speech of clients into text.
+//!
+//! A [`Transcription`] collects the audio of every speaker, e.g. from
+//! [`AudioHandler::add_tap`]. When a speaker stops talking, the segment is
+//! given to a [`Transcriber`], which is the interface to a speech recognition
+//! engine like whisper.cpp. The result is a [`TranscriptEvent`] with the time,
+//! the name of the client and the channel it talked in.
+//!
+//! Recognizing speech takes time, so a transcription should run outside of
+//! the audio callback, e.g. in [`tokio::task::spawn_blocking`].
+//!
+//! # Example
+//!
+//! ```
+//! use tsclientlib::transcribe::{BoxError, Transcriber, Transcription};
+//!
+//! struct Engine;
+//!
+//! impl Transcriber for Engine {
+//!     fn transcribe(&mut self, samples: &[f32]) -> Result<String, BoxError> {
+//!         // Call the speech recognition here
+//!         Ok(format!("{} samples", samples.len()))
+//!     }
+//! }
+//!
+//! let transcription = Transcription::new(Engine);
+//! ```
+//!
+//! [`AudioHandler::add_tap`]: crate::audio::AudioHandler::add_tap
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use thiserror::Error;
+use time::OffsetDateTime;
+use tsproto_packets::packets::OutCommand;
+
+#[cfg(feature = "audio")]
+use crate::audio::SpeakerAudio;
+use crate::{data, dsp, ChannelId, ClientId, MessageTarget};
+
+/// The sample rate of the audio from the [`AudioHandler`](crate::audio::AudioHandler).
+const INPUT_SAMPLE_RATE: u32 = 48_000;
+/// The audio from the [`AudioHandler`](crate::audio::AudioHandler) is stereo.
+const INPUT_CHANNELS: usize = 2;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+	#[error("Failed to transcribe audio of {client:?}: {source}")]
+	Transcriber { client: ClientId, source: BoxError },
+}
+
+/// A speech recognition engine.
+pub trait Transcriber: Send {
+	/// Convert mono audio with [`sample_rate`](Self::sample_rate) into text.
+	fn transcribe(&mut self, samples: &[f32]) -> std::result::Result<String, BoxError>;
+
+	/// The sample rate which the engine expects, 16 kHz by default.
+	fn sample_rate(&self) -> u32 { 16_000 }
+}
+
+/// Parameters of a [`Transcription`].
+///
+/// # Default
+///
+/// Segments shorter than 300 ms are dropped, segments are split after 30 s.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TranscriptionConfig {
+	/// Drop segments which are shorter, they usually contain only noise.
+	pub min_duration: Duration,
+	/// Split segments when a client talks longer than this.
+	pub max_duration: Duration,
+}
+
+/// Text that was spoken by a client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TranscriptEvent {
+	pub client: ClientId,
+	pub client_name: String,
+	/// The channel of the client when it started talking.
+	pub channel: ChannelId,
+	pub start: OffsetDateTime,
+	pub end: OffsetDateTime,
+	pub text: String,
+}
+
+/// Collects audio per speaker and transcribes it.
+pub struct Transcription<T: Transcriber> {
+	transcriber: T,
+	config: TranscriptionConfig,
+	segments: HashMap<ClientId, Segment>,
+}
+
+/// The audio of a speaker which is not yet transcribed.
+#[derive(Clone, Debug)]
+struct Segment {
+	client_name: String,
+	channel: ChannelId,
+	start: OffsetDateTime,
+	/// Mono samples with 48 kHz.
+	samples: Vec<f32>,
+}
+
+impl Default for TranscriptionConfig {
+	fn default() -> Self {
+		Self { min_duration: Duration::from_millis(300), max_duration: Duration::from_secs(30) }
+	}
+}
+
+impl TranscriptEvent {
+	/// The text in the form `name: text`.
+	pub fn message(&self) -> String { format!("{}: {}", self.client_name, self.text) }
+
+	/// Send the text as a message into the channel.
+	///
+	/// Messages can only be sent into the channel of our own client, returns
+	/// `None` if we are in a different channel.
+	pub fn to_channel_message(&self, book: &data::Connection) -> Option<OutCommand> {
+		let own_channel = book.clients.get(&book.own_client)?.channel;
+		(own_channel == self.channel)
+			.then(|| book.send_message(MessageTarget::Channel, &self.message()))
+	}
+}
+
+impl<T: Transcriber> Transcription<T> {
+	pub fn new(transcriber: T) -> Self { Self::with_config(transcriber, Default::default()) }
+
+	pub fn with_config(transcriber: T, config: TranscriptionConfig) -> Self {
+		Self { transcriber, config, segments: HashMap::new() }
+	}
+
+	pub fn get_transcriber(&mut self) -> &mut T { &mut self.transcriber }
+	pub fn get_config(&self) -> &TranscriptionConfig { &self.config }
+	pub fn set_config(&mut self, config: TranscriptionConfig) { self.config = config; }
+
+	/// Add audio from the [`AudioHandler`](crate::audio::AudioHandler).
+	#[cfg(feature = "audio")]
+	pub fn handle_speaker_audio(
+		&mut self, book: &data::Connection, audio: &SpeakerAudio<ClientId>,
+	) -> Result<Option<TranscriptEvent>> {
+		self.handle_audio(book, audio.id, &audio.samples, audio.is_end)
+	}
+
+	/// Add interleaved stereo samples with 48 kHz of a client.
+	///
+	/// Returns the text when the client stopped talking (`is_end`) or talked
+	/// longer than the maximum duration.
+	pub fn handle_audio(
+		&mut self, book: &data::Connection, client: ClientId, samples: &[f32], is_end: bool,
+	) -> Result<Option<TranscriptEvent>> {
+		if !self.segments.contains_key(&client) && !samples.is_empty() {
+			// Remember the name and channel, the client could leave before
+			// the segment ends.
+			let (name, channel) = book
+				.clients
+				.get(&client)
+				.map(|c| (c.name.clone(), c.channel))
+				.unwrap_or_else(|| (String::new(), ChannelId(0)));
+			self.start_segment(client, name, channel);
+		}
+		self.push(client, samples, is_end)
+	}
+
+	/// Transcribe the audio of all clients, e.g. before disconnecting.
+	pub fn flush(&mut self) -> Vec<Result<TranscriptEvent>> {
+		let clients = self.segments.keys().copied().collect::<Vec<_>>();
+		clients.into_iter().filter_map(|c| self.finish(c).transpose()).collect()
+	}
+
+	fn start_segment(&mut self, client: ClientId, client_name: String, channel: ChannelId) {
+		let segment =
+			Segment { client_name, channel, start: OffsetDateTime::now_utc(), samples: Vec::new() };
+		self.segments.insert(client, segment);
+	}
+
+	fn push(
+		&mut self, client: ClientId, samples: &[f32], is_end: bool,
+	) -> Result<Option<TranscriptEvent>> {
+		let segment = if let Some(s) = self.segments.get_mut(&client) {
+			s
+		} else {
+			return Ok(None);
+		};
+		// Mix to mono
+		segment.samples.extend(
+			samples.chunks(INPUT_CHANNELS).map(|f| f.iter().sum::<f32>() / f.len() as f32),
+		);
+
+		if is_end {
+			self.finish(client)
+		} else if duration(segment.samples.len()) >= self.config.max_duration {
+			// Continue with a new segment
+			let (name, channel) = (segment.client_name.clone(), segment.channel);
+			let res = self.finish(client);
+			self.start_segment(client, name, channel);
+			res
+		} else {
+			Ok(None)
+		}
+	}
+
+	fn finish(&mut self, client: ClientId) -> Result<Option<TranscriptEvent>> {
+		let segment = if let Some(s) = self.segments.remove(&client) {
+			s
+		} else {
+			return Ok(None);
+		};
+		let length = duration(segment.samples.len());
+		if length < self.config.min_duration {
+			return Ok(None);
+		}
+
+		let rate = self.transcriber.sample_rate();
+		let samples = dsp::resample(&segment.samples, INPUT_SAMPLE_RATE, rate);
+		let text = self
+			.transcriber
+			.transcribe(&samples)
+			.map_err(|source| Error::Transcriber { client, source })?;
+		let text = text.trim();
+		if text.is_empty() {
+			return Ok(None);
+		}
+		Ok(Some(TranscriptEvent {
+			client,
+			client_name: segment.client_name,
+			channel: segment.channel,
+			start: segment.start,
+			end: segment.start + length,
+			text: text.to_string(),
+		}))
+	}
+}
+
+/// The duration of mono samples with 48 kHz.
+fn duration(samples: usize) -> Duration {
+	Duration::from_secs_f64(samples as f64 / f64::from(INPUT_SAMPLE_RATE))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Returns the number of samples.
+	struct Counter;
+
+	impl Transcriber for Counter {
+		fn transcribe(&mut self, samples: &[f32]) -> std::result::Result<String, BoxError> {
+			if samples.iter().all(|s| *s == 0.0) {
+				Ok(" ".into())
+			} else {
+				Ok(samples.len().to_string())
+			}
+		}
+	}
+
+	#[test]
+	fn segments() {
+		let mut transcription = Transcription::new(Counter);
+		let client = ClientId(5);
+		let second = vec![0.5; 96_000];
+		transcription.start_segment(client, "Bob".into(), ChannelId(2));
+		assert!(transcription.push(client, &second, false).unwrap().is_none());
+		let event = transcription.push(client, &[], true).unwrap().unwrap();
+		assert_eq!(event.text, "16000");
+		assert_eq!(event.client_name, "Bob");
+		assert_eq!(event.channel, ChannelId(2));
+		assert_eq!(event.end - event.start, Duration::from_secs(1));
+		assert_eq!(event.message(), "Bob: 16000");
+
+		// Too short
+		transcription.start_segment(client, "Bob".into(), ChannelId(2));
+		assert!(transcription.push(client, &second[..1000], true).unwrap().is_none());
+		// Silence gives no text
+		transcription.start_segment(client, "Bob".into(), ChannelId(2));
+		assert!(transcription.push(client, &[0.0; 96_000], true).unwrap().is_none());
+	}
+
+	#[test]
+	fn split_long_segments() {
+		let config =
+			TranscriptionConfig { max_duration: Duration::from_secs(1), ..Default::default() };
+		let mut transcription = Transcription::with_config(Counter, config);
+		let client = ClientId(5);
+		transcription.start_segment(client, "Bob".into(), ChannelId(2));
+		let event = transcription.push(client, &[0.5; 100_000], false).unwrap().unwrap();
+		assert_eq!(event.text, "16666");
+		assert!(transcription.push(client, &[0.5; 48_000], false).unwrap().is_none());
+		let events = transcription.flush();
+		assert_eq!(events.len(), 1);
+		assert_eq!(events[0].as_ref().unwrap().text, "8000");
+	}
+}
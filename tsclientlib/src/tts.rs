@@ -0,0 +1,161 @@
+//! Speak text through a connection.
+//!
+//! A [`TextToSpeech`] engine, e.g. espeak, renders text into audio. The
+//! [`Speaker`] queues texts, encodes the audio with opus and returns the
+//! packets in the right pace as a [`Stream`], so they can be sent with
+//! [`Connection::send_audio`](crate::Connection::send_audio).
+//!
+//! The speaker can also announce clients joining or leaving our channel, the
+//! texts are configured in [`Announcements`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # #[cfg(feature = "audio")]
+//! # async fn f(mut con: tsclientlib::Connection) -> anyhow::Result<()> {
+//! use futures::prelude::*;
+//! use tsclientlib::tts::{Speaker, TextToSpeech};
+//! use tsclientlib::transcribe::BoxError;
+//!
+//! struct Engine;
+//!
+//! impl TextToSpeech for Engine {
+//!     fn render(&mut self, text: &str) -> Result<Vec<f32>, BoxError> {
+//!         // Call the speech synthesis here
+//!         Ok(vec![0.0; 48_000])
+//!     }
+//! }
+//!
+//! let mut speaker = Speaker::new(Engine)?;
+//! speaker.say("Hello");
+//! while let Some(packet) = speaker.next().await {
+//!     con.send_audio(packet?)?;
+//!     if !speaker.is_speaking() {
+//!         break;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "audio")]
+mod speaker;
+
+use crate::events::{Event, PropertyId, PropertyValue};
+use crate::transcribe::BoxError;
+use crate::{data, ClientId};
+#[cfg(feature = "audio")]
+pub use speaker::{Error, Speaker};
+
+/// A speech synthesis engine.
+pub trait TextToSpeech: Send {
+	/// Render text into mono audio with [`sample_rate`](Self::sample_rate).
+	fn render(&mut self, text: &str) -> std::result::Result<Vec<f32>, BoxError>;
+
+	/// The sample rate of the rendered audio, 48 kHz by default.
+	fn sample_rate(&self) -> u32 { 48_000 }
+}
+
+/// Texts which are spoken when something happens.
+///
+/// `{name}` is replaced by the name of the client.
+///
+/// # Default
+///
+/// Announces clients which join or leave our channel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Announcements {
+	/// A client joined the channel of our own client.
+	pub join: Option<String>,
+	/// A client left the channel of our own client.
+	pub leave: Option<String>,
+}
+
+impl Default for Announcements {
+	fn default() -> Self {
+		Self {
+			join: Some("{name} joined the channel".into()),
+			leave: Some("{name} left the channel".into()),
+		}
+	}
+}
+
+impl Announcements {
+	/// The texts that should be spoken for events of a book.
+	///
+	/// Has to be called after the events are applied to the book.
+	pub fn texts(&self, book: &data::Connection, events: &[Event]) -> Vec<String> {
+		let own_channel = if let Some(c) = book.clients.get(&book.own_client) {
+			c.channel
+		} else {
+			return Vec::new();
+		};
+		let channel_of = |id: &ClientId| book.clients.get(id).map(|c| c.channel);
+		let mut res = Vec::new();
+		for e in events {
+			let (template, client) = match e {
+				Event::PropertyAdded { id: PropertyId::Client(id), .. }
+					if channel_of(id) == Some(own_channel) =>
+				{
+					(&self.join, *id)
+				}
+				Event::PropertyChanged {
+					id: PropertyId::ClientChannel(id),
+					old: PropertyValue::ChannelId(old),
+					..
+				} => {
+					if channel_of(id) == Some(own_channel) {
+						(&self.join, *id)
+					} else if *old == own_channel {
+						(&self.leave, *id)
+					} else {
+						continue;
+					}
+				}
+				Event::PropertyRemoved {
+					id: PropertyId::Client(id), old: PropertyValue::Client(c), ..
+				} if c.channel == own_channel => {
+					if let Some(t) = &self.leave {
+						res.push(t.replace("{name}", &c.name));
+					}
+					continue;
+				}
+				_ => continue,
+			};
+			if client == book.own_client {
+				continue;
+			}
+			if let (Some(t), Some(c)) = (template, book.clients.get(&client)) {
+				res.push(t.replace("{name}", &c.name));
+			}
+		}
+		res
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ts_bookkeeping::test_util::{create_populated_connection, enter_view, handle};
+
+	use super::*;
+
+	#[test]
+	fn announce_join_and_leave() {
+		let mut book = create_populated_connection();
+		let announcements = Announcements::default();
+
+		let events = handle(&mut book, &enter_view(3, 1, "Alice", 0));
+		assert_eq!(announcements.texts(&book, &events), vec!["Alice joined the channel"]);
+		let events = handle(&mut book, &enter_view(4, 2, "Bob", 0));
+		assert!(announcements.texts(&book, &events).is_empty());
+
+		let events = handle(&mut book, "notifyclientmoved ctid=1 reasonid=0 clid=4");
+		assert_eq!(announcements.texts(&book, &events), vec!["Bob joined the channel"]);
+		let events = handle(&mut book, "notifyclientmoved ctid=2 reasonid=0 clid=3");
+		assert_eq!(announcements.texts(&book, &events), vec!["Alice left the channel"]);
+
+		let events = handle(&mut book, "notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=4");
+		let announcements = Announcements { join: None, leave: Some("Bye {name}".into()) };
+		assert_eq!(announcements.texts(&book, &events), vec!["Bye Bob"]);
+	}
+}
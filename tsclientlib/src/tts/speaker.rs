@@ -0,0 +1,210 @@
+//! Turn queued texts into audio packets.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use audiopus::coder::Encoder;
+use futures::prelude::*;
+use thiserror::Error;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Duration, Interval, MissedTickBehavior};
+use tsproto_packets::packets::{CodecType, OutPacket};
+
+use super::{Announcements, TextToSpeech};
+use crate::data;
+use crate::events::Event;
+use crate::transcribe::BoxError;
+use crate::whisper::{self, WhisperTarget};
+
+/// The amount of samples in a 20 ms frame.
+const FRAME_SIZE: usize = 48_000 / 50;
+/// The maximum size of an opus frame is 1275 as from RFC6716.
+const MAX_OPUS_FRAME_SIZE: usize = 1275;
+
+type Result<T> = std::result::Result<T, Error>;
+/// Renders a text on the blocking thread pool and returns the engine afterwards.
+type RenderTask<T> = JoinHandle<(T, std::result::Result<Vec<f32>, BoxError>)>;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+	#[error("Failed to create opus encoder: {0}")]
+	CreateEncoder(#[source] audiopus::Error),
+	#[error("Opus encode failed: {0}")]
+	Encode(#[source] audiopus::Error),
+	#[error("Failed to render {text:?}: {source}")]
+	Render { text: String, source: BoxError },
+}
+
+/// Queues texts and returns them as audio packets.
+///
+/// The stream never ends, it returns packets in a 20 ms interval while
+/// speaking. Texts are rendered with [`tokio::task::spawn_blocking`], so the
+/// stream has to be polled inside a tokio runtime.
+pub struct Speaker<T: TextToSpeech> {
+	/// The engine, `None` while it renders a text.
+	tts: Option<T>,
+	encoder: Encoder,
+	announcements: Announcements,
+	whisper_target: Option<WhisperTarget>,
+	queue: VecDeque<String>,
+	/// The encoded packets of the current text.
+	packets: VecDeque<OutPacket>,
+	/// The text that is currently rendered.
+	rendering: Option<(String, RenderTask<T>)>,
+	interval: Interval,
+	waker: Option<Waker>,
+}
+
+impl<T: TextToSpeech> Speaker<T> {
+	pub fn new(tts: T) -> Result<Self> {
+		let encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Voip,
+		)
+		.map_err(Error::CreateEncoder)?;
+		let mut interval = time::interval(Duration::from_millis(20));
+		interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		Ok(Self {
+			tts: Some(tts),
+			encoder,
+			announcements: Default::default(),
+			whisper_target: None,
+			queue: Default::default(),
+			packets: Default::default(),
+			rendering: None,
+			interval,
+			waker: None,
+		})
+	}
+
+	/// The engine, `None` while it renders a text.
+	pub fn get_tts(&mut self) -> Option<&mut T> { self.tts.as_mut() }
+	pub fn get_announcements(&self) -> &Announcements { &self.announcements }
+	pub fn set_announcements(&mut self, announcements: Announcements) {
+		self.announcements = announcements;
+	}
+
+	/// Whisper to the given target instead of talking in the current channel.
+	pub fn set_whisper_target(&mut self, target: Option<WhisperTarget>) {
+		self.whisper_target = target;
+	}
+
+	/// Add a text to the queue.
+	pub fn say<S: Into<String>>(&mut self, text: S) {
+		self.queue.push_back(text.into());
+		if let Some(waker) = self.waker.take() {
+			waker.wake();
+		}
+	}
+
+	/// Queue the announcements for events of a book.
+	pub fn handle_events(&mut self, book: &data::Connection, events: &[Event]) {
+		for text in self.announcements.texts(book, events) {
+			self.say(text);
+		}
+	}
+
+	/// If there is audio left to send.
+	pub fn is_speaking(&self) -> bool {
+		!self.packets.is_empty() || self.rendering.is_some() || !self.queue.is_empty()
+	}
+
+	/// Stop speaking and clear the queue.
+	///
+	/// A text which is currently rendered is still spoken.
+	pub fn clear(&mut self) {
+		self.queue.clear();
+		if !self.packets.is_empty() {
+			// Only keep the end of the stream
+			let end = self.packets.pop_back();
+			self.packets.clear();
+			self.packets.extend(end);
+		}
+	}
+
+	/// Start rendering the next text in the queue.
+	fn start_render(&mut self)
+	where T: 'static {
+		if self.rendering.is_some() {
+			return;
+		}
+		let (mut tts, text) = match (self.tts.take(), self.queue.pop_front()) {
+			(Some(tts), Some(text)) => (tts, text),
+			(tts, _) => {
+				self.tts = tts;
+				return;
+			}
+		};
+		let task_text = text.clone();
+		let task = task::spawn_blocking(move || {
+			let samples = tts
+				.render(&task_text)
+				.map(|s| crate::dsp::resample(&s, tts.sample_rate(), 48_000));
+			(tts, samples)
+		});
+		self.rendering = Some((text, task));
+	}
+
+	/// Encode rendered audio into packets.
+	fn encode(&mut self, samples: &[f32]) -> Result<()> {
+		let mut output = [0; MAX_OPUS_FRAME_SIZE];
+		let target = self.whisper_target.as_ref();
+		for chunk in samples.chunks(FRAME_SIZE) {
+			let mut frame = [0.0; FRAME_SIZE];
+			frame[..chunk.len()].copy_from_slice(chunk);
+			let len =
+				self.encoder.encode_float(&frame[..], &mut output[..]).map_err(Error::Encode)?;
+			self.packets.push_back(whisper::create_audio_packet(
+				target,
+				CodecType::OpusVoice,
+				&output[..len],
+			));
+		}
+		// End of stream
+		self.packets.push_back(whisper::create_audio_packet(target, CodecType::OpusVoice, &[]));
+		self.interval.reset();
+		Ok(())
+	}
+}
+
+impl<T: TextToSpeech + Unpin + 'static> Stream for Speaker<T> {
+	type Item = Result<OutPacket>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		let this = &mut *self;
+		if this.packets.is_empty() {
+			this.start_render();
+			let (text, task) = if let Some(r) = &mut this.rendering {
+				r
+			} else {
+				this.waker = Some(cx.waker().clone());
+				return Poll::Pending;
+			};
+			let res = match Pin::new(task).poll(cx) {
+				Poll::Pending => return Poll::Pending,
+				Poll::Ready(Ok((tts, res))) => {
+					this.tts = Some(tts);
+					res.map_err(|source| Error::Render { text: std::mem::take(text), source })
+				}
+				Poll::Ready(Err(e)) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+				// The runtime shuts down
+				Poll::Ready(Err(_)) => {
+					this.rendering = None;
+					return Poll::Ready(None);
+				}
+			};
+			this.rendering = None;
+			if let Err(e) = res.and_then(|samples| this.encode(&samples)) {
+				return Poll::Ready(Some(Err(e)));
+			}
+		}
+
+		if this.interval.poll_tick(cx).is_pending() {
+			return Poll::Pending;
+		}
+		Poll::Ready(this.packets.pop_front().map(Ok))
+	}
+}
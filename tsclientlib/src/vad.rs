@@ -0,0 +1,203 @@
+//! Decide when captured audio should be sent.
+//!
+//! The real TeamSpeak client does not send silence. It only sends audio while
+//! push-to-talk is pressed or while someone speaks, and marks the end of
+//! speech with an empty audio packet. Other clients use this to show the
+//! talking indicator.
+//!
+//! A [`VoiceGate`] gets every captured frame and returns what should be done
+//! with it. Voice activation compares the loudness of a frame with a
+//! threshold and keeps sending for a hangover time after the last loud frame,
+//! so short pauses between words are not cut off.
+//!
+//! # Example
+//!
+//! ```
+//! use std::time::Duration;
+//! use tsclientlib::vad::{GateDecision, TransmitMode, VoiceGate};
+//!
+//! let mut gate = VoiceGate::new(TransmitMode::VoiceActivation);
+//! let frame = vec![0.0; 960];
+//! match gate.process(&frame, Duration::from_millis(20)) {
+//!     GateDecision::Send => { /* Encode and send the frame */ }
+//!     GateDecision::EndOfStream => { /* Send an empty packet */ }
+//!     GateDecision::Silent => {}
+//! }
+//! ```
+
+use std::time::Duration;
+
+/// The loudness in dBFS which is used for silence.
+const SILENCE_DB: f32 = -100.0;
+
+/// When audio should be sent.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TransmitMode {
+	/// Send all audio.
+	Continuous,
+	/// Send audio when it is louder than the threshold.
+	VoiceActivation,
+	/// Send audio while push-to-talk is pressed.
+	PushToTalk,
+}
+
+/// What should be done with a captured frame.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum GateDecision {
+	/// Encode and send the frame.
+	Send,
+	/// Drop the frame.
+	Silent,
+	/// Drop the frame and send an empty packet to end the audio stream.
+	EndOfStream,
+}
+
+/// Gates captured audio by voice activity or push-to-talk.
+#[derive(Clone, Debug)]
+pub struct VoiceGate {
+	mode: TransmitMode,
+	threshold: f32,
+	hangover: Duration,
+	push_to_talk: bool,
+
+	/// If we are currently sending.
+	active: bool,
+	/// The time since the last loud frame.
+	quiet_time: Duration,
+	/// The loudness of the last frame in dBFS.
+	level: f32,
+}
+
+impl VoiceGate {
+	/// Create a gate with a threshold of -40 dBFS and 300 ms hangover.
+	pub fn new(mode: TransmitMode) -> Self {
+		Self {
+			mode,
+			threshold: -40.0,
+			hangover: Duration::from_millis(300),
+			push_to_talk: false,
+
+			active: false,
+			quiet_time: Duration::ZERO,
+			level: SILENCE_DB,
+		}
+	}
+
+	#[inline]
+	pub fn get_mode(&self) -> TransmitMode { self.mode }
+	#[inline]
+	pub fn set_mode(&mut self, mode: TransmitMode) { self.mode = mode; }
+
+	/// The loudness in dBFS above which voice activation sends audio.
+	#[inline]
+	pub fn get_threshold(&self) -> f32 { self.threshold }
+	#[inline]
+	pub fn set_threshold(&mut self, threshold: f32) { self.threshold = threshold; }
+
+	/// How long voice activation keeps sending after the last loud frame.
+	#[inline]
+	pub fn get_hangover(&self) -> Duration { self.hangover }
+	#[inline]
+	pub fn set_hangover(&mut self, hangover: Duration) { self.hangover = hangover; }
+
+	#[inline]
+	pub fn is_push_to_talk_pressed(&self) -> bool { self.push_to_talk }
+	/// Press or release push-to-talk.
+	#[inline]
+	pub fn set_push_to_talk(&mut self, pressed: bool) { self.push_to_talk = pressed; }
+	/// Toggle push-to-talk and return if it is pressed now.
+	pub fn toggle_push_to_talk(&mut self) -> bool {
+		self.push_to_talk = !self.push_to_talk;
+		self.push_to_talk
+	}
+
+	/// If the last frame was sent.
+	#[inline]
+	pub fn is_active(&self) -> bool { self.active }
+
+	/// The loudness of the last frame in dBFS.
+	#[inline]
+	pub fn get_level(&self) -> f32 { self.level }
+
+	/// Stop sending, e.g. after the end of the stream was sent manually.
+	pub fn reset(&mut self) {
+		self.active = false;
+		self.quiet_time = Duration::ZERO;
+	}
+
+	/// Decide what should happen with a frame of `duration`.
+	pub fn process(&mut self, samples: &[f32], duration: Duration) -> GateDecision {
+		self.level = level(samples);
+		let send = match self.mode {
+			TransmitMode::Continuous => true,
+			TransmitMode::PushToTalk => self.push_to_talk,
+			TransmitMode::VoiceActivation => {
+				if self.level >= self.threshold {
+					self.quiet_time = Duration::ZERO;
+					true
+				} else {
+					self.quiet_time += duration;
+					self.active && self.quiet_time <= self.hangover
+				}
+			}
+		};
+
+		let was_active = self.active;
+		self.active = send;
+		match (was_active, send) {
+			(_, true) => GateDecision::Send,
+			(true, false) => GateDecision::EndOfStream,
+			(false, false) => GateDecision::Silent,
+		}
+	}
+}
+
+/// The loudness of samples in dBFS, computed from the root mean square.
+pub fn level(samples: &[f32]) -> f32 {
+	if samples.is_empty() {
+		return SILENCE_DB;
+	}
+	let sum: f32 = samples.iter().map(|s| s * s).sum();
+	let rms = (sum / samples.len() as f32).sqrt();
+	if rms <= 0.0 { SILENCE_DB } else { (20.0 * rms.log10()).max(SILENCE_DB) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FRAME: Duration = Duration::from_millis(20);
+
+	#[test]
+	fn voice_activation_hangover() {
+		let loud = vec![0.5; 960];
+		let quiet = vec![0.001; 960];
+		let mut gate = VoiceGate::new(TransmitMode::VoiceActivation);
+		gate.set_hangover(Duration::from_millis(40));
+
+		assert_eq!(gate.process(&quiet, FRAME), GateDecision::Silent);
+		assert_eq!(gate.process(&loud, FRAME), GateDecision::Send);
+		assert!(gate.get_level() > -10.0);
+		// Hangover
+		assert_eq!(gate.process(&quiet, FRAME), GateDecision::Send);
+		assert_eq!(gate.process(&quiet, FRAME), GateDecision::Send);
+		assert_eq!(gate.process(&quiet, FRAME), GateDecision::EndOfStream);
+		assert_eq!(gate.process(&quiet, FRAME), GateDecision::Silent);
+		assert!(!gate.is_active());
+	}
+
+	#[test]
+	fn push_to_talk() {
+		let loud = vec![0.5; 960];
+		let mut gate = VoiceGate::new(TransmitMode::PushToTalk);
+		assert_eq!(gate.process(&loud, FRAME), GateDecision::Silent);
+		assert!(gate.toggle_push_to_talk());
+		assert_eq!(gate.process(&[0.0; 960], FRAME), GateDecision::Send);
+		gate.set_push_to_talk(false);
+		assert_eq!(gate.process(&loud, FRAME), GateDecision::EndOfStream);
+
+		gate.set_mode(TransmitMode::Continuous);
+		assert_eq!(gate.process(&[], FRAME), GateDecision::Send);
+		assert_eq!(level(&[]), SILENCE_DB);
+	}
+}
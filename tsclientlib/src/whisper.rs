@@ -0,0 +1,168 @@
+//! Send audio to a selected set of clients instead of the current channel.
+//!
+//! A [`WhisperTarget`] can either be a list of channels and clients or a group
+//! of clients, like all clients of a server group in all channels.
+//!
+//! # Example
+//!
+//! Whisper to all moderators.
+//!
+//! ```
+//! # use tsclientlib::ServerGroupId;
+//! use tsclientlib::whisper::*;
+//! use tsproto_packets::packets::CodecType;
+//! let moderators = ServerGroupId(9);
+//! let target = WhisperTarget::Group {
+//!     group: GroupWhisperType::ServerGroup(moderators),
+//!     target: GroupWhisperTarget::AllChannels,
+//! };
+//! let packet = target.create_packet(CodecType::OpusVoice, &[1, 2, 3]);
+//! // con.send_audio(packet)?;
+//! ```
+
+use tsproto_packets::packets::{AudioData, CodecType, OutAudio, OutPacket};
+
+use crate::{ChannelGroupId, ChannelId, ClientId, ServerGroupId};
+
+/// The clients that should receive whispered audio.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum WhisperTarget {
+	/// Whisper to all clients in the given channels and to the given clients.
+	List { channels: Vec<ChannelId>, clients: Vec<ClientId> },
+	/// Whisper to a group of clients in a set of channels.
+	Group { group: GroupWhisperType, target: GroupWhisperTarget },
+}
+
+/// Which clients should receive the whisper.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum GroupWhisperType {
+	/// All clients in a server group.
+	ServerGroup(ServerGroupId),
+	/// All clients with a channel group.
+	ChannelGroup(ChannelGroupId),
+	/// All channel commanders.
+	ChannelCommander,
+	/// All clients.
+	AllClients,
+}
+
+/// In which channels clients should receive the whisper.
+///
+/// The discriminant is the target that is sent in whisper packets.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u8)]
+pub enum GroupWhisperTarget {
+	AllChannels = 0,
+	CurrentChannel = 1,
+	ParentChannel = 2,
+	AllParentChannels = 3,
+	ChannelFamily = 4,
+	CompleteChannelFamily = 5,
+	Subchannels = 6,
+}
+
+impl WhisperTarget {
+	/// Whisper to a list of channels and clients.
+	pub fn list<C, L>(channels: C, clients: L) -> Self
+	where
+		C: IntoIterator<Item = ChannelId>,
+		L: IntoIterator<Item = ClientId>,
+	{
+		Self::List {
+			channels: channels.into_iter().collect(),
+			clients: clients.into_iter().collect(),
+		}
+	}
+
+	/// The content of an audio packet which is sent to this target.
+	///
+	/// The `id` is overwritten when sending the packet.
+	pub fn audio_data<'a>(&self, codec: CodecType, data: &'a [u8]) -> AudioData<'a> {
+		match self {
+			Self::List { channels, clients } => AudioData::C2SWhisper {
+				id: 0,
+				codec,
+				channels: channels.iter().map(|c| c.0).collect(),
+				clients: clients.iter().map(|c| c.0).collect(),
+				data,
+			},
+			Self::Group { group, target } => {
+				let (whisper_type, target_id) = match group {
+					GroupWhisperType::ServerGroup(id) => (0, id.0),
+					GroupWhisperType::ChannelGroup(id) => (1, id.0),
+					GroupWhisperType::ChannelCommander => (2, 0),
+					GroupWhisperType::AllClients => (3, 0),
+				};
+				AudioData::C2SWhisperNew {
+					id: 0,
+					codec,
+					whisper_type,
+					target: *target as u8,
+					target_id,
+					data,
+				}
+			}
+		}
+	}
+
+	/// Create an audio packet that can be sent with
+	/// [`Connection::send_audio`](crate::Connection::send_audio).
+	pub fn create_packet(&self, codec: CodecType, data: &[u8]) -> OutPacket {
+		OutAudio::new(&self.audio_data(codec, data))
+	}
+}
+
+/// Create an audio packet which is sent to the current channel if `target` is
+/// `None` or whispered to the target otherwise.
+///
+/// An empty `data` slice signals the end of the audio stream.
+pub fn create_audio_packet(
+	target: Option<&WhisperTarget>, codec: CodecType, data: &[u8],
+) -> OutPacket {
+	match target {
+		Some(target) => target.create_packet(codec, data),
+		None => OutAudio::new(&AudioData::C2S { id: 0, codec, data }),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tsproto_packets::packets::{Direction, InPacket};
+
+	use super::*;
+
+	fn check(packet: &OutPacket, expected: AudioData) {
+		let packet = InPacket::try_new(Direction::C2S, packet.data()).unwrap();
+		assert_eq!(packet.into_audio().unwrap().data(), &expected);
+	}
+
+	#[test]
+	fn whisper_list() {
+		let target = WhisperTarget::list(vec![ChannelId(4), ChannelId(5)], vec![ClientId(7)]);
+		let packet = create_audio_packet(Some(&target), CodecType::OpusVoice, &[1, 2]);
+		check(&packet, AudioData::C2SWhisper {
+			id: 0,
+			codec: CodecType::OpusVoice,
+			channels: vec![4, 5],
+			clients: vec![7],
+			data: &[1, 2],
+		});
+	}
+
+	#[test]
+	fn whisper_group() {
+		let target = WhisperTarget::Group {
+			group: GroupWhisperType::ServerGroup(ServerGroupId(9)),
+			target: GroupWhisperTarget::Subchannels,
+		};
+		let packet = target.create_packet(CodecType::OpusMusic, &[3]);
+		check(&packet, AudioData::C2SWhisperNew {
+			id: 0,
+			codec: CodecType::OpusMusic,
+			whisper_type: 0,
+			target: 6,
+			target_id: 9,
+			data: &[3],
+		});
+	}
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use clap::Parser;
+use tsproto::miner::Miner;
+use tsproto::Identity;
+
+#[derive(Parser, Debug)]
+#[command(author, about)]
+struct Args {
+	/// The identity in the TeamSpeak format (`<counter>V<key>`) or a private
+	/// key. A new identity is created if this is omitted.
+	#[arg(short, long)]
+	identity: Option<String>,
+	/// Resume from this counter, all counters below were already checked
+	#[arg(short, long)]
+	start: Option<u64>,
+	/// The level that should be reached
+	#[arg(short, long)]
+	level: u8,
+	/// The number of threads, defaults to the number of cpu cores
+	#[arg(short, long)]
+	threads: Option<usize>,
+	/// Stop after this many seconds, the computation can be resumed later
+	#[arg(long)]
+	timeout: Option<u64>,
+}
+
+#[tokio::main]
+async fn main() {
+	// Parse command line options
+	let args = Args::parse();
+
+	let mut identity = match &args.identity {
+		Some(id) => Identity::new_from_str(id).unwrap(),
+		None => Identity::create(),
+	};
+	if let Some(start) = args.start {
+		identity.set_max_counter(start.max(identity.max_counter()));
+	}
+	println!("Uid: {}", identity.key().to_pub().get_uid());
+	println!("Current level: {}", identity.level());
+
+	let mut miner = Miner::new(identity, args.level);
+	if let Some(threads) = args.threads {
+		miner = miner.threads(threads);
+	}
+	let handle = miner.start();
+	let control = handle.control();
+	let mut waiter = tokio::task::spawn_blocking(move || handle.wait());
+
+	let timeout = tokio::time::sleep(Duration::from_secs(args.timeout.unwrap_or(u64::MAX / 4)));
+	tokio::pin!(timeout);
+	let mut timed_out = false;
+	let mut interval = tokio::time::interval(Duration::from_secs(1));
+	let identity = loop {
+		tokio::select! {
+			res = &mut waiter => break res.unwrap(),
+			_ = interval.tick() => {
+				let p = control.progress();
+				let remaining = p
+					.estimated_remaining()
+					.map(|d| format!("{}s", d.as_secs()))
+					.unwrap_or_else(|| "-".into());
+				println!(
+					"Checked {} ({:.0}/s), best level {}, about {} remaining",
+					p.checked,
+					p.rate(),
+					p.best_level,
+					remaining
+				);
+			}
+			_ = &mut timeout, if !timed_out => {
+				timed_out = true;
+				control.cancel();
+			}
+			_ = tokio::signal::ctrl_c() => control.cancel(),
+		}
+	};
+
+	println!("Level: {}", identity.level());
+	println!("Identity: {}", identity.to_ts_str());
+	if identity.level() < args.level {
+		println!(
+			"Canceled, resume with --identity {} --start {}",
+			identity.to_ts_str(),
+			identity.max_counter()
+		);
+	}
+}
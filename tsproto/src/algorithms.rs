@@ -1,5 +1,8 @@
 //! Handle packet splitting and cryptography
-use curve25519_dalek_ng::edwards::EdwardsPoint;
+use std::convert::TryInto;
+
+use curve25519_dalek_ng::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek_ng::scalar::Scalar;
 use eax::aead::consts::{U16, U8};
 use eax::{AeadInPlace, Eax, KeyInit};
 use generic_array::GenericArray;
@@ -249,8 +252,14 @@ pub fn hash_cash(key: &EccKeyPubP256, level: u8) -> u64 {
 #[inline]
 pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
 	let data = Sha1::digest(format!("{}{}", omega, offset).as_bytes());
+	hash_cash_level(data.as_slice())
+}
+
+/// The level of a hash, which is the number of trailing zero bits.
+#[inline]
+pub(crate) fn hash_cash_level(hash: &[u8]) -> u8 {
 	let mut res = 0;
-	for &d in data.as_slice() {
+	for &d in hash {
 		if d == 0 {
 			res += 8;
 		} else {
@@ -261,6 +270,37 @@ pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
 	res
 }
 
+/// Check the signature of a client version.
+///
+/// The signature is an Ed25519 signature of `platform || version`, created with
+/// the [`VERSION_SIGN_KEY`](crate::VERSION_SIGN_KEY).
+pub fn verify_version_signature(platform: &str, version: &str, signature: &[u8]) -> Result<()> {
+	if signature.len() != 64 {
+		return Err(Error::WrongVersionSignature);
+	}
+	let key = CompressedEdwardsY(crate::VERSION_SIGN_KEY)
+		.decompress()
+		.ok_or(Error::WrongVersionSignature)?;
+	let (r, s) = signature.split_at(32);
+	let s = Scalar::from_canonical_bytes(s.try_into().unwrap())
+		.ok_or(Error::WrongVersionSignature)?;
+
+	let mut hasher = Sha512::new();
+	hasher.update(r);
+	hasher.update(crate::VERSION_SIGN_KEY);
+	hasher.update(platform.as_bytes());
+	hasher.update(version.as_bytes());
+	let k = Scalar::from_bytes_mod_order_wide(&hasher.finalize().into());
+
+	// Check s * B = R + k * A
+	let expected_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(&k, &-key, &s);
+	if expected_r.compress().as_bytes() == r {
+		Ok(())
+	} else {
+		Err(Error::WrongVersionSignature)
+	}
+}
+
 pub fn biguint_to_array(i: &BigUint) -> [u8; 64] {
 	let mut v = i.to_bytes_le();
 
@@ -285,6 +325,19 @@ mod tests {
 	use crate::packets::PacketType;
 	use crate::utils;
 	use tsproto_types::crypto::EccKeyPubEd25519;
+	use tsproto_types::versions::Version;
+
+	#[test]
+	fn version_signature() {
+		for v in &[Version::Linux_3_0_19, Version::Windows_3_6_0__9] {
+			verify_version_signature(v.get_platform(), v.get_version_string(), v.get_signature())
+				.unwrap();
+		}
+
+		let v = Version::Linux_3_0_19;
+		assert!(verify_version_signature("Windows", v.get_version_string(), v.get_signature())
+			.is_err());
+	}
 
 	#[test]
 	fn test_fake_crypt() {
@@ -26,6 +26,7 @@ pub mod client;
 pub mod connection;
 pub mod license;
 pub mod log;
+pub mod miner;
 pub mod packet_codec;
 pub mod resend;
 pub mod utils;
@@ -70,6 +71,12 @@ pub const ROOT_KEY: [u8; 32] = [
 	0x75, 0x55, 0xb2, 0x9d, 0xcc, 0xec, 0x73, 0xcd, 0x18, 0x75, 0x0f, 0x99, 0x38, 0x12, 0x40, 0x8a,
 ];
 
+/// The public key that is used to sign TeamSpeak client versions.
+pub const VERSION_SIGN_KEY: [u8; 32] = [
+	0x52, 0xb3, 0x75, 0x8d, 0x7d, 0x1d, 0x04, 0x4d, 0x6f, 0xba, 0x54, 0xcd, 0x2c, 0x2a, 0x18, 0xc2,
+	0xb5, 0x69, 0x7c, 0x84, 0xf2, 0xa3, 0xe3, 0x41, 0xba, 0xaf, 0xed, 0xc1, 0xb7, 0xfd, 0x84, 0xbc,
+];
+
 /// The maximum amount of ack pachets that a connection intermediately stores.
 ///
 /// When this amount is stored, no new packets will be polled from the UDP
@@ -107,6 +114,8 @@ pub enum Error {
 	WrongAddress,
 	#[error("{p_type:?} Packet {generation_id}:{packet_id} has a wrong mac")]
 	WrongMac { p_type: packets::PacketType, generation_id: u32, packet_id: u16 },
+	#[error("Wrong version signature")]
+	WrongVersionSignature,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -193,6 +202,15 @@ impl Identity {
 		Ok(res)
 	}
 
+	/// Export the identity in the format that is used by the TeamSpeak client.
+	///
+	/// Format: counter || 'V' || obfuscated key
+	///
+	/// The result can be read again with [`Identity::new_from_ts_str`].
+	pub fn to_ts_str(&self) -> String {
+		format!("{}V{}", self.counter, self.key.to_ts_obfuscated())
+	}
+
 	#[inline]
 	pub fn key(&self) -> &EccKeyPrivP256 { &self.key }
 	#[inline]
@@ -250,4 +268,15 @@ mod identity_tests {
 		assert_eq!(TEST_UID, &uid);
 		assert_eq!(identity.level(), 21u8);
 	}
+
+	#[test]
+	fn ts_str_roundtrip() {
+		let ident_str = String::from("2792354V") + TEST_PRIV_KEY;
+		let identity = Identity::new_from_str(&ident_str).unwrap();
+		let exported = identity.to_ts_str();
+		assert!(exported.starts_with("2792354V"));
+		let imported = Identity::new_from_ts_str(&exported).unwrap();
+		assert_eq!(TEST_UID, &imported.key().to_pub().get_uid());
+		assert_eq!(imported.counter(), 2792354);
+	}
 }
@@ -0,0 +1,377 @@
+//! Compute the identity level (hash cash) on multiple threads.
+//!
+//! The counter range is split into chunks, which are handed out to the worker
+//! threads. The miner keeps track of the counter up to which all values were
+//! checked, so a canceled computation can be resumed later from the returned
+//! [`Identity::max_counter`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use tsproto::Identity;
+//! # use tsproto::miner::Miner;
+//! let identity = Identity::create();
+//! let handle = Miner::new(identity, 24).start();
+//! let control = handle.control();
+//! std::thread::spawn(move || loop {
+//!     std::thread::sleep(std::time::Duration::from_secs(1));
+//!     println!("{:?}", control.progress());
+//! });
+//! let identity = handle.wait();
+//! assert!(identity.level() >= 24);
+//! ```
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
+
+use crate::algorithms as algs;
+use crate::Identity;
+
+/// The default amount of counters which are checked by a thread at once.
+const DEFAULT_CHUNK_SIZE: u64 = 65_536;
+/// Check every this many counters if the computation should stop.
+const STOP_CHECK_INTERVAL: u64 = 4096;
+
+/// Compute a higher identity level for an [`Identity`].
+#[derive(Clone, Debug)]
+pub struct Miner {
+	identity: Identity,
+	target: u8,
+	threads: usize,
+	chunk_size: u64,
+}
+
+/// The current state of a running [`Miner`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Progress {
+	/// The level that should be reached.
+	pub target: u8,
+	/// All counters below this value were checked.
+	pub checked: u64,
+	/// The amount of counters that were checked in this run.
+	pub tried: u64,
+	/// The best counter that was found so far.
+	pub best_counter: u64,
+	/// The level of `best_counter`.
+	pub best_level: u8,
+	/// The time since the miner was started.
+	pub elapsed: Duration,
+}
+
+/// A handle to a running [`Miner`].
+///
+/// Dropping the handle does not stop the worker threads, use
+/// [`MinerHandle::cancel`] for that.
+#[derive(Debug)]
+pub struct MinerHandle {
+	identity: Identity,
+	shared: Arc<Shared>,
+	threads: Vec<JoinHandle<()>>,
+}
+
+/// Query the progress or cancel a [`Miner`] from another thread.
+#[derive(Clone, Debug)]
+pub struct MinerControl {
+	shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+	omega: String,
+	target: u8,
+	chunk_size: u64,
+	started: Instant,
+	tried: AtomicU64,
+	/// Set if a solution was found or the miner was canceled.
+	stop: AtomicBool,
+	canceled: AtomicBool,
+	state: Mutex<SharedState>,
+}
+
+#[derive(Debug)]
+struct SharedState {
+	/// The start of the next chunk that will be handed out.
+	next: u64,
+	/// The starts of all chunks that are not yet fully checked.
+	in_progress: BTreeSet<u64>,
+	best_counter: u64,
+	best_level: u8,
+}
+
+impl Miner {
+	/// Create a miner that upgrades `identity` to the `target` level.
+	///
+	/// The search starts at [`Identity::max_counter`]. By default, one thread
+	/// per available cpu core is used.
+	pub fn new(identity: Identity, target: u8) -> Self {
+		let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+		Self { identity, target, threads, chunk_size: DEFAULT_CHUNK_SIZE }
+	}
+
+	/// Set the number of worker threads.
+	#[inline]
+	pub fn threads(mut self, threads: usize) -> Self {
+		self.threads = threads.max(1);
+		self
+	}
+
+	/// Set the amount of counters that a thread checks at once.
+	///
+	/// When a computation is canceled, at most this many counters per thread
+	/// have to be checked again on resume.
+	#[inline]
+	pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+		self.chunk_size = chunk_size.max(1);
+		self
+	}
+
+	/// Start the worker threads.
+	pub fn start(self) -> MinerHandle {
+		let level = self.identity.level();
+		let shared = Arc::new(Shared {
+			omega: self.identity.key().to_pub().to_ts(),
+			target: self.target,
+			chunk_size: self.chunk_size,
+			started: Instant::now(),
+			tried: AtomicU64::new(0),
+			stop: AtomicBool::new(level >= self.target),
+			canceled: AtomicBool::new(false),
+			state: Mutex::new(SharedState {
+				next: self.identity.max_counter(),
+				in_progress: BTreeSet::new(),
+				best_counter: self.identity.counter(),
+				best_level: level,
+			}),
+		});
+
+		let threads = (0..self.threads)
+			.map(|i| {
+				let shared = shared.clone();
+				thread::Builder::new()
+					.name(format!("hash cash miner {}", i))
+					.spawn(move || shared.work())
+					.expect("Failed to spawn miner thread")
+			})
+			.collect();
+		MinerHandle { identity: self.identity, shared, threads }
+	}
+
+	/// Compute the level on the current thread, blocking until it is reached.
+	pub fn run(self) -> Identity { self.start().wait() }
+}
+
+impl Progress {
+	/// Checked counters per second.
+	pub fn rate(&self) -> f64 {
+		let secs = self.elapsed.as_secs_f64();
+		if secs > 0.0 { self.tried as f64 / secs } else { 0.0 }
+	}
+
+	/// The expected time until the target level is reached.
+	///
+	/// Returns `None` if the target is already reached or the rate is not yet
+	/// known.
+	pub fn estimated_remaining(&self) -> Option<Duration> {
+		let rate = self.rate();
+		if self.best_level >= self.target || rate <= 0.0 {
+			return None;
+		}
+		Some(Duration::from_secs_f64(expected_tries(self.target) / rate))
+	}
+}
+
+impl MinerHandle {
+	pub fn control(&self) -> MinerControl { MinerControl { shared: self.shared.clone() } }
+
+	#[inline]
+	pub fn progress(&self) -> Progress { self.shared.progress() }
+
+	/// Stop the computation, [`MinerHandle::wait`] returns the progress so far.
+	#[inline]
+	pub fn cancel(&self) { self.shared.cancel() }
+
+	/// If all worker threads finished.
+	pub fn is_finished(&self) -> bool { self.threads.iter().all(|t| t.is_finished()) }
+
+	/// Wait until the target level is reached or the miner is canceled.
+	///
+	/// The returned identity has the best found counter. Its `max_counter` is
+	/// set so that a new miner resumes where this one stopped.
+	pub fn wait(self) -> Identity {
+		for t in self.threads {
+			if t.join().is_err() {
+				// Make sure all other threads stop too
+				self.shared.stop.store(true, Ordering::Relaxed);
+			}
+		}
+
+		let progress = self.shared.progress();
+		let mut identity = self.identity;
+		if progress.best_level > identity.level() {
+			identity.set_counter(progress.best_counter);
+		}
+		let max_counter = identity.max_counter().max(progress.checked).max(identity.counter());
+		identity.set_max_counter(max_counter);
+		identity
+	}
+}
+
+impl MinerControl {
+	#[inline]
+	pub fn progress(&self) -> Progress { self.shared.progress() }
+
+	/// Stop the computation, [`MinerHandle::wait`] returns the progress so far.
+	#[inline]
+	pub fn cancel(&self) { self.shared.cancel() }
+
+	#[inline]
+	pub fn is_canceled(&self) -> bool { self.shared.canceled.load(Ordering::Relaxed) }
+}
+
+impl Shared {
+	fn progress(&self) -> Progress {
+		let state = self.state.lock().unwrap();
+		Progress {
+			target: self.target,
+			checked: state.in_progress.iter().next().copied().unwrap_or(state.next),
+			tried: self.tried.load(Ordering::Relaxed),
+			best_counter: state.best_counter,
+			best_level: state.best_level,
+			elapsed: self.started.elapsed(),
+		}
+	}
+
+	fn cancel(&self) {
+		self.canceled.store(true, Ordering::Relaxed);
+		self.stop.store(true, Ordering::Relaxed);
+	}
+
+	fn work(&self) {
+		let prefix = Sha1::new_with_prefix(self.omega.as_bytes());
+		let mut buf = [0; 20];
+		while !self.stop.load(Ordering::Relaxed) {
+			let start = {
+				let mut state = self.state.lock().unwrap();
+				let start = state.next;
+				if start == u64::MAX {
+					return;
+				}
+				state.next = start.saturating_add(self.chunk_size);
+				state.in_progress.insert(start);
+				start
+			};
+			let end = start.saturating_add(self.chunk_size);
+
+			let mut best = (0, 0);
+			let mut finished = true;
+			let mut counter = start;
+			while counter < end {
+				if (counter - start) % STOP_CHECK_INTERVAL == 0
+					&& self.stop.load(Ordering::Relaxed)
+				{
+					finished = false;
+					break;
+				}
+				let mut hasher = prefix.clone();
+				hasher.update(format_counter(counter, &mut buf));
+				let level = algs::hash_cash_level(hasher.finalize().as_slice());
+				if level > best.1 {
+					best = (counter, level);
+					if level >= self.target {
+						self.stop.store(true, Ordering::Relaxed);
+						counter += 1;
+						break;
+					}
+				}
+				counter += 1;
+			}
+			self.tried.fetch_add(counter - start, Ordering::Relaxed);
+
+			let mut state = self.state.lock().unwrap();
+			if finished && counter == end {
+				state.in_progress.remove(&start);
+			}
+			if best.1 > state.best_level
+				|| (best.1 == state.best_level
+					&& best.1 >= self.target
+					&& best.0 < state.best_counter)
+			{
+				state.best_counter = best.0;
+				state.best_level = best.1;
+			}
+		}
+	}
+}
+
+/// The average number of counters that need to be tried to reach a level.
+pub fn expected_tries(level: u8) -> f64 { 2f64.powi(i32::from(level)) }
+
+/// Write the decimal representation of `counter` into `buf`.
+fn format_counter(mut counter: u64, buf: &mut [u8; 20]) -> &[u8] {
+	let mut pos = buf.len();
+	loop {
+		pos -= 1;
+		buf[pos] = b'0' + (counter % 10) as u8;
+		counter /= 10;
+		if counter == 0 {
+			break;
+		}
+	}
+	&buf[pos..]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tsproto_types::crypto::EccKeyPrivP256;
+
+	const TEST_PRIV_KEY: &str =
+		"MG8DAgeAAgEgAiEA6rtKxDn/o/Bo50rNtAE5Ph3h2RKLHQ0gbFkvm2yA79kCIQCrfzAZts/\
+		 vHP+3MOetKLjNnpZXt4c6U3UB4gWLKR4H9AIgYTyJofmztcTBjq3KZcDdxu+G4RPVwE5vg8VaN2jbQao=";
+
+	fn test_identity() -> Identity {
+		Identity::new(EccKeyPrivP256::import_str(TEST_PRIV_KEY).unwrap(), 0)
+	}
+
+	#[test]
+	fn format_counters() {
+		let mut buf = [0; 20];
+		for &i in &[0, 7, 10, 2792354, u64::MAX] {
+			assert_eq!(format_counter(i, &mut buf), i.to_string().as_bytes());
+		}
+	}
+
+	#[test]
+	fn same_level_as_single_threaded() {
+		let mut expected = test_identity();
+		expected.upgrade_level(12);
+		let identity = Miner::new(test_identity(), 12).threads(1).chunk_size(100).run();
+		assert_eq!(identity.counter(), expected.counter());
+
+		let identity = Miner::new(test_identity(), 12).threads(4).chunk_size(100).run();
+		assert!(identity.level() >= 12);
+	}
+
+	#[test]
+	fn resume() {
+		let handle = Miner::new(test_identity(), 60).threads(2).chunk_size(1000).start();
+		while handle.progress().tried < 10_000 {
+			thread::sleep(Duration::from_millis(1));
+		}
+		handle.cancel();
+		let identity = handle.wait();
+		let checked = identity.max_counter();
+		assert!(checked >= 8000, "Checked only {}", checked);
+		assert!(identity.level() > 0);
+
+		let target = identity.level() + 1;
+		let resumed = Miner::new(identity, target).threads(3).chunk_size(10).run();
+		assert!(resumed.counter() >= checked);
+		assert!(resumed.level() >= target);
+	}
+}
@@ -5,6 +5,7 @@ use std::path::Path;
 
 mod book_parser;
 mod book_to_messages_parser;
+mod diff;
 mod events;
 mod message_parser;
 mod messages_to_book_parser;
@@ -12,6 +13,7 @@ mod properties;
 
 use crate::book_parser::BookDeclarations;
 use crate::book_to_messages_parser::BookToMessagesDeclarations;
+use crate::diff::Diff;
 use crate::events::EventDeclarations;
 use crate::message_parser::MessageDeclarations;
 use crate::messages_to_book_parser::MessagesToBookDeclarations;
@@ -49,4 +51,8 @@ fn main() {
 	// Properties
 	let mut structs = File::create(path.join("properties.rs")).unwrap();
 	write!(&mut structs, "{}", Properties::default()).unwrap();
+
+	// Diff
+	let mut structs = File::create(path.join("diff.rs")).unwrap();
+	write!(&mut structs, "{}", Diff::default()).unwrap();
 }
@@ -0,0 +1,30 @@
+//! Compare two connection states and create the events for the changes.
+use std::default::Default;
+use std::fmt::Write;
+
+use heck::*;
+use t4rust_derive::Template;
+use tsproto_structs::book::*;
+use tsproto_structs::embrace;
+
+#[derive(Template)]
+#[TemplatePath = "build/Diff.tt"]
+#[derive(Debug)]
+pub struct Diff<'a>(&'a BookDeclarations);
+
+impl Default for Diff<'static> {
+	fn default() -> Self { Diff(&DATA) }
+}
+
+/// The arguments for a `PropertyId` of a struct, taken from the `new` value of
+/// the struct which contains the ids.
+fn get_ids(ids: &[Id]) -> String {
+	let mut res = String::new();
+	for id in ids {
+		if !res.is_empty() {
+			res.push_str(", ");
+		}
+		let _ = write!(res, "new.{}", id.prop.to_snake_case());
+	}
+	res
+}
@@ -0,0 +1,418 @@
+//! The channels and clients of a server, sorted like in the TeamSpeak client.
+//!
+//! The `order` of a channel is the id of the channel above it, or `0` if it is
+//! the first channel of its parent. Clients inside a channel are sorted by
+//! descending talk power and then by name.
+//!
+//! A [`ChannelTree`] is created once from a [`Connection`] and can then be
+//! updated with the events of the connection.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use ts_bookkeeping::data::Connection;
+//! # use ts_bookkeeping::channel_tree::ChannelTree;
+//! # fn f(con: &Connection) {
+//! let tree = ChannelTree::new(con);
+//! for channel in tree.nodes(con) {
+//!     println!("{}", channel.name);
+//! }
+//! # }
+//! ```
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::data::{Channel, Connection};
+use crate::events::{Event, PropertyId};
+use crate::{ChannelId, ClientId};
+
+/// The parent of top-level channels.
+const ROOT: ChannelId = ChannelId(0);
+
+/// The sorted channels and clients of a connection.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChannelTree {
+	/// Sorted child channels, top-level channels are stored for `ChannelId(0)`.
+	children: HashMap<ChannelId, Vec<ChannelId>>,
+	/// Sorted clients in each channel.
+	clients: HashMap<ChannelId, Vec<ClientId>>,
+	parents: HashMap<ChannelId, ChannelId>,
+	client_channels: HashMap<ClientId, ChannelId>,
+}
+
+/// A channel in the tree, created by [`ChannelTree::nodes`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ChannelNode<'a> {
+	pub id: ChannelId,
+	pub name: &'a str,
+	/// Set if this channel should be displayed as a spacer.
+	pub spacer: Option<Spacer<'a>>,
+	pub clients: Vec<ClientNode<'a>>,
+	pub children: Vec<ChannelNode<'a>>,
+}
+
+/// A client in the tree, created by [`ChannelTree::nodes`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ClientNode<'a> {
+	pub id: ClientId,
+	pub name: &'a str,
+	pub talk_power: i32,
+}
+
+/// A top-level channel with a name like `[cspacer]Text` is displayed as a
+/// spacer instead of a normal channel.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct Spacer<'a> {
+	pub alignment: SpacerAlignment,
+	/// The displayed text, without the `[spacer]` prefix.
+	pub text: &'a str,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum SpacerAlignment {
+	/// `[spacer]` or `[lspacer]`
+	Left,
+	/// `[cspacer]`
+	Center,
+	/// `[rspacer]`
+	Right,
+	/// `[*spacer]`, the text is repeated to fill the whole line.
+	Repeat,
+}
+
+impl<'a> Spacer<'a> {
+	/// Parse the name of a channel.
+	///
+	/// Only top-level channels can be spacers, this is not checked here. Text
+	/// between `spacer` and `]` is ignored, it is used to get unique names.
+	pub fn parse(name: &'a str) -> Option<Self> {
+		let rest = name.strip_prefix('[')?;
+		let (alignment, rest) = match rest.as_bytes().first()? {
+			b'c' => (SpacerAlignment::Center, &rest[1..]),
+			b'l' => (SpacerAlignment::Left, &rest[1..]),
+			b'r' => (SpacerAlignment::Right, &rest[1..]),
+			b'*' => (SpacerAlignment::Repeat, &rest[1..]),
+			_ => (SpacerAlignment::Left, rest),
+		};
+		let rest = rest.strip_prefix("spacer")?;
+		let end = rest.find(']')?;
+		Some(Self { alignment, text: &rest[end + 1..] })
+	}
+}
+
+impl ChannelTree {
+	/// Sort all channels and clients of a connection.
+	pub fn new(con: &Connection) -> Self {
+		let mut res = Self::default();
+		let mut siblings: HashMap<ChannelId, Vec<&Channel>> = HashMap::new();
+		for channel in con.channels.values() {
+			siblings.entry(channel.parent).or_default().push(channel);
+			res.parents.insert(channel.id, channel.parent);
+		}
+		for (parent, channels) in siblings {
+			res.children.insert(parent, sort_siblings(&channels));
+		}
+
+		for client in con.clients.values() {
+			res.client_channels.insert(client.id, client.channel);
+			res.clients.entry(client.channel).or_default().push(client.id);
+		}
+		let channels: Vec<_> = res.clients.keys().copied().collect();
+		for channel in channels {
+			res.sort_clients(con, channel);
+		}
+		res
+	}
+
+	/// Update the tree after an event was applied to `con`.
+	///
+	/// Returns `true` if the event changes how the tree is displayed.
+	pub fn handle_event(&mut self, con: &Connection, event: &Event) -> bool {
+		match event {
+			Event::PropertyAdded { id: PropertyId::Channel(id), .. } => {
+				self.insert_channel(con, *id)
+			}
+			Event::PropertyRemoved { id: PropertyId::Channel(id), .. } => self.remove_channel(*id),
+			Event::PropertyChanged {
+				id: PropertyId::ChannelOrder(id) | PropertyId::ChannelParent(id),
+				..
+			} => {
+				self.remove_channel(*id);
+				self.insert_channel(con, *id);
+			}
+			Event::PropertyChanged { id: PropertyId::ChannelName(_), .. } => {}
+
+			Event::PropertyAdded { id: PropertyId::Client(id), .. } => self.insert_client(con, *id),
+			Event::PropertyRemoved { id: PropertyId::Client(id), .. } => self.remove_client(*id),
+			Event::PropertyChanged { id: PropertyId::ClientChannel(id), .. } => {
+				self.remove_client(*id);
+				self.insert_client(con, *id);
+			}
+			Event::PropertyChanged {
+				id: PropertyId::ClientName(id) | PropertyId::ClientTalkPower(id),
+				..
+			} => {
+				if let Some(channel) = self.client_channels.get(id).copied() {
+					self.sort_clients(con, channel);
+				}
+			}
+			_ => return false,
+		}
+		true
+	}
+
+	/// The sorted child channels of a channel, `ChannelId(0)` returns the
+	/// top-level channels.
+	pub fn children(&self, channel: ChannelId) -> &[ChannelId] {
+		self.children.get(&channel).map(|c| c.as_slice()).unwrap_or_default()
+	}
+
+	/// The sorted clients in a channel.
+	pub fn clients(&self, channel: ChannelId) -> &[ClientId] {
+		self.clients.get(&channel).map(|c| c.as_slice()).unwrap_or_default()
+	}
+
+	/// All channels in the order they are displayed, with their depth.
+	pub fn flatten(&self) -> Vec<(ChannelId, usize)> {
+		let mut res = Vec::new();
+		let mut stack: Vec<_> = self.children(ROOT).iter().rev().map(|c| (*c, 0)).collect();
+		while let Some((channel, depth)) = stack.pop() {
+			res.push((channel, depth));
+			stack.extend(self.children(channel).iter().rev().map(|c| (*c, depth + 1)));
+		}
+		res
+	}
+
+	/// Create the nested tree of channels and clients, e.g. to serialize it.
+	///
+	/// `con` has to be the connection that this tree tracks.
+	pub fn nodes<'a>(&self, con: &'a Connection) -> Vec<ChannelNode<'a>> {
+		self.channel_nodes(con, ROOT)
+	}
+
+	fn channel_nodes<'a>(&self, con: &'a Connection, parent: ChannelId) -> Vec<ChannelNode<'a>> {
+		self.children(parent)
+			.iter()
+			.filter_map(|id| con.channels.get(id))
+			.map(|channel| ChannelNode {
+				id: channel.id,
+				name: &channel.name,
+				spacer: if parent == ROOT { Spacer::parse(&channel.name) } else { None },
+				clients: self
+					.clients(channel.id)
+					.iter()
+					.filter_map(|id| con.clients.get(id))
+					.map(|client| ClientNode {
+						id: client.id,
+						name: &client.name,
+						talk_power: client.talk_power,
+					})
+					.collect(),
+				children: self.channel_nodes(con, channel.id),
+			})
+			.collect()
+	}
+
+	fn insert_channel(&mut self, con: &Connection, id: ChannelId) {
+		if let Some(channel) = con.channels.get(&id) {
+			self.parents.insert(id, channel.parent);
+			self.sort_channels(con, channel.parent);
+		}
+	}
+
+	/// Sort the children of a channel again.
+	///
+	/// The server sends the changed `order` of the following channel before a
+	/// new channel is added, so a single channel cannot be inserted at its
+	/// position.
+	fn sort_channels(&mut self, con: &Connection, parent: ChannelId) {
+		let siblings: Vec<_> = con
+			.channels
+			.values()
+			.filter(|c| self.parents.get(&c.id) == Some(&parent))
+			.collect();
+		self.children.insert(parent, sort_siblings(&siblings));
+	}
+
+	/// Remove a channel from its parent, its children and clients are kept.
+	fn remove_channel(&mut self, id: ChannelId) {
+		if let Some(parent) = self.parents.remove(&id) {
+			if let Some(siblings) = self.children.get_mut(&parent) {
+				siblings.retain(|c| *c != id);
+			}
+		}
+		if !self.parents.values().any(|p| *p == id) {
+			self.children.remove(&id);
+		}
+	}
+
+	fn insert_client(&mut self, con: &Connection, id: ClientId) {
+		if let Some(client) = con.clients.get(&id) {
+			self.client_channels.insert(id, client.channel);
+			self.clients.entry(client.channel).or_default().push(id);
+			self.sort_clients(con, client.channel);
+		}
+	}
+
+	fn remove_client(&mut self, id: ClientId) {
+		if let Some(channel) = self.client_channels.remove(&id) {
+			if let Some(clients) = self.clients.get_mut(&channel) {
+				clients.retain(|c| *c != id);
+			}
+		}
+	}
+
+	fn sort_clients(&mut self, con: &Connection, channel: ChannelId) {
+		if let Some(clients) = self.clients.get_mut(&channel) {
+			clients.sort_by_cached_key(|id| {
+				let client = con.clients.get(id);
+				(
+					client.is_none(),
+					Reverse(client.map(|c| c.talk_power).unwrap_or_default()),
+					client.map(|c| c.name.to_lowercase()).unwrap_or_default(),
+					id.0,
+				)
+			});
+		}
+	}
+}
+
+/// Resolve the linked list of `order`s for channels with the same parent.
+///
+/// Channels with a missing or cyclic `order` are sorted to the end.
+fn sort_siblings(channels: &[&Channel]) -> Vec<ChannelId> {
+	let mut followers: HashMap<ChannelId, Vec<ChannelId>> = HashMap::new();
+	for channel in channels {
+		followers.entry(channel.order).or_default().push(channel.id);
+	}
+	// Sort descending, so that popping from the stack returns the lowest id
+	for f in followers.values_mut() {
+		f.sort_by_key(|c| Reverse(c.0));
+	}
+	let mut rest: Vec<_> = channels.iter().map(|c| c.id).collect();
+	rest.sort_by_key(|c| Reverse(c.0));
+
+	let mut res = Vec::with_capacity(channels.len());
+	let mut visited = HashSet::new();
+	let mut stack = followers.get(&ROOT).cloned().unwrap_or_default();
+	loop {
+		while let Some(channel) = stack.pop() {
+			if visited.insert(channel) {
+				res.push(channel);
+				stack.extend(followers.get(&channel).into_iter().flatten());
+			}
+		}
+		match rest.pop() {
+			Some(channel) => stack.push(channel),
+			None => break,
+		}
+	}
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::{channel_list, create_connection, enter_view, handle};
+
+	fn apply(tree: &mut ChannelTree, con: &mut Connection, msg: &str) {
+		for event in handle(con, msg) {
+			tree.handle_event(con, &event);
+		}
+	}
+
+	fn ids(tree: &ChannelTree) -> Vec<(u64, usize)> {
+		tree.flatten().into_iter().map(|(c, d)| (c.0, d)).collect()
+	}
+
+	fn client_ids(tree: &ChannelTree, channel: u64) -> Vec<u16> {
+		tree.clients(ChannelId(channel)).iter().map(|c| c.0).collect()
+	}
+
+	#[test]
+	fn sort_channels_and_clients() {
+		let mut con = create_connection();
+		// Channels are not sent in order
+		for (id, parent, order, name) in [
+			(3, 0, 1, "Third"),
+			(1, 0, 0, "[cspacer]First"),
+			(5, 1, 4, "Sub2"),
+			(4, 1, 0, "Sub1"),
+			(2, 0, 3, "Last"),
+		] {
+			handle(&mut con, &channel_list(id, parent, order, name));
+		}
+		handle(&mut con, &enter_view(7, 4, "bob", 10));
+		handle(&mut con, &enter_view(8, 4, "Alice", 10));
+		handle(&mut con, &enter_view(9, 4, "Zed", 50));
+
+		let mut tree = ChannelTree::new(&con);
+		assert_eq!(ids(&tree), [(1, 0), (4, 1), (5, 1), (3, 0), (2, 0)]);
+		assert_eq!(client_ids(&tree, 4), [9, 8, 7]);
+
+		let nodes = tree.nodes(&con);
+		assert_eq!(
+			nodes[0].spacer,
+			Some(Spacer { alignment: SpacerAlignment::Center, text: "First" })
+		);
+		assert_eq!(nodes[0].children[0].clients[0].name, "Zed");
+		assert_eq!(nodes[0].children[0].spacer, None);
+
+		// Create a channel below the first one and move a channel to the top
+		apply(
+			&mut tree,
+			&mut con,
+			"notifychannelcreated cid=6 invokerid=7 invokername=bob channel_order=1 \
+			 channel_name=New cpid=0",
+		);
+		apply(
+			&mut tree,
+			&mut con,
+			"notifychannelmoved order=0 cid=2 invokerid=7 invokername=bob reasonid=0 cpid=0",
+		);
+		assert_eq!(ids(&tree), [(2, 0), (1, 0), (4, 1), (5, 1), (6, 0), (3, 0)]);
+
+		// Move clients around
+		apply(&mut tree, &mut con, "notifyclientupdated clid=7 client_talk_power=60");
+		apply(&mut tree, &mut con, "notifyclientmoved clid=8 reasonid=0 ctid=5");
+		assert_eq!(client_ids(&tree, 4), [7, 9]);
+		assert_eq!(client_ids(&tree, 5), [8]);
+
+		apply(&mut tree, &mut con, "notifyclientleftview clid=9 reasonid=8 cfid=4 ctid=0");
+		assert_eq!(client_ids(&tree, 4), [7]);
+	}
+
+	#[test]
+	fn create_between_channels() {
+		let mut con = create_connection();
+		for (id, order, name) in [(1, 0, "One"), (2, 1, "Two"), (3, 2, "Three")] {
+			handle(&mut con, &channel_list(id, 0, order, name));
+		}
+		let mut tree = ChannelTree::new(&con);
+
+		// The server changes the order of channel 2 before adding channel 4
+		apply(
+			&mut tree,
+			&mut con,
+			"notifychannelcreated cid=4 invokerid=7 invokername=bob channel_order=1 \
+			 channel_name=Four cpid=0",
+		);
+		assert_eq!(ids(&tree), [(1, 0), (4, 0), (2, 0), (3, 0)]);
+		assert_eq!(ids(&tree), ids(&ChannelTree::new(&con)));
+	}
+
+	#[test]
+	fn parse_spacer() {
+		let s = |alignment, text| Some(Spacer { alignment, text });
+		assert_eq!(Spacer::parse("[spacer]Text"), s(SpacerAlignment::Left, "Text"));
+		assert_eq!(Spacer::parse("[cspacer0]Mid"), s(SpacerAlignment::Center, "Mid"));
+		assert_eq!(Spacer::parse("[rspacer]"), s(SpacerAlignment::Right, ""));
+		assert_eq!(Spacer::parse("[*spacer1]-"), s(SpacerAlignment::Repeat, "-"));
+		assert_eq!(Spacer::parse("[xspacer]a"), None);
+		assert_eq!(Spacer::parse("[spacer"), None);
+		assert_eq!(Spacer::parse("Lobby"), None);
+	}
+}
@@ -7,8 +7,12 @@ use serde::{Deserialize, Serialize};
 use time::{Duration, OffsetDateTime};
 use tsproto_packets::packets::OutCommand;
 use tsproto_types::crypto::EccKeyPubP256;
+use tsproto_types::badges::{Badge, Badges};
 use tsproto_types::*;
 
+use self::exts::{
+	M2BChannelEditExt, M2BClientEditExt, M2BClientKickExt, M2BClientMoveExt, M2BClientUpdateExt,
+};
 use crate::events::{Event, ExtraInfo, PropertyId, PropertyValue, PropertyValueRef};
 use crate::messages::s2c::InMessage;
 use crate::messages::{c2s, s2c};
@@ -17,6 +21,7 @@ use crate::{Error, MessageTarget, Result};
 include!(concat!(env!("OUT_DIR"), "/m2bdecls.rs"));
 include!(concat!(env!("OUT_DIR"), "/structs.rs"));
 include!(concat!(env!("OUT_DIR"), "/properties.rs"));
+include!(concat!(env!("OUT_DIR"), "/diff.rs"));
 
 pub mod exts {
 	use super::*;
@@ -37,6 +42,25 @@ macro_rules! max_clients {
 	}};
 }
 
+macro_rules! max_family_clients {
+	($msg:ident) => {{
+		if $msg.is_max_family_clients_unlimited.unwrap_or_default() {
+			Some(MaxClients::Unlimited)
+		} else if $msg.inherits_max_family_clients.unwrap_or_default() {
+			Some(MaxClients::Inherited)
+		} else if $msg
+			.max_family_clients
+			.map(|i| i >= 0 && i <= u16::MAX as i32)
+			.unwrap_or_default()
+		{
+			Some(MaxClients::Limited($msg.max_family_clients.unwrap() as u16))
+		} else {
+			// Max clients is less than zero or too high so ignore it
+			None
+		}
+	}};
+}
+
 macro_rules! copy_attrs {
 	($from:ident, $to:ident; $($attr:ident),* $(,)*; $($extra:ident: $ex:expr),* $(,)*) => {
 		$to {
@@ -332,17 +356,7 @@ impl Connection {
 		&self, msg: &s2c::InChannelCreatedPart, _: &mut Vec<Event>,
 	) -> Result<(Option<MaxClients>, Option<MaxClients>)> {
 		let ch = max_clients!(msg);
-		let ch_fam = if msg.is_max_family_clients_unlimited.unwrap_or_default() {
-			Some(MaxClients::Unlimited)
-		} else if msg.inherits_max_family_clients.unwrap_or_default() {
-			Some(MaxClients::Inherited)
-		} else if msg.max_family_clients.map(|i| i >= 0 && i <= u16::MAX as i32).unwrap_or_default()
-		{
-			Some(MaxClients::Limited(msg.max_family_clients.unwrap() as u16))
-		} else {
-			// Max clients is less than zero or too high so ignore it
-			None
-		};
+		let ch_fam = max_family_clients!(msg);
 		Ok((ch, ch_fam))
 	}
 	fn max_clients_ce_fun(
@@ -360,17 +374,7 @@ impl Connection {
 			});
 			channel.max_clients = Some(ch);
 		}
-		let ch_fam = if msg.is_max_family_clients_unlimited.unwrap_or_default() {
-			Some(MaxClients::Unlimited)
-		} else if msg.inherits_max_family_clients.unwrap_or_default() {
-			Some(MaxClients::Inherited)
-		} else if msg.max_family_clients.map(|i| i >= 0 && i <= u16::MAX as i32).unwrap_or_default()
-		{
-			Some(MaxClients::Limited(msg.max_family_clients.unwrap() as u16))
-		} else {
-			// Max clients is less than zero or too high so ignore it
-			None
-		};
+		let ch_fam = max_family_clients!(msg);
 		if let Some(ch_fam) = ch_fam {
 			events.push(Event::PropertyChanged {
 				id: PropertyId::ChannelMaxFamilyClients(channel_id),
@@ -742,6 +746,69 @@ impl Client {
 			message: message.into(),
 		}))
 	}
+
+	/// The parsed badges of this client.
+	pub fn get_badges(&self) -> Vec<Badge> { Badges::parse(&self.badges).badges }
+
+	/// If this client uses the Overwolf overlay.
+	pub fn is_overwolf(&self) -> bool { Badges::parse(&self.badges).overwolf }
+
+	/// Move this client into another channel.
+	pub fn move_to(&self, channel: ChannelId, password: Option<&str>) -> OutCommand {
+		let mut part = self.client_move(channel);
+		if let Some(password) = password {
+			part = part.set_password(password);
+		}
+		c2s::OutClientMoveMessage::new(&mut iter::once(part))
+	}
+
+	/// Kick this client into the default channel.
+	pub fn kick_from_channel(&self, reason: Option<&str>) -> OutCommand {
+		self.kick_with_reason(Reason::KickChannel, reason)
+	}
+
+	pub fn kick_from_server(&self, reason: Option<&str>) -> OutCommand {
+		self.kick_with_reason(Reason::KickServer, reason)
+	}
+
+	fn kick_with_reason(&self, kind: Reason, reason: Option<&str>) -> OutCommand {
+		let mut part = self.kick(kind);
+		if let Some(reason) = reason {
+			part = part.set_reason_message(reason);
+		}
+		c2s::OutClientKickMessage::new(&mut iter::once(part))
+	}
+
+	/// Ban this client from the server.
+	///
+	/// The ban is permanent if no `duration` is given.
+	pub fn ban(&self, duration: Option<Duration>, reason: Option<&str>) -> OutCommand {
+		c2s::OutBanClientMessage::new(&mut iter::once(c2s::OutBanClientPart {
+			client_id: self.id,
+			time: duration,
+			ban_reason: reason.map(Into::into),
+		}))
+	}
+
+	/// Set the channel group of this client in its current channel.
+	pub fn set_channel_group(&self, group: ChannelGroupId) -> OutCommand {
+		c2s::OutSetClientChannelGroupMessage::new(&mut iter::once(
+			c2s::OutSetClientChannelGroupPart {
+				channel_group: group,
+				channel_id: self.channel,
+				client_db_id: self.database_id,
+			},
+		))
+	}
+
+	pub fn set_description(&self, description: &str) -> OutCommand {
+		c2s::OutClientEditMessage::new(&mut iter::once(self.edit().set_description(description)))
+	}
+
+	/// Grant or revoke talk power in a moderated channel.
+	pub fn set_talk_power(&self, granted: bool) -> OutCommand {
+		c2s::OutClientEditMessage::new(&mut iter::once(self.edit().set_talk_power_granted(granted)))
+	}
 }
 
 impl Channel {
@@ -799,6 +866,39 @@ impl Channel {
 			))
 		}
 	}
+
+	/// Change properties of this channel.
+	///
+	/// See [`ChannelEditOptions`].
+	pub fn edit_options(&self) -> ChannelEditOptions<'_> {
+		ChannelEditOptions { channel: self, part: self.edit() }
+	}
+
+	/// Move this channel below a new parent.
+	///
+	/// The channel is sorted after `order` or at the top if `order` is `None`.
+	/// Returns `None` if the channel is already at this position.
+	pub fn move_to(&self, parent: ChannelId, order: Option<ChannelId>) -> Option<OutCommand> {
+		if parent == self.parent && order.unwrap_or(ChannelId(0)) == self.order {
+			return None;
+		}
+		Some(c2s::OutChannelMoveMessage::new(&mut iter::once(c2s::OutChannelMovePart {
+			channel_id: self.id,
+			parent_id: parent,
+			order,
+		})))
+	}
+
+	/// Delete this channel.
+	///
+	/// If `force` is `false`, the server refuses to delete a channel which
+	/// still contains clients.
+	pub fn delete(&self, force: bool) -> OutCommand {
+		c2s::OutChannelDeleteMessage::new(&mut iter::once(c2s::OutChannelDeletePart {
+			channel_id: self.id,
+			force,
+		}))
+	}
 }
 
 /// The `ChannelOptions` are used to set initial properties of a new channel.
@@ -923,6 +1023,203 @@ impl<'a> ChannelOptions<'a> {
 	}
 }
 
+/// The `ChannelEditOptions` are used to change properties of an existing
+/// channel.
+///
+/// They are created with [`Channel::edit_options`]. Properties which are set
+/// to their current value are not sent to the server. Passwords are not known
+/// locally, so they are always sent.
+#[derive(Clone, Debug)]
+pub struct ChannelEditOptions<'a> {
+	channel: &'a Channel,
+	part: c2s::OutChannelEditPart<'a>,
+}
+
+impl<'a> ChannelEditOptions<'a> {
+	pub fn name(mut self, name: &'a str) -> Self {
+		self.part.name = None;
+		if self.channel.name != name {
+			self.part = self.part.set_name(name);
+		}
+		self
+	}
+
+	pub fn topic(mut self, topic: &'a str) -> Self {
+		self.part.topic = None;
+		if self.channel.topic.as_deref() != Some(topic) {
+			self.part = self.part.set_topic(topic);
+		}
+		self
+	}
+
+	/// The description is only compared if it was fetched before.
+	pub fn description(mut self, description: &'a str) -> Self {
+		self.part.description = None;
+		let old = self.channel.optional_data.as_ref().map(|d| d.description.as_str());
+		if old != Some(description) {
+			self.part = self.part.set_description(description);
+		}
+		self
+	}
+
+	/// Set or remove (`None`) the password.
+	pub fn password(mut self, password: Option<&'a str>) -> Self {
+		self.part.has_password = None;
+		self.part.password = None;
+		if password.is_some() || self.channel.has_password != Some(false) {
+			self.part = self.part.set_password(password);
+		}
+		self
+	}
+
+	pub fn codec(mut self, codec: Codec) -> Self {
+		self.part.codec = None;
+		if self.channel.codec != codec {
+			self.part = self.part.set_codec(codec);
+		}
+		self
+	}
+
+	pub fn codec_quality(mut self, codec_quality: u8) -> Self {
+		self.part.codec_quality = None;
+		if self.channel.codec_quality != Some(codec_quality) {
+			self.part = self.part.set_codec_quality(codec_quality);
+		}
+		self
+	}
+
+	pub fn codec_latency_factor(mut self, codec_latency_factor: i32) -> Self {
+		self.part.codec_latency_factor = None;
+		if self.channel.codec_latency_factor != Some(codec_latency_factor) {
+			self.part = self.part.set_codec_latency_factor(codec_latency_factor);
+		}
+		self
+	}
+
+	pub fn is_unencrypted(mut self, is_unencrypted: bool) -> Self {
+		self.part.is_unencrypted = None;
+		if self.channel.is_unencrypted != Some(is_unencrypted) {
+			self.part = self.part.set_is_unencrypted(is_unencrypted);
+		}
+		self
+	}
+
+	pub fn delete_delay(mut self, delete_delay: Duration) -> Self {
+		self.part.delete_delay = None;
+		if self.channel.delete_delay != Some(delete_delay) {
+			self.part = self.part.set_delete_delay(delete_delay);
+		}
+		self
+	}
+
+	/// Make this the default channel.
+	pub fn default(mut self) -> Self {
+		self.part.is_default = None;
+		if self.channel.is_default != Some(true) {
+			self.part = self.part.set_is_default(true);
+		}
+		self
+	}
+
+	pub fn max_clients(mut self, max_clients: MaxClients) -> Self {
+		self.part.max_clients = None;
+		self.part.is_max_clients_unlimited = None;
+		if self.channel.max_clients != Some(max_clients) {
+			self.part = self.part.set_max_clients(max_clients);
+		}
+		self
+	}
+
+	pub fn max_family_clients(mut self, max_family_clients: MaxClients) -> Self {
+		self.part.max_family_clients = None;
+		self.part.is_max_family_clients_unlimited = None;
+		self.part.inherits_max_family_clients = None;
+		if self.channel.max_family_clients != Some(max_family_clients) {
+			self.part = self.part.set_max_family_clients(max_family_clients);
+		}
+		self
+	}
+
+	pub fn channel_type(mut self, channel_type: ChannelType) -> Self {
+		self.part.is_permanent = None;
+		self.part.is_semi_permanent = None;
+		if self.channel.channel_type != channel_type {
+			self.part = self.part.set_channel_type(channel_type);
+		}
+		self
+	}
+
+	pub fn needed_talk_power(mut self, needed_talk_power: i32) -> Self {
+		self.part.needed_talk_power = None;
+		if self.channel.needed_talk_power != Some(needed_talk_power) {
+			self.part = self.part.set_needed_talk_power(needed_talk_power);
+		}
+		self
+	}
+
+	/// The previous order
+	pub fn order(mut self, order: ChannelId) -> Self {
+		self.part.order = None;
+		if self.channel.order != order {
+			self.part = self.part.set_order(order);
+		}
+		self
+	}
+
+	pub fn phonetic_name(mut self, phonetic_name: &'a str) -> Self {
+		self.part.phonetic_name = None;
+		if self.channel.phonetic_name.as_deref() != Some(phonetic_name) {
+			self.part = self.part.set_phonetic_name(phonetic_name);
+		}
+		self
+	}
+
+	/// If any property differs from the current state of the channel.
+	pub fn has_changes(&self) -> bool {
+		let p = &self.part;
+		p.order.is_some()
+			|| p.name.is_some()
+			|| p.topic.is_some()
+			|| p.is_default.is_some()
+			|| p.has_password.is_some()
+			|| p.is_permanent.is_some()
+			|| p.codec.is_some()
+			|| p.codec_quality.is_some()
+			|| p.needed_talk_power.is_some()
+			|| p.max_clients.is_some()
+			|| p.max_family_clients.is_some()
+			|| p.codec_latency_factor.is_some()
+			|| p.is_unencrypted.is_some()
+			|| p.delete_delay.is_some()
+			|| p.phonetic_name.is_some()
+			|| p.description.is_some()
+	}
+
+	/// Create the `channeledit` command.
+	///
+	/// Returns `None` if nothing changed.
+	pub fn build(self) -> Option<OutCommand> {
+		if self.has_changes() {
+			Some(c2s::OutChannelEditMessage::new(&mut iter::once(self.part)))
+		} else {
+			None
+		}
+	}
+}
+
+/// A channel change which was sent to the server but is not confirmed yet.
+///
+/// It is returned when applying the change optimistically with
+/// [`Connection::apply_channel_edit`] or [`Connection::apply_channel_move`] and
+/// can be reverted with [`Connection::revert_channel_edit`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingChannelEdit {
+	/// The channel before the change.
+	pub old: Channel,
+	/// The channel after the change.
+	pub new: Channel,
+}
+
 impl Server {
 	pub fn add_channel(&self, options: ChannelOptions) -> OutCommand {
 		let inherits_max_family_clients = options
@@ -1048,4 +1345,272 @@ impl Connection {
 			reason_message: options.message.map(Into::into),
 		}))
 	}
+
+	/// Request talk power for our own client with a message or withdraw the
+	/// request with `None`.
+	pub fn request_talk_power(&self, message: Option<&str>) -> OutCommand {
+		c2s::OutClientUpdateMessage::new(&mut iter::once(
+			self.client_update().set_talk_power_request(message),
+		))
+	}
+
+	/// Apply a `channeledit` command before the server confirms it.
+	///
+	/// Returns the created events and the change, which can be reverted if the
+	/// server rejects the command.
+	pub fn apply_channel_edit(
+		&mut self, msg: &c2s::InChannelEditPart,
+	) -> Result<(Vec<Event>, PendingChannelEdit)> {
+		let mut events = Vec::new();
+		let old = self.get_channel(msg.channel_id)?.clone();
+		let mut new = old.clone();
+		if let Some(name) = &msg.name {
+			new.name = name.clone();
+		}
+		if msg.topic.is_some() {
+			new.topic = msg.topic.clone();
+		}
+		if let Some(codec) = msg.codec {
+			new.codec = codec;
+		}
+		if msg.is_permanent.is_some() || msg.is_semi_permanent.is_some() {
+			new.channel_type = Self::channel_flags_to_type(msg.is_permanent, msg.is_semi_permanent);
+		}
+		if let Some(max_clients) = max_clients!(msg) {
+			new.max_clients = Some(max_clients);
+		}
+		if let Some(max_family_clients) = max_family_clients!(msg) {
+			new.max_family_clients = Some(max_family_clients);
+		}
+		if msg.phonetic_name.is_some() {
+			new.phonetic_name = msg.phonetic_name.clone();
+		}
+		if let Some(description) = &msg.description {
+			new.optional_data = Some(OptionalChannelData {
+				channel_id: msg.channel_id,
+				description: description.clone(),
+			});
+		}
+		macro_rules! set_optional {
+			($($attr:ident),*) => {
+				$(if msg.$attr.is_some() {
+					new.$attr = msg.$attr;
+				})*
+			};
+		}
+		set_optional!(
+			codec_quality,
+			is_default,
+			has_password,
+			codec_latency_factor,
+			is_unencrypted,
+			delete_delay,
+			needed_talk_power
+		);
+
+		diff_channel(&old, &new, &mut events);
+		self.channels.insert(new.id, new);
+		self.channel_order_move_fun(msg.channel_id, msg.order, None, &mut events)?;
+		let new = self.get_channel(msg.channel_id)?.clone();
+		Ok((events, PendingChannelEdit { old, new }))
+	}
+
+	/// Apply a `channelmove` command before the server confirms it.
+	///
+	/// Returns the created events and the change, which can be reverted if the
+	/// server rejects the command.
+	pub fn apply_channel_move(
+		&mut self, msg: &c2s::InChannelMovePart,
+	) -> Result<(Vec<Event>, PendingChannelEdit)> {
+		let mut events = Vec::new();
+		let old = self.get_channel(msg.channel_id)?.clone();
+		let order = msg.order.unwrap_or(ChannelId(0));
+		self.move_channel(msg.channel_id, msg.parent_id, order, &mut events)?;
+		let new = self.get_channel(msg.channel_id)?.clone();
+		Ok((events, PendingChannelEdit { old, new }))
+	}
+
+	/// Undo a change that was applied before it was confirmed.
+	///
+	/// Properties which were changed again in the meantime are kept.
+	pub fn revert_channel_edit(&mut self, edit: &PendingChannelEdit) -> Vec<Event> {
+		let mut events = Vec::new();
+		let cur = if let Some(c) = self.channels.get(&edit.old.id) {
+			c.clone()
+		} else {
+			return events;
+		};
+		let mut reverted = cur.clone();
+		macro_rules! revert {
+			($($attr:ident),*) => {
+				$(if cur.$attr == edit.new.$attr && edit.old.$attr != edit.new.$attr {
+					reverted.$attr = edit.old.$attr.clone();
+				})*
+			};
+		}
+		revert!(
+			name,
+			topic,
+			codec,
+			codec_quality,
+			max_clients,
+			max_family_clients,
+			channel_type,
+			is_default,
+			has_password,
+			codec_latency_factor,
+			is_unencrypted,
+			delete_delay,
+			needed_talk_power,
+			phonetic_name,
+			optional_data
+		);
+		diff_channel(&cur, &reverted, &mut events);
+		self.channels.insert(reverted.id, reverted);
+
+		let moved = edit.old.parent != edit.new.parent || edit.old.order != edit.new.order;
+		if moved && cur.parent == edit.new.parent && cur.order == edit.new.order {
+			// The channel still exists, so moving it cannot fail
+			let _ = self.move_channel(cur.id, edit.old.parent, edit.old.order, &mut events);
+		}
+		events
+	}
+
+	fn move_channel(
+		&mut self, channel_id: ChannelId, parent: ChannelId, order: ChannelId,
+		events: &mut Vec<Event>,
+	) -> Result<()> {
+		self.channel_order_move_fun(channel_id, Some(order), Some(parent), events)?;
+		let channel = self.get_mut_channel(channel_id)?;
+		if channel.parent != parent {
+			events.push(Event::PropertyChanged {
+				id: PropertyId::ChannelParent(channel_id),
+				old: PropertyValue::ChannelId(channel.parent),
+				invoker: None,
+				extra: ExtraInfo { reason: None },
+			});
+			channel.parent = parent;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util;
+
+	fn create_connection() -> Connection {
+		let mut con = test_util::create_connection();
+		test_util::handle(&mut con, &test_util::channel_list(1, 0, 0, "Lobby"));
+		test_util::handle(&mut con, &test_util::channel_list(2, 0, 1, "Hall"));
+		con
+	}
+
+	fn content(cmd: &OutCommand) -> &str { std::str::from_utf8(cmd.0.content()).unwrap() }
+
+	fn parse_out(cmd: &OutCommand) -> c2s::InMessage {
+		c2s::InMessage::new(&cmd.0.header(), cmd.0.content()).unwrap()
+	}
+
+	#[test]
+	fn edit_only_changed() {
+		let con = create_connection();
+		let channel = &con.channels[&ChannelId(1)];
+		let edit = channel.edit_options().name("Lobby").codec_quality(7).password(None);
+		assert!(edit.build().is_none());
+
+		let cmd = channel
+			.edit_options()
+			.name("Hall")
+			.name("Lobby")
+			.codec_quality(10)
+			.max_clients(MaxClients::Unlimited)
+			.max_family_clients(MaxClients::Limited(5))
+			.build()
+			.unwrap();
+		assert_eq!(
+			content(&cmd),
+			"channeledit cid=1 channel_codec_quality=10 channel_maxfamilyclients=5 \
+			 channel_flag_maxfamilyclients_unlimited=0 channel_flag_maxfamilyclients_inherited=0"
+		);
+
+		assert!(channel.move_to(ChannelId(0), None).is_none());
+		let cmd = channel.move_to(ChannelId(2), None).unwrap();
+		assert_eq!(content(&cmd), "channelmove cid=1 cpid=2");
+		assert_eq!(content(&channel.delete(true)), "channeldelete cid=1 force=1");
+	}
+
+	#[test]
+	fn client_moderation() {
+		let mut con = create_connection();
+		test_util::handle(&mut con, &test_util::enter_view(3, 1, "Bob", 0));
+		let client = &con.clients[&ClientId(3)];
+
+		assert_eq!(content(&client.move_to(ChannelId(2), None)), "clientmove clid=3 cid=2");
+		assert_eq!(
+			content(&client.kick_from_server(Some("Bye"))),
+			"clientkick clid=3 reasonid=5 reasonmsg=Bye"
+		);
+		assert_eq!(content(&client.kick_from_channel(None)), "clientkick clid=3 reasonid=4");
+		assert_eq!(
+			content(&client.ban(Some(Duration::minutes(2)), Some("Spam"))),
+			"banclient clid=3 time=120 banreason=Spam"
+		);
+		assert_eq!(
+			content(&client.set_channel_group(ChannelGroupId(5))),
+			"setclientchannelgroup cgid=5 cid=1 cldbid=3"
+		);
+		assert_eq!(content(&client.set_talk_power(true)), "clientedit clid=3 client_is_talker=1");
+		assert_eq!(
+			content(&con.request_talk_power(Some("Please"))),
+			"clientupdate client_talk_request=1 client_talk_request_msg=Please"
+		);
+		assert_eq!(
+			content(&con.request_talk_power(None)),
+			"clientupdate client_talk_request=0 client_talk_request_msg"
+		);
+	}
+
+	#[test]
+	fn apply_and_revert_edit() {
+		let mut con = create_connection();
+		let original = con.clone();
+		let cmd = con.channels[&ChannelId(1)].edit_options().name("Hall").topic("Hi").build();
+		let edit = match parse_out(&cmd.unwrap()) {
+			c2s::InMessage::ChannelEdit(msg) => msg.iter().next().unwrap().clone(),
+			_ => panic!("Expected channeledit"),
+		};
+
+		let (events, pending) = con.apply_channel_edit(&edit).unwrap();
+		assert_eq!(events.len(), 2, "{:?}", events);
+		assert_eq!(con.channels[&ChannelId(1)].name, "Hall");
+		assert_eq!(pending.new, con.channels[&ChannelId(1)]);
+
+		// A change from someone else is kept
+		test_util::handle(&mut con, "notifychanneledited cid=1 reasonid=10 channel_topic=Hello");
+		let events = con.revert_channel_edit(&pending);
+		assert_eq!(events.len(), 1, "{:?}", events);
+		assert_eq!(con.channels[&ChannelId(1)].name, "Lobby");
+		assert_eq!(con.channels[&ChannelId(1)].topic.as_deref(), Some("Hello"));
+		assert_eq!(con.channels[&ChannelId(2)], original.channels[&ChannelId(2)]);
+	}
+
+	#[test]
+	fn apply_and_revert_move() {
+		let mut con = create_connection();
+		let original = con.clone();
+		let cmd = con.channels[&ChannelId(1)].move_to(ChannelId(2), None).unwrap();
+		let msg = match parse_out(&cmd) {
+			c2s::InMessage::ChannelMove(msg) => msg.iter().next().unwrap().clone(),
+			_ => panic!("Expected channelmove"),
+		};
+
+		let (_, pending) = con.apply_channel_move(&msg).unwrap();
+		assert_eq!(con.channels[&ChannelId(1)].parent, ChannelId(2));
+		assert_eq!(con.channels[&ChannelId(2)].order, ChannelId(0));
+
+		con.revert_channel_edit(&pending);
+		assert_eq!(con, original);
+	}
 }
@@ -10,6 +10,9 @@
 //!
 //! The structs have methods to create packets for various actions. The generated packets can be
 //! sent to a server.
+//!
+//! Two states of a connection can be compared with [`data::Connection::diff`]. With the
+//! `snapshot` feature, the state can be stored as JSON or CBOR.
 
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
@@ -17,12 +20,19 @@ use std::net::{IpAddr, SocketAddr};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+pub mod channel_tree;
 pub mod data;
 pub mod events;
 pub mod messages;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 // Reexports
-pub use tsproto_types::errors::Error as TsError;
+pub use tsproto_types::badges::{Badge, BadgeInfo, Badges};
+pub use tsproto_types::errors::{Error as TsError, ErrorCategory};
 pub use tsproto_types::versions::Version;
 pub use tsproto_types::{
 	ChannelGroupId, ChannelId, ChannelPermissionHint, ChannelType, ClientDbId, ClientId,
@@ -0,0 +1,202 @@
+//! Persist the state of a connection and compare stored states.
+//!
+//! A [`Snapshot`] contains the whole [`Connection`] together with the time it
+//! was taken. It can be stored as JSON, which is easy to inspect, or as the
+//! more compact CBOR.
+//!
+//! Two snapshots can be compared with [`Snapshot::diff`], which returns the
+//! same events that the live connection emits for these changes.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use ts_bookkeeping::data::Connection;
+//! # use ts_bookkeeping::snapshot::Snapshot;
+//! # fn f(state: &Connection) -> ts_bookkeeping::snapshot::Result<()> {
+//! let yesterday = Snapshot::load("yesterday.json")?;
+//! for event in yesterday.diff(&Snapshot::new(state.clone())) {
+//!     println!("{:?}", event);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use crate::data::Connection;
+use crate::events::Event;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+	#[error("Failed to parse CBOR snapshot: {0}")]
+	CborDeserialize(#[source] ciborium::de::Error<io::Error>),
+	#[error("Failed to write CBOR snapshot: {0}")]
+	CborSerialize(#[source] ciborium::ser::Error<io::Error>),
+	#[error("Io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("Failed to read or write JSON snapshot: {0}")]
+	Json(#[source] serde_json::Error),
+}
+
+/// The file format of a snapshot.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Format {
+	Cbor,
+	Json,
+}
+
+/// The state of a connection at a point in time.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot {
+	/// When this snapshot was taken.
+	pub taken: OffsetDateTime,
+	pub connection: Connection,
+}
+
+impl Format {
+	/// Files with a `.cbor` extension are CBOR, everything else is JSON.
+	pub fn from_path(path: &Path) -> Self {
+		if path.extension().map(|e| e.eq_ignore_ascii_case("cbor")).unwrap_or_default() {
+			Format::Cbor
+		} else {
+			Format::Json
+		}
+	}
+}
+
+impl Snapshot {
+	/// Take a snapshot of the current state.
+	pub fn new(connection: Connection) -> Self {
+		Self { taken: OffsetDateTime::now_utc(), connection }
+	}
+
+	/// The events which change the state of this snapshot into the state of
+	/// `newer`.
+	pub fn diff(&self, newer: &Snapshot) -> Vec<Event> { self.connection.diff(&newer.connection) }
+
+	pub fn to_json(&self) -> Result<String> {
+		serde_json::to_string_pretty(self).map_err(Error::Json)
+	}
+
+	pub fn from_json(s: &str) -> Result<Self> { serde_json::from_str(s).map_err(Error::Json) }
+
+	pub fn to_cbor(&self) -> Result<Vec<u8>> {
+		let mut res = Vec::new();
+		self.write(&mut res, Format::Cbor)?;
+		Ok(res)
+	}
+
+	pub fn from_cbor(data: &[u8]) -> Result<Self> { Self::read(data, Format::Cbor) }
+
+	pub fn write<W: Write>(&self, writer: W, format: Format) -> Result<()> {
+		match format {
+			Format::Cbor => ciborium::into_writer(self, writer).map_err(Error::CborSerialize),
+			Format::Json => serde_json::to_writer_pretty(writer, self).map_err(Error::Json),
+		}
+	}
+
+	pub fn read<R: Read>(reader: R, format: Format) -> Result<Self> {
+		match format {
+			Format::Cbor => ciborium::from_reader(reader).map_err(Error::CborDeserialize),
+			Format::Json => serde_json::from_reader(reader).map_err(Error::Json),
+		}
+	}
+
+	/// Store the snapshot in a file, the format is chosen by the extension.
+	///
+	/// See [`Format::from_path`].
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+		let path = path.as_ref();
+		let mut file = io::BufWriter::new(fs::File::create(path)?);
+		self.write(&mut file, Format::from_path(path))?;
+		file.flush()?;
+		Ok(())
+	}
+
+	/// Load a snapshot from a file, the format is chosen by the extension.
+	///
+	/// See [`Format::from_path`].
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+		let path = path.as_ref();
+		let file = io::BufReader::new(fs::File::open(path)?);
+		Self::read(file, Format::from_path(path))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::events::PropertyId;
+	use crate::{test_util, ClientId};
+
+	fn create_connection() -> Connection {
+		let mut con = test_util::create_connection();
+		test_util::handle(&mut con, &test_util::channel_list(1, 0, 0, "Lobby"));
+		test_util::handle(&mut con, &test_util::enter_view(3, 1, "Bob", 0));
+		con
+	}
+
+	/// Remove the parts of events which cannot be restored by a diff.
+	fn strip(events: Vec<Event>) -> Vec<Event> {
+		events
+			.into_iter()
+			.map(|e| match e {
+				Event::PropertyAdded { id, .. } => {
+					Event::PropertyAdded { id, invoker: None, extra: Default::default() }
+				}
+				Event::PropertyChanged { id, old, .. } => {
+					Event::PropertyChanged { id, old, invoker: None, extra: Default::default() }
+				}
+				Event::PropertyRemoved { id, old, .. } => {
+					Event::PropertyRemoved { id, old, invoker: None, extra: Default::default() }
+				}
+				e => e,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn roundtrip() {
+		let snapshot = Snapshot::new(create_connection());
+		assert_eq!(Snapshot::from_json(&snapshot.to_json().unwrap()).unwrap(), snapshot);
+		assert_eq!(Snapshot::from_cbor(&snapshot.to_cbor().unwrap()).unwrap(), snapshot);
+	}
+
+	#[test]
+	fn diff_like_live_events() {
+		let old = Snapshot::new(create_connection());
+		let mut con = old.connection.clone();
+		let mut live = Vec::new();
+		for msg in [
+			"notifyclientupdated clid=3 client_nickname=Alice client_servergroups=6,8".into(),
+			test_util::enter_view(4, 1, "Eve", 0),
+			"notifychanneledited cid=1 reasonid=10 channel_name=Hall".into(),
+		] {
+			live.extend(test_util::handle(&mut con, &msg));
+		}
+		let new = Snapshot::new(con);
+
+		let diff = old.diff(&new);
+		assert_eq!(diff.len(), live.len(), "{:#?}", diff);
+		let live = strip(live);
+		for e in &diff {
+			assert!(live.contains(e), "Missing event {:?}", e);
+		}
+		let added = Event::PropertyAdded {
+			id: PropertyId::Client(ClientId(4)),
+			invoker: None,
+			extra: Default::default(),
+		};
+		assert!(diff.contains(&added));
+		assert!(new.diff(&new).is_empty());
+	}
+}
@@ -0,0 +1,84 @@
+//! Helpers to create a connection state from server messages.
+//!
+//! Enable the `test-util` feature to use them in tests of other crates.
+use tsproto_packets::packets::{Direction, Flags, OutPacket, PacketType};
+use tsproto_types::crypto::EccKeyPrivP256;
+
+use crate::data::Connection;
+use crate::events::Event;
+use crate::messages::s2c::InMessage;
+
+const INIT_SERVER: &str = "initserver virtualserver_name=Server virtualserver_welcomemessage \
+	virtualserver_platform=Linux virtualserver_version=3.11.0 virtualserver_maxclients=32 \
+	virtualserver_created=0 virtualserver_codec_encryption_mode=2 virtualserver_hostmessage \
+	virtualserver_hostmessage_mode=0 virtualserver_default_server_group=8 \
+	virtualserver_default_channel_group=8 virtualserver_hostbanner_url \
+	virtualserver_hostbanner_gfx_url virtualserver_hostbanner_gfx_interval=0 \
+	virtualserver_priority_speaker_dimm_modificator=-18.0000 virtualserver_id=1 \
+	virtualserver_hostbutton_tooltip virtualserver_hostbutton_url \
+	virtualserver_hostbutton_gfx_url virtualserver_name_phonetic virtualserver_ip=0.0.0.0 \
+	virtualserver_ask_for_privilegekey=0 virtualserver_hostbanner_mode=0 \
+	virtualserver_channel_temp_delete_delay_default=0 acn=Bot aclid=2 pv=7 \
+	client_talk_power=75 client_needed_serverquery_view_power=75 virtualserver_icon_id=0";
+
+pub fn parse_msg(msg: &str) -> InMessage {
+	let header = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
+	InMessage::new(&header.header(), msg.as_bytes()).expect(msg)
+}
+
+/// A connection to a server without channels and clients.
+pub fn create_connection() -> Connection {
+	let key = EccKeyPrivP256::create().to_pub();
+	match parse_msg(INIT_SERVER) {
+		InMessage::InitServer(msg) => Connection::new(key, &msg),
+		_ => panic!("Failed to parse initserver"),
+	}
+}
+
+/// Apply a message to the connection and return the created events.
+pub fn handle(con: &mut Connection, msg: &str) -> Vec<Event> {
+	con.handle_command(&parse_msg(msg)).unwrap().0
+}
+
+/// A `channellist` message.
+pub fn channel_list(id: u64, parent: u64, order: u64, name: &str) -> String {
+	format!(
+		"channellist cid={} cpid={} channel_name={} channel_topic channel_codec=4 \
+		 channel_codec_quality=7 channel_maxclients=-1 channel_maxfamilyclients=-1 \
+		 channel_order={} channel_flag_permanent=1 channel_flag_semi_permanent=0 \
+		 channel_flag_default=0 channel_flag_password=0 channel_codec_latency_factor=1 \
+		 channel_codec_is_unencrypted=1 channel_delete_delay=0 \
+		 channel_flag_maxclients_unlimited=1 channel_flag_maxfamilyclients_unlimited=0 \
+		 channel_flag_maxfamilyclients_inherited=1 channel_needed_talk_power=0 \
+		 channel_forced_silence=0 channel_name_phonetic channel_icon_id=0 channel_flag_private=0",
+		id, parent, name, order
+	)
+}
+
+/// A `notifycliententerview` message.
+pub fn enter_view(id: u16, channel: u64, name: &str, talk_power: i32) -> String {
+	format!(
+		"notifycliententerview reasonid=0 ctid={1} clid={0} client_database_id={0} \
+		 client_nickname={2} client_type=0 cfid=0 client_unique_identifier=uid{0}= \
+		 client_flag_avatar client_description client_icon_id=0 client_input_muted=0 \
+		 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 \
+		 client_output_hardware=1 client_meta_data client_is_recording=0 \
+		 client_channel_group_id=8 client_channel_group_inherited_channel_id={1} \
+		 client_servergroups=8 client_away=0 client_away_message client_talk_power={3} \
+		 client_talk_request=0 client_talk_request_msg client_is_talker=0 \
+		 client_is_priority_speaker=0 client_unread_messages=0 client_nickname_phonetic \
+		 client_needed_serverquery_view_power=0 client_is_channel_commander=0 \
+		 client_country client_badges client_myteamspeak_id client_integrations",
+		id, channel, name, talk_power
+	)
+}
+
+/// A connection with the channels `1` (Lobby) and `2` (Hall) and our own
+/// client `2` (Bot) in channel `1`.
+pub fn create_populated_connection() -> Connection {
+	let mut con = create_connection();
+	handle(&mut con, &channel_list(1, 0, 0, "Lobby"));
+	handle(&mut con, &channel_list(2, 0, 1, "Hall"));
+	handle(&mut con, &enter_view(2, 1, "Bot", 0));
+	con
+}
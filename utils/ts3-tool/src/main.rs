@@ -0,0 +1,403 @@
+//! A command line tool to debug TeamSpeak identities, licenses and packets.
+
+use std::fmt::Write as _;
+use std::io::Cursor;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, format_err, Context, Result};
+use base64::prelude::*;
+use clap::{Parser, Subcommand};
+use tsproto::algorithms as algs;
+use tsproto::license::Licenses;
+use tsproto::miner::Miner;
+use tsproto::utils;
+use tsproto::Identity;
+use tsproto_packets::commands::{CommandItem, CommandParser};
+use tsproto_packets::packets::{AudioData, Direction, Flags, InPacket, PacketType};
+use tsproto_packets::HexSlice;
+use tsproto_types::crypto::{self, EccKeyPubEd25519};
+
+/// The maximum decompressed size of a packet, same as in tsproto.
+const MAX_DECOMPRESSED_SIZE: u32 = 2 * 1024 * 1024;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Create and inspect identities
+	#[command(subcommand)]
+	Identity(IdentityCommand),
+	/// Parse and verify licenses
+	#[command(subcommand)]
+	License(LicenseCommand),
+	/// Decode packets
+	#[command(subcommand)]
+	Packet(PacketCommand),
+	/// Hash passwords like the TeamSpeak client
+	#[command(subcommand)]
+	Password(PasswordCommand),
+	/// Check client versions
+	#[command(subcommand)]
+	Version(VersionCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum IdentityCommand {
+	/// Create a new identity
+	Create {
+		/// The level of the new identity
+		#[arg(short, long, default_value_t = 8)]
+		level: u8,
+		/// The number of threads, defaults to the number of cpu cores
+		#[arg(short, long)]
+		threads: Option<usize>,
+	},
+	/// Increase the level of an identity
+	Upgrade {
+		/// The identity in the TeamSpeak format (`<counter>V<key>`) or a
+		/// private key
+		identity: String,
+		/// The level that should be reached
+		#[arg(short, long)]
+		level: u8,
+		/// Resume from this counter, all counters below were already checked
+		#[arg(short, long)]
+		start: Option<u64>,
+		/// The number of threads, defaults to the number of cpu cores
+		#[arg(short, long)]
+		threads: Option<usize>,
+		/// Stop after this many seconds, the computation can be resumed later
+		#[arg(long)]
+		timeout: Option<u64>,
+	},
+	/// Print the uid and level of an identity
+	Uid {
+		/// The identity in the TeamSpeak format (`<counter>V<key>`) or a
+		/// private key
+		identity: String,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum LicenseCommand {
+	/// Print the content of a license
+	Parse {
+		/// The license data (base64)
+		license: String,
+	},
+	/// Check that a license is valid and print the derived public key
+	Verify {
+		/// The license data (base64)
+		license: String,
+		/// Do not fail if the license is expired
+		#[arg(long)]
+		ignore_expired: bool,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum PacketCommand {
+	/// Decode a packet from hex
+	///
+	/// Encrypted packets can only be decrypted if they are encrypted with the
+	/// fake key, which is used before the connection is established.
+	Decode {
+		/// The packet was sent from the client to the server
+		#[arg(short, long)]
+		c2s: bool,
+		/// Packet data (hex)
+		data: String,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum PasswordCommand {
+	/// Hash a server or channel password
+	Hash { password: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum VersionCommand {
+	/// Check the signature of a client version
+	Verify {
+		/// E.g. `Linux`
+		platform: String,
+		/// E.g. `3.5.6 [Build: 1606312422]`
+		version: String,
+		/// The signature (base64)
+		signature: String,
+	},
+}
+
+fn main() -> Result<()> {
+	// Parse command line options
+	let args = Args::parse();
+
+	match args.command {
+		Command::Identity(cmd) => identity(cmd),
+		Command::License(cmd) => license(cmd),
+		Command::Packet(PacketCommand::Decode { c2s, data }) => {
+			let dir = if c2s { Direction::C2S } else { Direction::S2C };
+			let data = utils::read_hex(&data).context("Failed to parse hex")?;
+			print!("{}", decode_packet(dir, &data)?);
+			Ok(())
+		}
+		Command::Password(PasswordCommand::Hash { password }) => {
+			println!("{}", crypto::encode_password(password.as_bytes()));
+			Ok(())
+		}
+		Command::Version(VersionCommand::Verify { platform, version, signature }) => {
+			let signature =
+				BASE64_STANDARD.decode(&signature).context("Signature is not base64")?;
+			algs::verify_version_signature(&platform, &version, &signature)?;
+			println!("Signature is valid");
+			Ok(())
+		}
+	}
+}
+
+fn parse_identity(identity: &str) -> Result<Identity> {
+	Identity::new_from_str(identity).context("Failed to parse identity")
+}
+
+fn print_identity(identity: &Identity) {
+	println!("Uid: {}", identity.key().to_pub().get_uid());
+	println!("Level: {}", identity.level());
+	println!("Counter: {}", identity.counter());
+	println!("Identity: {}", identity.to_ts_str());
+}
+
+fn identity(cmd: IdentityCommand) -> Result<()> {
+	match cmd {
+		IdentityCommand::Create { level, threads } => {
+			let identity = Identity::new(crypto::EccKeyPrivP256::create(), 0);
+			print_identity(&upgrade(identity, level, threads, None));
+		}
+		IdentityCommand::Upgrade { identity, level, start, threads, timeout } => {
+			let mut identity = parse_identity(&identity)?;
+			if let Some(start) = start {
+				identity.set_max_counter(start.max(identity.max_counter()));
+			}
+			let identity = upgrade(identity, level, threads, timeout.map(Duration::from_secs));
+			print_identity(&identity);
+			if identity.level() < level {
+				println!(
+					"Stopped before reaching level {}, resume with --start {}",
+					level,
+					identity.max_counter()
+				);
+			}
+		}
+		IdentityCommand::Uid { identity } => print_identity(&parse_identity(&identity)?),
+	}
+	Ok(())
+}
+
+/// Increase the identity level and print the progress every second.
+fn upgrade(
+	identity: Identity, level: u8, threads: Option<usize>, timeout: Option<Duration>,
+) -> Identity {
+	let mut miner = Miner::new(identity, level);
+	if let Some(threads) = threads {
+		miner = miner.threads(threads);
+	}
+	let handle = miner.start();
+	let start = Instant::now();
+	let mut last_print = start;
+	while !handle.is_finished() {
+		thread::sleep(Duration::from_millis(50));
+		if timeout.map(|t| start.elapsed() >= t).unwrap_or_default() {
+			handle.cancel();
+		}
+		if last_print.elapsed() >= Duration::from_secs(1) {
+			last_print = Instant::now();
+			let p = handle.progress();
+			let remaining = p
+				.estimated_remaining()
+				.map(|d| format!("{}s", d.as_secs()))
+				.unwrap_or_else(|| "-".into());
+			eprintln!(
+				"Checked {} ({:.0}/s), best level {}, about {} remaining",
+				p.checked,
+				p.rate(),
+				p.best_level,
+				remaining
+			);
+		}
+	}
+	handle.wait()
+}
+
+fn license(cmd: LicenseCommand) -> Result<()> {
+	match cmd {
+		LicenseCommand::Parse { license } => {
+			let data = BASE64_STANDARD.decode(&license).context("License is not base64")?;
+			println!("{:#?}", Licenses::parse_ignore_expired(data)?);
+		}
+		LicenseCommand::Verify { license, ignore_expired } => {
+			let data = BASE64_STANDARD.decode(&license).context("License is not base64")?;
+			let licenses = if ignore_expired {
+				Licenses::parse_ignore_expired(data)?
+			} else {
+				Licenses::parse(data)?
+			};
+			licenses.is_valid()?;
+			let root = EccKeyPubEd25519::from_bytes(tsproto::ROOT_KEY);
+			let key = licenses.derive_public_key(root)?;
+			println!("License is valid");
+			println!("Public key: {}", BASE64_STANDARD.encode(key.compress().as_bytes()));
+		}
+	}
+	Ok(())
+}
+
+/// Describe the header and content of a packet.
+fn decode_packet(dir: Direction, data: &[u8]) -> Result<String> {
+	let packet = InPacket::try_new(dir, data).map_err(|e| format_err!("Invalid packet: {}", e))?;
+	let header = packet.header();
+	let flags = header.flags();
+	let p_type = header.packet_type();
+
+	let mut res = String::new();
+	writeln!(res, "Direction: {:?}", dir)?;
+	writeln!(res, "Type: {:?}", p_type)?;
+	writeln!(res, "Id: {}", header.packet_id())?;
+	if let Some(c_id) = header.client_id() {
+		writeln!(res, "Client id: {}", c_id)?;
+	}
+	writeln!(res, "Flags: {:?}", flags)?;
+	writeln!(res, "Mac: {}", HexSlice(header.mac()))?;
+
+	let mut content = if flags.contains(Flags::UNENCRYPTED) {
+		packet.content().to_vec()
+	} else {
+		match algs::decrypt_fake(&packet) {
+			Ok(content) => {
+				writeln!(res, "Decrypted with the fake key")?;
+				content
+			}
+			Err(_) => {
+				writeln!(res, "Encrypted content: {}", HexSlice(packet.content()))?;
+				return Ok(res);
+			}
+		}
+	};
+
+	let is_fragment = flags.contains(Flags::COMPRESSED) && flags.contains(Flags::FRAGMENTED);
+	if is_fragment {
+		writeln!(res, "Compressed first fragment, the packet needs to be reassembled")?;
+	} else if flags.contains(Flags::COMPRESSED) {
+		content = quicklz::decompress(&mut Cursor::new(content), MAX_DECOMPRESSED_SIZE)
+			.map_err(|e| format_err!("Failed to decompress packet: {:?}", e))?;
+	}
+
+	match p_type {
+		PacketType::Command | PacketType::CommandLow if !is_fragment => {
+			describe_command(&mut res, &content)?
+		}
+		PacketType::Voice | PacketType::VoiceWhisper => {
+			let newprotocol = flags.contains(Flags::NEWPROTOCOL);
+			match AudioData::parse(p_type, newprotocol, dir, &content) {
+				Ok(audio) => describe_audio(&mut res, &audio)?,
+				Err(e) => writeln!(res, "Invalid audio: {}", e)?,
+			}
+		}
+		_ => writeln!(res, "Content: {}", HexSlice(content.as_slice()))?,
+	}
+	Ok(res)
+}
+
+fn describe_command(res: &mut String, content: &[u8]) -> Result<()> {
+	let (name, parser) = CommandParser::new(content);
+	if name.is_empty() {
+		bail!("Content is not a command: {}", String::from_utf8_lossy(content));
+	}
+	writeln!(res, "Command: {}", String::from_utf8_lossy(name))?;
+	for item in parser {
+		match item {
+			CommandItem::Argument(arg) => {
+				let value = arg.value();
+				let value =
+					value.get_str().unwrap_or_else(|_| String::from_utf8_lossy(value.get_raw()));
+				writeln!(res, "  {} = {}", String::from_utf8_lossy(arg.name()), value)?
+			}
+			CommandItem::NextCommand => writeln!(res, "  |")?,
+		}
+	}
+	Ok(())
+}
+
+fn describe_audio(res: &mut String, audio: &AudioData) -> Result<()> {
+	writeln!(res, "Voice id: {}", audio.id())?;
+	writeln!(res, "Codec: {:?}", audio.codec())?;
+	match audio {
+		AudioData::C2S { .. } => {}
+		AudioData::C2SWhisper { channels, clients, .. } => {
+			writeln!(res, "Whisper channels: {:?}", channels)?;
+			writeln!(res, "Whisper clients: {:?}", clients)?;
+		}
+		AudioData::C2SWhisperNew { whisper_type, target, target_id, .. } => {
+			writeln!(res, "Whisper type: {}", whisper_type)?;
+			writeln!(res, "Whisper target: {}", target)?;
+			writeln!(res, "Whisper target id: {}", target_id)?;
+		}
+		AudioData::S2C { from, .. } => writeln!(res, "From: {}", from)?,
+		AudioData::S2CWhisper { from, .. } => writeln!(res, "From: {} (whisper)", from)?,
+	}
+	if audio.data().is_empty() {
+		writeln!(res, "Voice data: empty, the speaker stopped talking")?;
+	} else {
+		writeln!(res, "Voice data ({} bytes): {}", audio.data().len(), HexSlice(audio.data()))?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use tsproto_packets::packets::{CodecType, OutAudio, OutPacket};
+
+	use super::*;
+
+	#[test]
+	fn decode_command() {
+		let mut packet =
+			OutPacket::new_with_dir(Direction::C2S, Flags::UNENCRYPTED, PacketType::Command);
+		packet.data_mut().extend_from_slice(b"clientinit client_nickname=Bot\\sName");
+		let res = decode_packet(Direction::C2S, packet.data()).unwrap();
+		assert!(res.contains("Type: Command\n"), "{}", res);
+		assert!(res.contains("Command: clientinit\n"), "{}", res);
+		assert!(res.contains("  client_nickname = Bot Name\n"), "{}", res);
+	}
+
+	#[test]
+	fn decode_fake_encrypted() {
+		let mut packet = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Ack);
+		packet.data_mut().extend_from_slice(&[0, 1]);
+		algs::encrypt_fake(&mut packet).unwrap();
+		let res = decode_packet(Direction::S2C, packet.data()).unwrap();
+		assert!(res.contains("Decrypted with the fake key\n"), "{}", res);
+		assert!(res.contains("Content: Hex[00 01]\n"), "{}", res);
+	}
+
+	#[test]
+	fn decode_voice() {
+		let mut packet = OutAudio::new(&AudioData::S2C {
+			id: 7,
+			from: 3,
+			codec: CodecType::OpusVoice,
+			data: &[1, 2],
+		});
+		packet.flags(Flags::UNENCRYPTED);
+		let res = decode_packet(Direction::S2C, packet.data()).unwrap();
+		assert!(res.contains("Voice id: 7\n"), "{}", res);
+		assert!(res.contains("Codec: OpusVoice\n"), "{}", res);
+		assert!(res.contains("From: 3\n"), "{}", res);
+		assert!(res.contains("Voice data (2 bytes): Hex[01 02]\n"), "{}", res);
+	}
+}
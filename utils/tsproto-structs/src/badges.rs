@@ -0,0 +1,28 @@
+use std::result::Result;
+
+use crate::*;
+
+use once_cell::sync::Lazy;
+
+pub const DATA_STR: &str =
+	include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/declarations/Badges.csv"));
+
+pub static DATA: Lazy<Badges> = Lazy::new(|| {
+	let mut table = csv::Reader::from_reader(DATA_STR.as_bytes());
+	Badges(table.deserialize().collect::<Result<Vec<_>, _>>().unwrap())
+});
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Badge {
+	pub uid: String,
+	pub name: String,
+	pub description: String,
+	/// The name of the icon file, without extension.
+	pub filename: String,
+	/// Codes that can be redeemed for this badge, may be empty.
+	pub codes: String,
+}
+
+#[derive(Default, Debug)]
+pub struct Badges(pub Vec<Badge>);
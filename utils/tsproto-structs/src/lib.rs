@@ -11,6 +11,7 @@ use serde::Deserialize;
 
 type Result<T> = std::result::Result<T, fmt::Error>;
 
+pub mod badges;
 pub mod book;
 pub mod book_to_messages;
 pub mod enums;
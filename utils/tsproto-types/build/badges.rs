@@ -0,0 +1,14 @@
+use std::ops::Deref;
+
+use t4rust_derive::Template;
+use tsproto_structs::badges::*;
+
+#[derive(Template)]
+#[TemplatePath = "build/Badges.tt"]
+#[derive(Default, Debug)]
+pub struct Badges;
+
+impl Deref for Badges {
+	type Target = Vec<Badge>;
+	fn deref(&self) -> &Self::Target { &DATA.0 }
+}
@@ -3,10 +3,12 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
+mod badges;
 mod enums;
 mod errors;
 mod versions;
 
+use crate::badges::Badges;
 use crate::enums::Enums;
 use crate::errors::Errors;
 use crate::versions::Versions;
@@ -24,6 +26,10 @@ fn main() {
 	let mut structs = File::create(path.join("errors.rs")).unwrap();
 	write!(&mut structs, "{}", Errors).unwrap();
 
+	// Badges
+	let mut structs = File::create(path.join("badges.rs")).unwrap();
+	write!(&mut structs, "{}", Badges).unwrap();
+
 	// Versions
 	let mut structs = File::create(path.join("versions.rs")).unwrap();
 	write!(&mut structs, "{}", Versions).unwrap();
@@ -0,0 +1,109 @@
+//! Badges that clients can show next to their name.
+//!
+//! Clients send their badges as a string like
+//! `overwolf=1:badges=<uid>,<uid>`, which can be parsed with
+//! [`Badges::parse`]. Information about known badges is generated from
+//! `Badges.csv`.
+
+use serde::Serialize;
+
+include!(concat!(env!("OUT_DIR"), "/badges.rs"));
+
+/// The server where badge icons are stored.
+const ICON_URL: &str = "https://badges-content.teamspeak.com";
+
+/// Information about a badge.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct BadgeInfo {
+	pub uid: &'static str,
+	pub name: &'static str,
+	pub description: &'static str,
+	/// The name of the icon file, without extension.
+	pub filename: &'static str,
+}
+
+/// A badge of a client.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct Badge {
+	pub uid: String,
+	/// Information about the badge, `None` if the badge is unknown.
+	pub info: Option<&'static BadgeInfo>,
+}
+
+/// The parsed badge string of a client.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Badges {
+	/// If the client uses the Overwolf overlay.
+	pub overwolf: bool,
+	/// The badges in the order they should be displayed.
+	pub badges: Vec<Badge>,
+}
+
+impl BadgeInfo {
+	/// The url of the small icon, shown next to the client name.
+	pub fn icon_url(&self) -> String { format!("{}/{}/{}.svg", ICON_URL, self.uid, self.filename) }
+
+	/// The url of the big icon, shown in the client details.
+	pub fn details_icon_url(&self) -> String {
+		format!("{}/{}/{}_details.svg", ICON_URL, self.uid, self.filename)
+	}
+}
+
+impl Badge {
+	pub fn new(uid: String) -> Self {
+		let info = BadgeInfo::get(&uid);
+		Self { uid, info }
+	}
+}
+
+impl Badges {
+	/// Parse the badge string of a client.
+	///
+	/// Unknown flags and empty uids are ignored.
+	pub fn parse(s: &str) -> Self {
+		let mut res = Self::default();
+		for flag in s.split(':') {
+			let (key, value) = flag.split_once('=').unwrap_or((flag, ""));
+			if key.eq_ignore_ascii_case("overwolf") {
+				res.overwolf = value.trim() == "1";
+			} else if key.eq_ignore_ascii_case("badges") {
+				res.badges = value
+					.split(',')
+					.map(str::trim)
+					.filter(|u| !u.is_empty())
+					.map(|u| Badge::new(u.to_string()))
+					.collect();
+			}
+		}
+		res
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_badges() {
+		let badges = Badges::parse(concat!(
+			"Overwolf=1:badges=4b27be5a-b92a-4b30-8b2d-14b59653f427,",
+			"00000000-0000-0000-0000-000000000000"
+		));
+		assert!(badges.overwolf);
+		assert_eq!(badges.badges.len(), 2);
+		let info = badges.badges[0].info.unwrap();
+		assert_eq!(info.name, "20th Anniversary");
+		assert!(info.icon_url().ends_with("/4b27be5a-b92a-4b30-8b2d-14b59653f427/20_years.svg"));
+		assert_eq!(badges.badges[1].info, None);
+
+		assert_eq!(Badges::parse(""), Badges::default());
+		assert_eq!(Badges::parse("overwolf=0:badges="), Badges::default());
+	}
+
+	#[test]
+	fn lookup_all() {
+		for badge in BADGES {
+			assert_eq!(BadgeInfo::get(badge.uid), Some(badge));
+		}
+	}
+}
@@ -4,6 +4,149 @@ use num_derive::{FromPrimitive, ToPrimitive};
 
 include!(concat!(env!("OUT_DIR"), "/errors.rs"));
 
+/// The kind of an [`Error`], to decide how to react to it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum ErrorCategory {
+	/// Not an error.
+	Ok,
+	/// A wrong password or identity, or the client is banned.
+	Auth,
+	/// Too many commands were sent, the command can be retried later.
+	Flood,
+	/// The command or its arguments are invalid in the current state.
+	InvalidRequest,
+	/// The referenced client, channel, group or file does not exist.
+	NotFound,
+	/// The client is missing a permission.
+	Permission,
+	/// A problem on the server, which cannot be fixed by the client.
+	ServerSide,
+	/// A local error, e.g. from the sound or file system.
+	Local,
+}
+
+impl Error {
+	/// Classify this error.
+	pub fn category(&self) -> ErrorCategory {
+		use Error::*;
+		match *self {
+			Ok | OkNoUpdate | DontNotify | FileTransferComplete => ErrorCategory::Ok,
+			ClientIsFlooding | BanFlooding => ErrorCategory::Flood,
+
+			ClientCouldNotValidateIdentity
+			| ClientInvalidPassword
+			| ClientNotLoggedIn
+			| ClientLoginNotPermitted
+			| ClientVersionOutdated
+			| ClientHacked
+			| ChannelInvalidPassword
+			| ChannelInvalidSecurityHash
+			| ServerInvalidPassword
+			| ConnectFailedBanned
+			| RenameFailedBanned
+			| PrivilegeKeyInvalid
+			| ProvisioningInvalidPassword => ErrorCategory::Auth,
+
+			PermissionsClientInsufficient
+			| PermissionsInsufficientGroupPower
+			| PermissionsInsufficientPermissionPower
+			| Permissions
+			| ChannelIsPrivateChannel
+			| FileInvalidPermissions
+			| ProvisioningNoPermission => ErrorCategory::Permission,
+
+			ClientInvalidId
+			| ChannelInvalidId
+			| ServerInvalidId
+			| DatabaseEmptyResult
+			| FileNotFound
+			| FileInvalidTransferId
+			| FileInvalidPath
+			| FileNoFilesAvailable
+			| MessageInvalidId
+			| BanInvalidId
+			| PermissionInvalidGroupId
+			| PermissionInvalidPermId
+			| PermissionEmptyResult
+			| ProvisioningTs3serverNotFound => ErrorCategory::NotFound,
+
+			Undefined
+			| NotImplemented
+			| LibTimeLimitReached
+			| UnableToBindNetworkPort
+			| NoNetworkPortAvailable
+			| ServerIsShuttingDown
+			| ServerDeploymentActive
+			| ServerWrongMachineid
+			| ServerIsNotRunning
+			| ServerIsBooting
+			| ServerStatusInvalid
+			| ServerVersionOutdated
+			| Database
+			| DatabaseReinvoke
+			| VsCritical
+			| FileIoError
+			| FileNoSpaceLeftOnDevice
+			| FileExceedsFileSystemMaximumSize
+			| FileTransferServerQuotaExceeded
+			| TtsUnableToInitialize => ErrorCategory::ServerSide,
+
+			ConnectionLost
+			| NotConnected
+			| NoCachedConnectionInfo
+			| FailedConnectionInitialisation
+			| CouldNotResolveHostname
+			| InvalidServerConnectionHandlerId
+			| CouldNotInitialiseInputManager
+			| ClientlibraryNotInitialised
+			| ServerlibraryNotInitialised
+			| FileCouldNotOpenConnection
+			| FileTransferConnectionTimeout
+			| FileConnectionLost
+			| FileTransferInterrupted
+			| FileTransferReset
+			| FileTransferCanceled => ErrorCategory::Local,
+
+			e => {
+				let num = e as u32;
+				if (0x0900..0x0a00).contains(&num) || (0x1000..0x1100).contains(&num) {
+					// Sound and voip errors
+					ErrorCategory::Local
+				} else if (0x0b00..0x0c00).contains(&num) || (0x1100..0x1200).contains(&num) {
+					// Accounting and provisioning errors
+					ErrorCategory::ServerSide
+				} else {
+					ErrorCategory::InvalidRequest
+				}
+			}
+		}
+	}
+
+	/// If sending the same command again later can succeed.
+	pub fn is_retryable(&self) -> bool {
+		use Error::*;
+		self.category() == ErrorCategory::Flood
+			|| matches!(
+				*self,
+				CurrentlyNotPossible
+					| ClientCannotVerifyNow
+					| ServerIsBooting
+					| ServerMaxclientsReached
+					| ChannelMaxclientsReached
+					| ChannelMaxfamilyReached
+					| DatabaseReinvoke
+					| FileAlreadyInUse
+					| FileTransferConnectionTimeout
+					| FileConnectionLost
+					| FileTransferInterrupted
+					| FileTransferReset
+					| FileTransferLimitReached
+					| AccountingUnableToConnectToServer
+			)
+	}
+}
+
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { fmt::Debug::fmt(self, f) }
 }
@@ -11,3 +154,22 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {
 	fn description(&self) -> &str { "TeamSpeak error" }
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn categories() {
+		assert_eq!(Error::ClientIsFlooding.category(), ErrorCategory::Flood);
+		assert_eq!(Error::PermissionsClientInsufficient.category(), ErrorCategory::Permission);
+		assert_eq!(Error::ChannelInvalidId.category(), ErrorCategory::NotFound);
+		assert_eq!(Error::ServerInvalidPassword.category(), ErrorCategory::Auth);
+		assert_eq!(Error::AccountingServerError.category(), ErrorCategory::ServerSide);
+		assert_eq!(Error::ParameterInvalid.category(), ErrorCategory::InvalidRequest);
+		assert!(Error::ClientIsFlooding.is_retryable());
+		assert!(Error::ServerMaxclientsReached.is_retryable());
+		assert!(!Error::ChannelNameInuse.is_retryable());
+		assert_eq!(Error::ChannelNameInuse.get_message(), "channel name is already in use");
+	}
+}
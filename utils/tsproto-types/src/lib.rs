@@ -10,6 +10,7 @@ use ref_cast::RefCast;
 use serde::{Deserialize, Deserializer, Serialize};
 use time::OffsetDateTime;
 
+pub mod badges;
 pub mod crypto;
 pub mod errors;
 pub mod versions;
@@ -26,10 +27,10 @@ pub struct ClientId(pub u16);
 /// encoded hash or a special reserved name.
 ///
 /// This is saved raw, so the base64-decoded TeamSpeak uid.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct UidBuf(pub Vec<u8>);
 
-#[derive(Debug, Eq, PartialEq, RefCast, Serialize)]
+#[derive(Debug, Eq, Hash, PartialEq, RefCast, Serialize)]
 #[repr(transparent)]
 pub struct Uid(pub [u8]);
 